@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use anyhow::{ensure, Context, Result};
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+
+use crate::epub::anchor::TextQuote;
+use crate::epub::cfi::{compare_locations, Cfi};
+use crate::epub::EpubFile;
+use crate::utils::{get_config_dir_path, read_json_file, write_json_file};
+
+pub mod export;
+pub mod kindle;
+pub mod web;
+
+/// The annotations of the books in the library, kept apart from `library.json` as there may be
+/// many of them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AnnotationStore {
+    /// Maps the IDs of books to their annotations, sorted by location.
+    books: HashMap<String, Vec<Annotation>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Annotation {
+    pub id: String,
+    /// The range of the annotation, saved against the original spine.
+    pub cfi: String,
+    /// The quoted text, and the text around it.
+    #[serde(flatten)]
+    pub quote: TextQuote,
+    /// A CSS color, or the default color of the style if `None`.
+    pub color: Option<String>,
+    #[serde(default)]
+    pub style: AnnotationStyle,
+    pub note: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(with = "humantime_serde")]
+    pub created_at: SystemTime,
+    #[serde(with = "humantime_serde")]
+    pub updated_at: SystemTime,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnnotationStyle {
+    #[default]
+    Highlight,
+    Underline,
+    Strikethrough,
+}
+
+/// The fields of an annotation set by the user. Missing fields are left as they are, and an
+/// empty color or note is removed.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct AnnotationChanges {
+    pub color: Option<String>,
+    pub style: Option<AnnotationStyle>,
+    pub note: Option<String>,
+    pub tags: Option<Vec<String>>,
+}
+
+/// The result of importing annotations from another app.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ImportReport {
+    pub imported: usize,
+    /// Annotations already in the library.
+    pub duplicates: usize,
+    /// Annotations of other books.
+    pub skipped: usize,
+    /// The text of the annotations which couldn't be found in the book.
+    pub unplaced: Vec<String>,
+}
+
+/// An annotation with the book it belongs to, returned by queries across books.
+#[derive(Debug, Clone, Serialize)]
+pub struct BookAnnotation {
+    pub book_id: String,
+    #[serde(flatten)]
+    pub annotation: Annotation,
+}
+
+impl AnnotationStore {
+    fn get_path() -> Result<PathBuf> {
+        let dir = get_config_dir_path()?;
+        Ok(dir.join("annotations.json"))
+    }
+
+    pub fn load() -> Result<Self> {
+        let path = Self::get_path()?;
+        read_json_file(&path).context("Failed to read annotations.json")
+    }
+
+    pub fn persist(&self) -> Result<()> {
+        let path = Self::get_path()?;
+        write_json_file(&path, self).context("Failed to write annotations.json")
+    }
+
+    pub fn get(&self, book_id: &str) -> &[Annotation] {
+        self.books.get(book_id).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn get_mut(&mut self, book_id: &str, id: &str) -> Option<&mut Annotation> {
+        let annotations = self.books.get_mut(book_id)?;
+        annotations.iter_mut().find(|x| x.id == id)
+    }
+
+    /// Inserts an annotation, keeping the annotations of the book sorted by location.
+    pub fn insert(&mut self, book_id: &str, annotation: Annotation) {
+        let annotations = self.books.entry(book_id.to_string()).or_default();
+        let index =
+            annotations.partition_point(|x| compare_locations(&x.cfi, &annotation.cfi).is_le());
+        annotations.insert(index, annotation);
+    }
+
+    /// Sorts the annotations of a book by location again, after they've been moved.
+    pub fn sort(&mut self, book_id: &str) {
+        if let Some(annotations) = self.books.get_mut(book_id) {
+            annotations.sort_by(|a, b| compare_locations(&a.cfi, &b.cfi));
+        }
+    }
+
+    /// Adds the annotations of a book, imported from another app.
+    pub fn extend(&mut self, book_id: &str, annotations: Vec<Annotation>) {
+        for annotation in annotations {
+            self.insert(book_id, annotation);
+        }
+    }
+
+    pub fn remove(&mut self, book_id: &str, id: &str) -> Option<Annotation> {
+        let annotations = self.books.get_mut(book_id)?;
+        let index = annotations.iter().position(|x| x.id == id)?;
+        Some(annotations.remove(index))
+    }
+
+    /// Finds the annotations with a tag, ignoring case, in all books.
+    pub fn find_by_tag(&self, tag: &str) -> Vec<BookAnnotation> {
+        let tag = tag.trim().to_lowercase();
+        let mut found = Vec::new();
+
+        for (book_id, annotations) in &self.books {
+            let tagged = annotations
+                .iter()
+                .filter(|x| x.tags.iter().any(|x| x.to_lowercase() == tag));
+            found.extend(tagged.map(|annotation| BookAnnotation {
+                book_id: book_id.clone(),
+                annotation: annotation.clone(),
+            }));
+        }
+
+        found
+    }
+}
+
+impl Annotation {
+    /// Creates an annotation of a range of a book, quoting its text.
+    pub fn new(epub: &EpubFile, cfi: &Cfi, changes: AnnotationChanges) -> Result<Self> {
+        ensure!(cfi.is_range(), "Annotations need a range");
+        let quote = TextQuote::new(epub, cfi).context("Failed to resolve the range")?;
+        let now = SystemTime::now();
+
+        let mut annotation = Self {
+            id: Alphanumeric.sample_string(&mut rand::thread_rng(), 16),
+            cfi: cfi.to_string(),
+            quote,
+            color: None,
+            style: AnnotationStyle::default(),
+            note: None,
+            tags: Vec::new(),
+            created_at: now,
+            updated_at: now,
+        };
+        annotation.update(changes);
+        Ok(annotation)
+    }
+
+    pub fn update(&mut self, changes: AnnotationChanges) {
+        if let Some(color) = changes.color {
+            self.color = Some(color).filter(|x| !x.trim().is_empty());
+        }
+        if let Some(style) = changes.style {
+            self.style = style;
+        }
+        if let Some(note) = changes.note {
+            self.note = Some(note).filter(|x| !x.trim().is_empty());
+        }
+
+        if let Some(tags) = changes.tags {
+            self.tags.clear();
+            for tag in tags {
+                let tag = tag.trim();
+                if !tag.is_empty() && !self.tags.iter().any(|x| x.eq_ignore_ascii_case(tag)) {
+                    self.tags.push(tag.to_string());
+                }
+            }
+        }
+
+        self.updated_at = SystemTime::now();
+    }
+}
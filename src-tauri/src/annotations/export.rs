@@ -0,0 +1,287 @@
+use std::fmt::Write;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use anyhow::{bail, Error, Result};
+use humantime_serde::re::humantime;
+use serde::{Deserialize, Serialize};
+
+use super::{web, Annotation};
+use crate::epub::cfi::Cfi;
+use crate::epub::progress::find_toc_entry;
+use crate::epub::toc::EpubTocEntry;
+use crate::epub::EpubFile;
+use crate::library::Book;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    #[default]
+    Markdown,
+    Json,
+    /// The CSV format imported by Readwise.
+    Csv,
+    /// JSON-LD of the W3C Web Annotation Data Model.
+    WebAnnotation,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct ExportOptions {
+    pub format: ExportFormat,
+    /// Whether to link each annotation to its location in the book file.
+    pub links: bool,
+}
+
+/// The annotations of a book grouped by chapter, as exported to JSON.
+#[derive(Debug, Clone, Serialize)]
+pub struct AnnotationExport {
+    pub unique_id: Option<String>,
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub chapters: Vec<ChapterAnnotations>,
+}
+
+/// The annotations in an entry of the table of contents, or before the first entry if `label`
+/// is `None`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChapterAnnotations {
+    pub label: Option<String>,
+    pub annotations: Vec<ExportedAnnotation>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExportedAnnotation {
+    #[serde(flatten)]
+    pub annotation: Annotation,
+    /// The label of the page it starts on.
+    pub page: Option<String>,
+    pub link: Option<String>,
+    /// Used as the location by Readwise, which only takes numbers.
+    #[serde(skip)]
+    page_index: Option<usize>,
+}
+
+impl FromStr for ExportFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self> {
+        Ok(match format.to_lowercase().as_str() {
+            "markdown" | "md" => Self::Markdown,
+            "json" => Self::Json,
+            "csv" | "readwise" => Self::Csv,
+            "jsonld" | "web-annotation" => Self::WebAnnotation,
+            _ => bail!("Unknown export format: {}", format),
+        })
+    }
+}
+
+/// Exports the annotations of a book with an ID, which are sorted by location.
+pub fn export(
+    epub: &EpubFile,
+    id: &str,
+    book: &Book,
+    annotations: &[Annotation],
+    options: &ExportOptions,
+) -> Result<String> {
+    match options.format {
+        ExportFormat::WebAnnotation => web::write(epub, id, book, annotations),
+        format => AnnotationExport::new(epub, book, annotations, options).write(format),
+    }
+}
+
+impl AnnotationExport {
+    /// Groups the annotations of a book, which are sorted by location, by chapter.
+    pub fn new(
+        epub: &EpubFile,
+        book: &Book,
+        annotations: &[Annotation],
+        options: &ExportOptions,
+    ) -> Self {
+        let entries = epub.toc().entries();
+        let pages = epub.page_list().ok();
+        let mut chapters: Vec<ChapterAnnotations> = Vec::new();
+        let mut current = None;
+
+        for annotation in annotations {
+            let cfi = Cfi::parse(&annotation.cfi).ok();
+            let chapter = cfi.as_ref().and_then(|x| find_chapter(epub, &entries, x));
+            let page_index = cfi
+                .as_ref()
+                .and_then(|x| pages.as_ref()?.find_page(&x.collapse(true)));
+
+            let exported = ExportedAnnotation {
+                annotation: annotation.clone(),
+                page: page_index.and_then(|x| Some(pages.as_ref()?.pages[x].label.clone())),
+                link: options
+                    .links
+                    .then(|| make_link(&book.path, &annotation.cfi)),
+                page_index,
+            };
+
+            match chapters.last_mut() {
+                Some(last) if current == chapter => last.annotations.push(exported),
+                _ => {
+                    current = chapter;
+                    chapters.push(ChapterAnnotations {
+                        label: chapter.map(|x| entries[x].label.clone()),
+                        annotations: vec![exported],
+                    });
+                }
+            }
+        }
+
+        Self {
+            unique_id: book.metadata.unique_id.clone(),
+            title: book.metadata.title.clone(),
+            author: book.metadata.author.clone(),
+            chapters,
+        }
+    }
+
+    pub fn write(&self, format: ExportFormat) -> Result<String> {
+        match format {
+            ExportFormat::Markdown => Ok(self.to_markdown()),
+            ExportFormat::Json => Ok(serde_json::to_string_pretty(self)?),
+            ExportFormat::Csv => Ok(self.to_csv()),
+            ExportFormat::WebAnnotation => bail!("Web annotations aren't grouped by chapter"),
+        }
+    }
+
+    fn to_markdown(&self) -> String {
+        let mut output = String::new();
+        let _ = writeln!(output, "# {}", self.title.as_deref().unwrap_or("Untitled"));
+        if let Some(author) = &self.author {
+            let _ = writeln!(output, "\n*{}*", author);
+        }
+
+        for chapter in &self.chapters {
+            if let Some(label) = &chapter.label {
+                let _ = writeln!(output, "\n## {}", label);
+            }
+
+            for exported in &chapter.annotations {
+                let annotation = &exported.annotation;
+                output.push('\n');
+                for line in annotation
+                    .quote
+                    .text
+                    .lines()
+                    .filter(|x| !x.trim().is_empty())
+                {
+                    let _ = writeln!(output, "> {}", line.trim());
+                }
+
+                if let Some(note) = &annotation.note {
+                    let _ = writeln!(output, "\n{}", note.trim());
+                }
+                if !annotation.tags.is_empty() {
+                    let tags: Vec<String> = annotation.tags.iter().map(|x| make_tag(x)).collect();
+                    let _ = writeln!(output, "\nTags: {}", tags.join(" "));
+                }
+
+                let mut location = Vec::new();
+                if let Some(page) = &exported.page {
+                    location.push(format!("Page {}", page));
+                }
+                if let Some(link) = &exported.link {
+                    location.push(format!("[Open in book](<{}>)", link));
+                }
+                if !location.is_empty() {
+                    let _ = writeln!(output, "\n{}", location.join(" · "));
+                }
+            }
+        }
+
+        output
+    }
+
+    /// Writes the annotations in the columns of Readwise's CSV template. Tags are put at the start
+    /// of the notes, where Readwise reads words starting with a dot as tags.
+    fn to_csv(&self) -> String {
+        let mut output = String::from("Highlight,Title,Author,URL,Note,Location,Date\n");
+        let title = self.title.as_deref().unwrap_or_default();
+        let author = self.author.as_deref().unwrap_or_default();
+
+        for exported in self.chapters.iter().flat_map(|x| &x.annotations) {
+            let annotation = &exported.annotation;
+            let mut note: Vec<String> = annotation
+                .tags
+                .iter()
+                .map(|x| make_tag(x).replacen('#', ".", 1))
+                .collect();
+            note.extend(annotation.note.clone());
+            let note = note.join(" ");
+
+            let fields = [
+                annotation.quote.text.as_str(),
+                title,
+                author,
+                exported.link.as_deref().unwrap_or_default(),
+                &note,
+                &exported
+                    .page_index
+                    .map(|x| (x + 1).to_string())
+                    .unwrap_or_default(),
+                &format_date(annotation.created_at),
+            ];
+            let fields: Vec<String> = fields.iter().map(|x| escape_csv(x)).collect();
+            output.push_str(&fields.join(","));
+            output.push('\n');
+        }
+
+        output
+    }
+}
+
+/// Returns the entry of the table of contents containing a location.
+fn find_chapter(epub: &EpubFile, entries: &[EpubTocEntry], cfi: &Cfi) -> Option<usize> {
+    let index = cfi.spine_index()?;
+    let (_, path) = epub.get_spine_path(index)?;
+    let text = epub.document_text(&path).ok()?;
+    let offset = cfi.start().position().and_then(|x| text.offset(&x));
+    find_toc_entry(epub, entries, index, &text, offset.unwrap_or_default())
+}
+
+/// Links to a location in a book file, with the CFI as the fragment as in the CFI spec.
+fn make_link(path: &str, cfi: &str) -> String {
+    // Canonicalized paths on Windows are verbatim, e.g. `\\?\C:\Books\Book.epub`.
+    let path = match path.strip_prefix(r"\\?\UNC\") {
+        Some(share) => format!(r"\\{share}"),
+        None => path.strip_prefix(r"\\?\").unwrap_or(path).to_string(),
+    };
+    let path = path.replace('\\', "/");
+    let mut link = String::from("file://");
+    if !path.starts_with('/') {
+        link.push('/');
+    }
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' | b':' => {
+                link.push(byte as char)
+            }
+            _ => {
+                let _ = write!(link, "%{:02X}", byte);
+            }
+        }
+    }
+    link.push('#');
+    link.push_str(cfi);
+    link
+}
+
+/// Formats a tag as a hashtag, which can't contain whitespace.
+fn make_tag(tag: &str) -> String {
+    let words: Vec<&str> = tag.split_whitespace().collect();
+    format!("#{}", words.join("-"))
+}
+
+/// Formats a time as `YYYY-MM-DD HH:MM:SS` in UTC, as Readwise expects.
+fn format_date(time: SystemTime) -> String {
+    let date = humantime::format_rfc3339_seconds(time).to_string();
+    date.trim_end_matches('Z').replace('T', " ")
+}
+
+fn escape_csv(field: &str) -> String {
+    format!("\"{}\"", field.replace('"', "\"\""))
+}
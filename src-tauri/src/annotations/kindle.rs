@@ -0,0 +1,605 @@
+use std::sync::{Arc, LazyLock};
+
+use regex::Regex;
+use serde::Serialize;
+
+use super::{Annotation, AnnotationChanges, ImportReport};
+use crate::epub::cfi::Cfi;
+use crate::epub::media_type::is_document;
+use crate::epub::text::DocumentText;
+use crate::epub::EpubFile;
+use crate::library::BookMetadata;
+
+/// The line between clippings.
+const SEPARATOR: &str = "==========";
+
+/// The characters at the start and end of a highlight looked for if it's not found whole, e.g.
+/// as it was cut short by the limit on clippings of the book.
+const FUZZY_LENGTH: usize = 24;
+
+/// Words in the line describing a clipping, in the languages of Kindles.
+const HIGHLIGHT_WORDS: &[&str] = &[
+    "highlight",
+    "markierung",
+    "surlignement",
+    "subrayado",
+    "resaltado",
+    "evidenziazione",
+    "destaque",
+    "markering",
+    "ハイライト",
+    "标注",
+    "劃線",
+    "выделен",
+];
+const NOTE_WORDS: &[&str] = &[
+    "note",
+    "notiz",
+    "nota",
+    "notitie",
+    "メモ",
+    "笔记",
+    "筆記",
+    "заметк",
+];
+const BOOKMARK_WORDS: &[&str] = &[
+    "bookmark",
+    "lesezeichen",
+    "signet",
+    "marcador",
+    "segnalibro",
+    "bladwijzer",
+    "ブックマーク",
+    "书签",
+    "書籤",
+    "закладк",
+];
+
+/// Matches the location of a clipping, e.g. `Location 123-125`, `Position 123`, `位置No. 123`
+/// or `位置 #123-125`. Pages aren't used as they depend on the edition.
+static LOCATION: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"(?i)(?:location|loc\.|position|emplacement|posición|posizione|posição|locatie|место|位置\s*(?:no\.)?)\s*#?\s*(\d+)(?:\s*-\s*(\d+))?",
+    )
+    .unwrap()
+});
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClippingKind {
+    Highlight,
+    Note,
+    Bookmark,
+    /// Clippings of articles, which aren't in books.
+    Other,
+}
+
+/// An entry of `My Clippings.txt`, which Kindles append to for every highlight, note and
+/// bookmark.
+#[derive(Debug, Clone)]
+pub struct Clipping {
+    pub title: String,
+    pub author: Option<String>,
+    pub kind: ClippingKind,
+    /// The start and end of the Kindle location, which is about 128 bytes of the book.
+    pub location: Option<(usize, usize)>,
+    pub text: String,
+}
+
+/// The clippings of a book of the library.
+#[derive(Debug, Clone, Serialize)]
+pub struct KindleBookReport {
+    pub book_id: String,
+    pub title: String,
+    #[serde(flatten)]
+    pub report: ImportReport,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct KindleImportReport {
+    pub books: Vec<KindleBookReport>,
+    /// The titles of the books with clippings which aren't in the library.
+    pub unmatched: Vec<String>,
+    /// The titles of the books with clippings which match several books of the library, and
+    /// aren't imported.
+    pub ambiguous: Vec<String>,
+}
+
+/// Parses the clippings of a `My Clippings.txt` file, in any of the languages of Kindles.
+pub fn parse(content: &str) -> Vec<Clipping> {
+    let mut clippings = Vec::new();
+
+    for entry in content.split(SEPARATOR) {
+        let mut lines = entry
+            .lines()
+            .map(|x| x.trim_matches(|c: char| c == '\u{feff}' || c.is_whitespace()))
+            .skip_while(|x| x.is_empty());
+        let (Some(title), Some(meta)) = (lines.next(), lines.next()) else {
+            continue;
+        };
+        let text: Vec<&str> = lines.filter(|x| !x.is_empty()).collect();
+
+        let (title, author) = split_author(title);
+        let meta = meta.to_lowercase();
+        let kind = if BOOKMARK_WORDS.iter().any(|x| meta.contains(x)) {
+            ClippingKind::Bookmark
+        } else if HIGHLIGHT_WORDS.iter().any(|x| meta.contains(x)) {
+            ClippingKind::Highlight
+        } else if NOTE_WORDS.iter().any(|x| meta.contains(x)) {
+            ClippingKind::Note
+        } else {
+            ClippingKind::Other
+        };
+
+        clippings.push(Clipping {
+            title: title.to_string(),
+            author: author.map(str::to_string),
+            kind,
+            location: parse_location(&meta),
+            text: text.join("\n"),
+        });
+    }
+
+    clippings
+}
+
+/// Groups clippings by the title and author of their book, in order of first appearance. Books
+/// with only bookmarks, which can't be placed, are left out.
+pub fn group_by_book(clippings: &[Clipping]) -> Vec<(&str, Option<&str>, Vec<&Clipping>)> {
+    let mut groups: Vec<(&str, Option<&str>, Vec<&Clipping>)> = Vec::new();
+    for clipping in clippings {
+        if !matches!(clipping.kind, ClippingKind::Highlight | ClippingKind::Note) {
+            continue;
+        }
+        let (title, author) = (clipping.title.as_str(), clipping.author.as_deref());
+        match groups.iter_mut().find(|x| x.0 == title && x.1 == author) {
+            Some(group) => group.2.push(clipping),
+            None => groups.push((title, author, vec![clipping])),
+        }
+    }
+    groups
+}
+
+/// Splits `Title (Author)` into the title and the author, which is in the last parentheses.
+fn split_author(line: &str) -> (&str, Option<&str>) {
+    let Some(rest) = line.strip_suffix(')') else {
+        return (line, None);
+    };
+    match rest.rfind('(') {
+        Some(index) if index > 0 => (rest[..index].trim(), Some(rest[index + 1..].trim())),
+        _ => (line, None),
+    }
+}
+
+fn parse_location(meta: &str) -> Option<(usize, usize)> {
+    let captures = LOCATION.captures(meta)?;
+    let start: usize = captures.get(1)?.as_str().parse().ok()?;
+    let Some(end) = captures.get(2) else {
+        return Some((start, start));
+    };
+
+    // Older Kindles shorten the end, e.g. `123-25` for `123-125`.
+    let end: usize = end.as_str().parse().ok()?;
+    if end >= start {
+        return Some((start, end));
+    }
+    // The end may also have carried over, e.g. `199-5` for `199-205`.
+    let scale = 10usize.pow(end.checked_ilog10().unwrap_or_default() + 1);
+    let end = start - start % scale + end;
+    Some((start, if end < start { end + scale } else { end }))
+}
+
+/// Returns whether clippings of a title and an author are of a book. Titles match if one starts
+/// with the other, as Kindles may add the subtitle or series, and authors if they share a name,
+/// as they may be written `Last, First`.
+pub fn matches_book(title: &str, author: Option<&str>, metadata: &BookMetadata) -> bool {
+    match_book(title, author, metadata).is_some()
+}
+
+/// Finds the books clippings of a title and an author are of, among books with IDs. Only the
+/// best matches are returned, which are the books with the same title if there are any, and
+/// with the author if it's known. More than one book means that the clippings are ambiguous.
+pub fn find_books<'a>(
+    title: &str,
+    author: Option<&str>,
+    books: impl IntoIterator<Item = (&'a String, &'a BookMetadata)>,
+) -> Vec<&'a String> {
+    let mut found: Vec<_> = books
+        .into_iter()
+        .filter_map(|(id, metadata)| Some((match_book(title, author, metadata)?, id)))
+        .collect();
+    let Some(best) = found.iter().map(|x| x.0).max() else {
+        return Vec::new();
+    };
+    found.retain(|x| x.0 == best);
+
+    let mut ids: Vec<&String> = found.into_iter().map(|x| x.1).collect();
+    ids.sort();
+    ids
+}
+
+/// How well clippings of a title and an author match a book, see `matches_book`: whether the
+/// titles are the same, and whether the authors are both known and match. `None` if they don't
+/// match.
+fn match_book(title: &str, author: Option<&str>, metadata: &BookMetadata) -> Option<(bool, bool)> {
+    let book_title = metadata.title.as_ref()?;
+    let (title, book_title) = (normalize_words(title), normalize_words(book_title));
+    if title.is_empty() || book_title.is_empty() {
+        return None;
+    }
+    let exact = title == book_title;
+    let title_matches = exact
+        || title.starts_with(&format!("{book_title} "))
+        || book_title.starts_with(&format!("{title} "));
+    if !title_matches {
+        return None;
+    }
+
+    match (author, &metadata.author) {
+        (Some(author), Some(book_author)) => {
+            let book_author = normalize_words(book_author);
+            let mut names = book_author.split(' ');
+            let author = normalize_words(author);
+            names
+                .any(|x| author.split(' ').any(|y| x == y))
+                .then_some((exact, true))
+        }
+        _ => Some((exact, false)),
+    }
+}
+
+/// Lowercases the words of a text, and separates them by spaces.
+fn normalize_words(text: &str) -> String {
+    let words = text.split(|c: char| !c.is_alphanumeric());
+    let words: Vec<String> = words
+        .filter(|x| !x.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+    words.join(" ")
+}
+
+/// The text of a spine document with only its letters and digits, lowercased, to find text
+/// copied from other apps which may differ in whitespace, quotes and dashes.
+struct NormalizedText {
+    index: usize,
+    idref: String,
+    text: Arc<DocumentText>,
+    chars: Vec<char>,
+    /// The offsets of the characters in the text of the document.
+    offsets: Vec<usize>,
+    /// The characters in the documents before this one.
+    position: usize,
+}
+
+impl NormalizedText {
+    fn new(index: usize, idref: &str, text: Arc<DocumentText>, position: usize) -> Self {
+        let mut chars = Vec::new();
+        let mut offsets = Vec::new();
+        for (offset, c) in text.text.char_indices() {
+            if c.is_alphanumeric() {
+                chars.extend(c.to_lowercase());
+                offsets.resize(chars.len(), offset);
+            }
+        }
+        Self {
+            index,
+            idref: idref.to_string(),
+            text,
+            chars,
+            offsets,
+            position,
+        }
+    }
+
+    /// Returns the offsets in the text of the document of a range of its characters.
+    fn to_range(&self, start: usize, end: usize) -> std::ops::Range<usize> {
+        let start = self.offsets[start];
+        let last = self.offsets[end - 1];
+        let length = self.text.text[last..]
+            .chars()
+            .next()
+            .map_or(0, char::len_utf8);
+        start..last + length
+    }
+}
+
+/// Finds the highlights of a book, and imports them with the notes made on them. Notes are
+/// attached to the highlights ending at their locations, as Kindles do.
+pub fn import(
+    epub: &EpubFile,
+    clippings: &[&Clipping],
+    existing: &[Annotation],
+) -> (Vec<Annotation>, ImportReport) {
+    let mut report = ImportReport::default();
+    let documents = load_documents(epub);
+    let total = documents.last().map_or(0, |x| x.position + x.chars.len());
+    let last_location = clippings
+        .iter()
+        .filter_map(|x| Some(x.location?.1))
+        .max()
+        .unwrap_or_default();
+
+    // Kindles add a clipping whenever a highlight is changed, so only the last one of those
+    // starting at a location is kept.
+    let highlights: Vec<&Clipping> = clippings
+        .iter()
+        .enumerate()
+        .filter(|(i, x)| {
+            x.kind == ClippingKind::Highlight
+                && !x.text.is_empty()
+                && !clippings[i + 1..].iter().any(|y| {
+                    let start = |clipping: &Clipping| clipping.location.map(|x| x.0);
+                    y.kind == ClippingKind::Highlight
+                        && (y.text == x.text || (start(x).is_some() && start(y) == start(x)))
+                })
+        })
+        .map(|(_, x)| *x)
+        .collect();
+    let mut notes: Vec<&Clipping> = clippings
+        .iter()
+        .filter(|x| x.kind == ClippingKind::Note && !x.text.is_empty())
+        .copied()
+        .collect();
+
+    let mut imported: Vec<Annotation> = Vec::new();
+    for highlight in highlights {
+        // Where the highlight is expected in the book, from 0 to 1.
+        let expected = match (highlight.location, last_location) {
+            (Some((start, _)), 1..) => Some(start as f64 / last_location as f64),
+            _ => None,
+        };
+        let Some(cfi) = find_text(&documents, total, &highlight.text, expected) else {
+            report.unplaced.push(highlight.text.clone());
+            continue;
+        };
+
+        let note = notes.iter().position(|x| {
+            let (Some(note), Some(highlight)) = (x.location, highlight.location) else {
+                return false;
+            };
+            note.0 == highlight.1
+        });
+        let changes = AnnotationChanges {
+            note: note.map(|x| notes.remove(x).text.clone()),
+            ..Default::default()
+        };
+        let Ok(annotation) = Annotation::new(epub, &cfi, changes) else {
+            report.unplaced.push(highlight.text.clone());
+            continue;
+        };
+
+        let duplicate = existing
+            .iter()
+            .chain(&imported)
+            .any(|x| x.cfi == annotation.cfi);
+        if duplicate {
+            report.duplicates += 1;
+            continue;
+        }
+        imported.push(annotation);
+    }
+
+    report.unplaced.extend(notes.iter().map(|x| x.text.clone()));
+    report.imported = imported.len();
+    (imported, report)
+}
+
+fn load_documents(epub: &EpubFile) -> Vec<NormalizedText> {
+    let mut documents: Vec<NormalizedText> = Vec::new();
+    for index in 0..epub.rootfile().package.spine.children.len() {
+        let Some((idref, path)) = epub.get_spine_path(index) else {
+            continue;
+        };
+        if !epub.get_media_type(&path).is_some_and(is_document) {
+            continue;
+        }
+        let Ok(text) = epub.document_text(&path) else {
+            continue;
+        };
+        let position = documents.last().map_or(0, |x| x.position + x.chars.len());
+        documents.push(NormalizedText::new(index, idref, text, position));
+    }
+    documents
+}
+
+/// Finds text in the documents of a book. If it appears more than once, the match closest to
+/// where it's expected is used. Text which isn't found whole is looked for by its start and end.
+fn find_text(
+    documents: &[NormalizedText],
+    total: usize,
+    text: &str,
+    expected: Option<f64>,
+) -> Option<Cfi> {
+    let needle: Vec<char> = text
+        .chars()
+        .filter(|x| x.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect();
+    if needle.is_empty() {
+        return None;
+    }
+
+    let mut matches = Vec::new();
+    for (i, document) in documents.iter().enumerate() {
+        let starts = find_all(&document.chars, &needle);
+        matches.extend(starts.map(|x| (i, x, x + needle.len())));
+    }
+
+    if matches.is_empty() && needle.len() > FUZZY_LENGTH * 2 {
+        let head = &needle[..FUZZY_LENGTH];
+        let tail = &needle[needle.len() - FUZZY_LENGTH..];
+        // Allows for some text missing or added in the middle.
+        let limit = needle.len() + needle.len() / 2;
+
+        for (i, document) in documents.iter().enumerate() {
+            for start in find_all(&document.chars, head) {
+                let rest = &document.chars[start..(start + limit).min(document.chars.len())];
+                if let Some(end) = find_all(rest, tail).last() {
+                    matches.push((i, start, start + end + tail.len()));
+                }
+            }
+        }
+    }
+
+    let distance = |(i, start, _): &(usize, usize, usize)| match (expected, total) {
+        (Some(expected), 1..) => {
+            let position = (documents[*i].position + start) as f64 / total as f64;
+            (position - expected).abs()
+        }
+        _ => 0.0,
+    };
+    let (i, start, end) = matches
+        .into_iter()
+        .min_by(|a, b| distance(a).total_cmp(&distance(b)))?;
+
+    let document = &documents[i];
+    let range = document.to_range(start, end);
+    Cfi::from_text_range(document.index, &document.idref, &document.text, range)
+}
+
+/// Returns the starts of the occurrences of a sequence in another.
+fn find_all<'a>(haystack: &'a [char], needle: &'a [char]) -> impl Iterator<Item = usize> + 'a {
+    let count = (haystack.len() + 1).saturating_sub(needle.len());
+    (0..count).filter(move |x| haystack[*x..].starts_with(needle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIPPINGS: &str = "\u{feff}The Left Hand of Darkness (Le Guin, Ursula K.)\r
+- Your Highlight on page 12 | Location 170-172 | Added on Monday, 1 January 2024 10:00:00\r
+\r
+The king was pregnant.\r
+==========\r
+The Left Hand of Darkness (Le Guin, Ursula K.)\r
+- Your Note on page 12 | Location 172 | Added on Monday, 1 January 2024 10:01:00\r
+\r
+An opening line.\r
+==========\r
+The Left Hand of Darkness (Le Guin, Ursula K.)\r
+- Your Bookmark on page 20 | Location 301 | Added on Monday, 1 January 2024 10:02:00\r
+\r
+\r
+==========\r
+吾輩は猫である (夏目 漱石)\r
+- 位置No. 1234-1240のハイライト |作成日: 2024年1月1日月曜日 10:00:00\r
+\r
+吾輩は猫である。名前はまだ無い。\r
+==========\r
+";
+
+    fn metadata(title: &str, author: Option<&str>) -> BookMetadata {
+        BookMetadata {
+            unique_id: None,
+            title: Some(title.to_string()),
+            author: author.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn parses_clippings() {
+        let clippings = parse(CLIPPINGS);
+        assert_eq!(clippings.len(), 4);
+
+        let highlight = &clippings[0];
+        assert_eq!(highlight.title, "The Left Hand of Darkness");
+        assert_eq!(highlight.author.as_deref(), Some("Le Guin, Ursula K."));
+        assert_eq!(highlight.kind, ClippingKind::Highlight);
+        assert_eq!(highlight.location, Some((170, 172)));
+        assert_eq!(highlight.text, "The king was pregnant.");
+
+        assert_eq!(clippings[1].kind, ClippingKind::Note);
+        assert_eq!(clippings[1].location, Some((172, 172)));
+        assert_eq!(clippings[1].text, "An opening line.");
+
+        assert_eq!(clippings[2].kind, ClippingKind::Bookmark);
+        assert_eq!(clippings[2].text, "");
+
+        let japanese = &clippings[3];
+        assert_eq!(japanese.title, "吾輩は猫である");
+        assert_eq!(japanese.author.as_deref(), Some("夏目 漱石"));
+        assert_eq!(japanese.kind, ClippingKind::Highlight);
+        assert_eq!(japanese.location, Some((1234, 1240)));
+    }
+
+    #[test]
+    fn groups_clippings_without_bookmarks() {
+        let clippings = parse(CLIPPINGS);
+        let groups = group_by_book(&clippings);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].0, "The Left Hand of Darkness");
+        assert_eq!(groups[0].2.len(), 2);
+        assert_eq!(groups[1].0, "吾輩は猫である");
+    }
+
+    #[test]
+    fn splits_authors() {
+        assert_eq!(split_author("Title (Author)"), ("Title", Some("Author")));
+        assert_eq!(
+            split_author("Title (Series 1) (Author)"),
+            ("Title (Series 1)", Some("Author"))
+        );
+        assert_eq!(split_author("(Untitled)"), ("(Untitled)", None));
+        assert_eq!(split_author("Title"), ("Title", None));
+    }
+
+    #[test]
+    fn parses_locations() {
+        assert_eq!(parse_location("location 123"), Some((123, 123)));
+        assert_eq!(parse_location("location 123-125"), Some((123, 125)));
+        assert_eq!(parse_location("loc. 1234-56"), Some((1234, 1256)));
+        assert_eq!(parse_location("emplacement 123 - 25"), Some((123, 125)));
+        assert_eq!(parse_location("位置 #99-101"), Some((99, 101)));
+        assert_eq!(parse_location("page 12"), None);
+    }
+
+    #[test]
+    fn carries_shortened_locations_over() {
+        assert_eq!(parse_location("location 199-5"), Some((199, 205)));
+        assert_eq!(parse_location("location 1995-10"), Some((1995, 2010)));
+        assert_eq!(parse_location("location 1999-1"), Some((1999, 2001)));
+    }
+
+    #[test]
+    fn matches_titles_and_authors() {
+        let book = metadata("The Left Hand of Darkness", Some("Ursula K. Le Guin"));
+        assert!(matches_book(
+            "The Left Hand of Darkness",
+            Some("Le Guin, Ursula K."),
+            &book
+        ));
+        assert!(matches_book(
+            "The Left Hand of Darkness: A Novel",
+            None,
+            &book
+        ));
+        assert!(!matches_book("The Left Hand", Some("Someone Else"), &book));
+        assert!(!matches_book("The Left Handed", None, &book));
+    }
+
+    #[test]
+    fn prefers_exact_titles() {
+        let (a, b) = ("a".to_string(), "b".to_string());
+        let dune = metadata("Dune", Some("Frank Herbert"));
+        let messiah = metadata("Dune Messiah", Some("Frank Herbert"));
+        let books = [(&a, &dune), (&b, &messiah)];
+
+        assert_eq!(find_books("Dune", Some("Herbert, Frank"), books), vec![&a]);
+        assert_eq!(find_books("Dune Messiah", None, books), vec![&b]);
+        assert!(find_books("Children of Dune", None, books).is_empty());
+    }
+
+    #[test]
+    fn reports_ambiguous_books() {
+        let (a, b) = ("a".to_string(), "b".to_string());
+        let first = metadata("Collected Stories", Some("Anton Chekhov"));
+        let second = metadata("Collected Stories", Some("Mavis Gallant"));
+        let books = [(&a, &first), (&b, &second)];
+
+        assert_eq!(find_books("Collected Stories", None, books), vec![&a, &b]);
+        assert_eq!(
+            find_books("Collected Stories", Some("Gallant, Mavis"), books),
+            vec![&b]
+        );
+    }
+}
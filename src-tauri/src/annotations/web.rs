@@ -0,0 +1,468 @@
+use std::ops::Range;
+use std::sync::LazyLock;
+
+use anyhow::Result;
+use humantime_serde::re::humantime;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use super::{Annotation, AnnotationChanges, AnnotationStyle, ImportReport};
+use crate::epub::anchor::TextQuote;
+use crate::epub::cfi::Cfi;
+use crate::epub::EpubFile;
+use crate::library::Book;
+
+const CONTEXT: &str = "http://www.w3.org/ns/anno.jsonld";
+
+/// The value of `conformsTo` of fragment selectors which are EPUB CFIs.
+const CFI_SPEC: &str = "http://www.idpf.org/epub/linking/cfi/epub-cfi.html";
+
+static BACKGROUND_COLOR: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"background-color\s*:\s*([^;}]+)").unwrap());
+
+/// A document of the Web Annotation Data Model: a collection with its annotations embedded, as
+/// exported, or a page, a list or a single annotation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum WebAnnotationDocument {
+    Collection(Box<WebAnnotationCollection>),
+    Page(WebAnnotationPage),
+    Many(Vec<WebAnnotation>),
+    One(Box<WebAnnotation>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebAnnotationCollection {
+    #[serde(rename = "@context", skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub label: Option<String>,
+    pub total: Option<usize>,
+    pub first: WebAnnotationPage,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebAnnotationPage {
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub items: Vec<WebAnnotation>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebAnnotation {
+    #[serde(rename = "@context", skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+    pub id: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub motivation: Option<OneOrMany<String>>,
+    pub created: Option<String>,
+    pub modified: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub body: Option<OneOrMany<Body>>,
+    /// A shorthand for a body of plain text.
+    #[serde(rename = "bodyValue", skip_serializing_if = "Option::is_none")]
+    pub body_value: Option<String>,
+    pub target: OneOrMany<Target>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stylesheet: Option<Stylesheet>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Body {
+    Iri(String),
+    Resource {
+        #[serde(rename = "type")]
+        kind: Option<String>,
+        value: Option<String>,
+        purpose: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        format: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Target {
+    Iri(String),
+    Resource {
+        source: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        selector: Option<OneOrMany<Selector>>,
+        #[serde(rename = "styleClass", skip_serializing_if = "Option::is_none")]
+        style_class: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Selector {
+    FragmentSelector {
+        value: String,
+        #[serde(rename = "conformsTo")]
+        conforms_to: Option<String>,
+        #[serde(rename = "refinedBy", skip_serializing_if = "Option::is_none")]
+        refined_by: Option<Box<Selector>>,
+    },
+    TextQuoteSelector {
+        exact: String,
+        #[serde(default)]
+        prefix: String,
+        #[serde(default)]
+        suffix: String,
+    },
+    /// Positions in characters in the text of a spine document, with whitespace collapsed as in
+    /// rendering.
+    TextPositionSelector { start: usize, end: usize },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Stylesheet {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub value: String,
+}
+
+impl<T> OneOrMany<T> {
+    fn iter(&self) -> std::slice::Iter<'_, T> {
+        match self {
+            Self::One(x) => std::slice::from_ref(x).iter(),
+            Self::Many(x) => x.iter(),
+        }
+    }
+}
+
+impl WebAnnotationDocument {
+    /// Exports the annotations of a book as a collection, with the book as the source of the
+    /// targets, identified by its unique ID, or else by a URN of its ID in the library.
+    pub fn new(epub: &EpubFile, id: &str, book: &Book, annotations: &[Annotation]) -> Self {
+        let source = match &book.metadata.unique_id {
+            Some(unique_id) => unique_id.clone(),
+            None => format!("urn:ellisia:{}", id),
+        };
+        let items: Vec<WebAnnotation> = annotations
+            .iter()
+            .map(|x| WebAnnotation::new(epub, &source, x))
+            .collect();
+
+        Self::Collection(Box::new(WebAnnotationCollection {
+            context: Some(CONTEXT.to_string()),
+            kind: "AnnotationCollection".to_string(),
+            label: book.metadata.title.clone(),
+            total: Some(items.len()),
+            first: WebAnnotationPage {
+                kind: Some("AnnotationPage".to_string()),
+                items,
+            },
+        }))
+    }
+
+    pub fn into_annotations(self) -> Vec<WebAnnotation> {
+        match self {
+            Self::Collection(collection) => collection.first.items,
+            Self::Page(page) => page.items,
+            Self::Many(annotations) => annotations,
+            Self::One(annotation) => vec![*annotation],
+        }
+    }
+
+    /// Imports the annotations targeting a book, identified by its unique ID if it has one.
+    /// Annotations already in `existing` are skipped.
+    pub fn import(
+        self,
+        epub: &EpubFile,
+        book: &Book,
+        existing: &[Annotation],
+    ) -> (Vec<Annotation>, ImportReport) {
+        let mut imported: Vec<Annotation> = Vec::new();
+        let mut report = ImportReport::default();
+
+        for web in self.into_annotations() {
+            let mut targets = web.target.iter().filter_map(|x| match x {
+                Target::Resource {
+                    source, selector, ..
+                } => Some((source, selector)),
+                Target::Iri(_) => None,
+            });
+            let target = targets.find(|(source, _)| {
+                let id = book.metadata.unique_id.as_deref();
+                id.is_none_or(|id| id == source.as_str())
+            });
+            let Some((_, selector)) = target else {
+                report.skipped += 1;
+                continue;
+            };
+            let selectors: Vec<&Selector> = selector.iter().flat_map(|x| x.iter()).collect();
+
+            let placed = find_target(epub, &selectors)
+                .and_then(|cfi| Annotation::new(epub, &cfi, web.changes()).ok());
+            let Some(mut annotation) = placed else {
+                report.unplaced.push(web.label());
+                continue;
+            };
+
+            let duplicate = existing
+                .iter()
+                .chain(&imported)
+                .any(|x| x.cfi == annotation.cfi);
+            if duplicate {
+                report.duplicates += 1;
+                continue;
+            }
+
+            let parse = |x: &Option<String>| humantime::parse_rfc3339_weak(x.as_deref()?).ok();
+            if let Some(created) = parse(&web.created) {
+                annotation.created_at = created;
+                annotation.updated_at = created;
+            }
+            if let Some(modified) = parse(&web.modified) {
+                annotation.updated_at = modified;
+            }
+            imported.push(annotation);
+        }
+
+        report.imported = imported.len();
+        (imported, report)
+    }
+}
+
+impl WebAnnotation {
+    fn new(epub: &EpubFile, source: &str, annotation: &Annotation) -> Self {
+        let mut selectors = vec![Selector::FragmentSelector {
+            value: annotation.cfi.clone(),
+            conforms_to: Some(CFI_SPEC.to_string()),
+            refined_by: None,
+        }];
+        if let Some(selector) = get_position_selector(epub, &annotation.cfi) {
+            selectors.push(selector);
+        }
+        selectors.push(Selector::TextQuoteSelector {
+            exact: annotation.quote.text.clone(),
+            prefix: annotation.quote.prefix.clone(),
+            suffix: annotation.quote.suffix.clone(),
+        });
+
+        let mut bodies = Vec::new();
+        if let Some(note) = &annotation.note {
+            bodies.push(Body::Resource {
+                kind: Some("TextualBody".to_string()),
+                value: Some(note.clone()),
+                purpose: Some("commenting".to_string()),
+                format: Some("text/plain".to_string()),
+            });
+        }
+        for tag in &annotation.tags {
+            bodies.push(Body::Resource {
+                kind: Some("TextualBody".to_string()),
+                value: Some(tag.clone()),
+                purpose: Some("tagging".to_string()),
+                format: None,
+            });
+        }
+
+        let style = get_style_class(annotation.style);
+        let format_time = |x| humantime::format_rfc3339_seconds(x).to_string();
+        Self {
+            context: None,
+            id: Some(format!("urn:ellisia:annotation:{}", annotation.id)),
+            kind: Some("Annotation".to_string()),
+            motivation: Some(OneOrMany::One(match annotation.note {
+                Some(_) => "commenting".to_string(),
+                None => "highlighting".to_string(),
+            })),
+            created: Some(format_time(annotation.created_at)),
+            modified: Some(format_time(annotation.updated_at)),
+            body: (!bodies.is_empty()).then_some(OneOrMany::Many(bodies)),
+            body_value: None,
+            target: OneOrMany::One(Target::Resource {
+                source: source.to_string(),
+                selector: Some(OneOrMany::Many(selectors)),
+                style_class: Some(style.to_string()),
+            }),
+            stylesheet: annotation.color.as_ref().map(|color| Stylesheet {
+                kind: "CssStylesheet".to_string(),
+                value: format!(".{} {{ background-color: {}; }}", style, color),
+            }),
+        }
+    }
+
+    /// Reads the note, tags, style and color, from the bodies and styles of the annotation.
+    fn changes(&self) -> AnnotationChanges {
+        let mut changes = AnnotationChanges::default();
+        let mut notes: Vec<&str> = self.body_value.as_deref().into_iter().collect();
+
+        for body in self.body.iter().flat_map(|x| x.iter()) {
+            let Body::Resource {
+                value: Some(value),
+                purpose,
+                ..
+            } = body
+            else {
+                continue;
+            };
+            match purpose.as_deref() {
+                Some("tagging") => changes
+                    .tags
+                    .get_or_insert_with(Vec::new)
+                    .push(value.clone()),
+                _ => notes.push(value.as_str()),
+            }
+        }
+        if !notes.is_empty() {
+            changes.note = Some(notes.join("\n\n"));
+        }
+
+        let style_class = self.target.iter().find_map(|x| match x {
+            Target::Resource { style_class, .. } => style_class.as_deref(),
+            Target::Iri(_) => None,
+        });
+        changes.style = Some(match style_class {
+            Some("underline") => AnnotationStyle::Underline,
+            Some("strikethrough") => AnnotationStyle::Strikethrough,
+            _ => AnnotationStyle::Highlight,
+        });
+        changes.color = self.stylesheet.as_ref().and_then(|x| {
+            let color = BACKGROUND_COLOR.captures(&x.value)?.get(1)?;
+            Some(color.as_str().trim().to_string())
+        });
+
+        changes
+    }
+
+    /// Describes an annotation which couldn't be placed by its quoted text, or else its
+    /// location or its ID.
+    fn label(&self) -> String {
+        let selectors = self.target.iter().flat_map(|x| match x {
+            Target::Resource {
+                selector: Some(selector),
+                ..
+            } => selector.iter().collect(),
+            _ => Vec::new(),
+        });
+        let mut quote = None;
+        let mut location = None;
+        for selector in selectors {
+            match selector {
+                Selector::TextQuoteSelector { exact, .. } => quote = Some(exact.clone()),
+                Selector::FragmentSelector { value, .. } => location = Some(value.clone()),
+                _ => {}
+            }
+        }
+        quote.or(location).or(self.id.clone()).unwrap_or_default()
+    }
+}
+
+/// Finds the range targeted by selectors. The CFI is used if it's still at the quoted text, and
+/// else the quote is searched, starting at the CFI or the text position. If the quote isn't found,
+/// e.g. as its whitespace differs from the text, the CFI or the text position is used if it still
+/// resolves.
+fn find_target(epub: &EpubFile, selectors: &[&Selector]) -> Option<Cfi> {
+    let mut cfi = None;
+    let mut position = None;
+    let mut quote = None;
+
+    for selector in selectors {
+        match selector {
+            Selector::FragmentSelector {
+                value, refined_by, ..
+            } => {
+                let Ok(parsed) = Cfi::parse(value) else {
+                    continue;
+                };
+                match refined_by.as_deref() {
+                    Some(Selector::TextPositionSelector { start, end }) => {
+                        position = get_position_range(epub, &parsed, *start..*end);
+                    }
+                    _ if parsed.is_range() => cfi = Some(parsed),
+                    _ => {}
+                }
+            }
+            Selector::TextQuoteSelector {
+                exact,
+                prefix,
+                suffix,
+            } => {
+                quote = Some(TextQuote {
+                    text: exact.clone(),
+                    prefix: prefix.clone(),
+                    suffix: suffix.clone(),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    let resolves = |cfi: &Cfi| {
+        let path = cfi.spine_index().and_then(|x| epub.get_spine_path(x));
+        let text = path.and_then(|(_, path)| epub.document_text(&path).ok());
+        text.is_some_and(|text| cfi.resolve(&text).is_some())
+    };
+
+    let hint = cfi.or(position);
+    match quote {
+        Some(quote) if !quote.text.is_empty() => quote
+            .find(epub, hint.as_ref())
+            .or_else(|| hint.filter(resolves)),
+        _ => hint,
+    }
+}
+
+/// Returns the document of a location, refined by the positions of its range in the text of the
+/// document.
+fn get_position_selector(epub: &EpubFile, cfi: &str) -> Option<Selector> {
+    let cfi = Cfi::parse(cfi).ok()?;
+    let index = cfi.spine_index()?;
+    let (idref, path) = epub.get_spine_path(index)?;
+    let text = epub.document_text(&path).ok()?;
+    let range = cfi.resolve(&text)?;
+
+    let start = text.text[..range.start].chars().count();
+    let end = start + text.text[range].chars().count();
+    let document = Cfi::parse(&format!("epubcfi(/6/{}[{}])", (index + 1) * 2, idref)).ok()?;
+    Some(Selector::FragmentSelector {
+        value: document.to_string(),
+        conforms_to: Some(CFI_SPEC.to_string()),
+        refined_by: Some(Box::new(Selector::TextPositionSelector { start, end })),
+    })
+}
+
+/// Returns the range at positions in characters in the document of a location.
+fn get_position_range(epub: &EpubFile, document: &Cfi, range: Range<usize>) -> Option<Cfi> {
+    let index = document.spine_index()?;
+    let (idref, path) = epub.get_spine_path(index)?;
+    let text = epub.document_text(&path).ok()?;
+
+    let mut offsets = text
+        .text
+        .char_indices()
+        .map(|(x, _)| x)
+        .chain([text.text.len()]);
+    let start = offsets.nth(range.start)?;
+    let end = offsets.nth(range.end.checked_sub(range.start + 1)?)?;
+    Cfi::from_text_range(index, idref, &text, start..end)
+}
+
+fn get_style_class(style: AnnotationStyle) -> &'static str {
+    match style {
+        AnnotationStyle::Highlight => "highlight",
+        AnnotationStyle::Underline => "underline",
+        AnnotationStyle::Strikethrough => "strikethrough",
+    }
+}
+
+/// Exports the annotations of a book as JSON-LD.
+pub fn write(epub: &EpubFile, id: &str, book: &Book, annotations: &[Annotation]) -> Result<String> {
+    let document = WebAnnotationDocument::new(epub, id, book, annotations);
+    Ok(serde_json::to_string_pretty(&document)?)
+}
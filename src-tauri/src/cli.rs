@@ -0,0 +1,72 @@
+use std::ffi::OsString;
+
+use anyhow::{bail, Context, Result};
+use typed_path::Utf8NativePathBuf;
+
+use crate::annotations::export::{self, ExportOptions};
+use crate::annotations::AnnotationStore;
+use crate::epub::EpubFile;
+use crate::library::Library;
+
+const EXPORT_USAGE: &str =
+    "Usage: ellisia export <book> [--format markdown|json|csv|jsonld] [--links] [--output <file>]";
+
+/// Runs a command without opening the app, if the arguments start with one, e.g.
+/// `ellisia export book.epub --format csv`. Returns the exit code.
+pub fn run(args: &[OsString]) -> Option<i32> {
+    let result = match args.get(1)?.to_str()? {
+        "export" => export(&args[2..]),
+        _ => return None,
+    };
+
+    Some(match result {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            1
+        }
+    })
+}
+
+/// Exports the annotations of a book in the library, given by its path or ID. The output is
+/// printed unless `--output` is given, which is needed on Windows where release builds have no
+/// console.
+fn export(args: &[OsString]) -> Result<()> {
+    let mut book = None;
+    let mut options = ExportOptions::default();
+    let mut output = None;
+
+    let mut args = args.iter().map(|x| x.to_str().context("Invalid argument"));
+    while let Some(arg) = args.next() {
+        match arg? {
+            "--format" | "-f" => {
+                options.format = args.next().context(EXPORT_USAGE)??.parse()?;
+            }
+            "--links" => options.links = true,
+            "--output" | "-o" => output = Some(args.next().context(EXPORT_USAGE)??),
+            arg if book.is_none() => book = Some(arg),
+            arg => bail!("Unexpected argument: {}\n{}", arg, EXPORT_USAGE),
+        }
+    }
+    let book = book.context(EXPORT_USAGE)?;
+
+    let library = Library::load().context("Failed to load library.json")?;
+    let store = AnnotationStore::load().context("Failed to load annotations.json")?;
+
+    // Paths are saved canonicalized.
+    let path = std::fs::canonicalize(book).ok();
+    let path = path.and_then(|x| x.into_os_string().into_string().ok());
+    let (id, book) = library
+        .books()
+        .iter()
+        .find(|(id, x)| id.as_str() == book || Some(&x.path) == path.as_ref())
+        .context("Book not found in library")?;
+    let epub = EpubFile::open(Utf8NativePathBuf::from(book.path.clone()))?;
+
+    let text = export::export(&epub, id, book, store.get(id), &options)?;
+    match output {
+        Some(output) => std::fs::write(output, text).context("Failed to write the export")?,
+        None => print!("{}", text),
+    }
+    Ok(())
+}
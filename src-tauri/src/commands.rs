@@ -1,16 +1,37 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::SystemTime;
 
-use anyhow::Context;
+use anyhow::{bail, Context};
 use serde::Serialize;
 use tauri::api::dialog;
-use tauri::{AppHandle, Manager, Wry};
+use tauri::api::dialog::blocking::FileDialogBuilder;
+use tauri::{AppHandle, Manager, Window, Wry};
 use typed_path::Utf8NativePathBuf;
 
+use crate::annotations::export::{self, ExportFormat, ExportOptions};
+use crate::annotations::kindle::{self, KindleBookReport, KindleImportReport};
+use crate::annotations::web::WebAnnotationDocument;
+use crate::annotations::{Annotation, AnnotationChanges, BookAnnotation, ImportReport};
+use crate::epub::anchor::TextQuote;
+use crate::epub::cfi::Cfi;
+use crate::epub::pages::{PageList, PagePosition};
+use crate::epub::progress::ReadingProgress;
 use crate::epub::rootfile::EpubRootfile;
+use crate::epub::stats::ReadingTime;
+use crate::epub::text::ChapterText;
 use crate::epub::toc::EpubToc;
+use crate::epub::EpubFile;
 use crate::error::CommandError;
+use crate::index::{self, LibrarySearchResult};
+use crate::koreader::{self, KoreaderImportReport, Sidecar};
+use crate::library::bookmark::Bookmark;
 use crate::library::{Book, BookMetadata};
+use crate::renderer::transform::chinese::get_converter;
+use crate::search::{self, Matcher, SearchFinished, SearchOptions};
+use crate::settings::{RenderSettings, Settings};
 use crate::state::AppState;
+use crate::utils::get_file_signature;
 
 /// The handlers creating new windows need to be `async` to avoid deadlocks.
 /// See https://tauri.app/v1/guides/features/multiwindow/#create-a-window-using-an-apphandle-instance
@@ -53,9 +74,26 @@ pub fn get_library(app: AppHandle) -> Result<impl Serialize, CommandError> {
 #[tauri::command]
 pub fn get_toc(app: AppHandle, id: &str) -> Result<EpubToc, CommandError> {
     let state = app.state::<AppState>();
+    // Locks the library before the books, in the same order as `AppState::open_book`.
+    let settings = state.render_settings(id);
+    let spine = state.virtual_spine(id)?;
     let epubs = state.epubs().read();
     let epub = epubs.get(id).context("Book not opened")?;
-    let toc = epub.toc().clone();
+    let mut toc = epub.toc().clone();
+
+    if let Some(converter) = get_converter(&settings) {
+        toc.map_labels(|label| converter.convert(label));
+    }
+    // Points fragments in split documents to the parts containing them.
+    if let Some(spine) = spine {
+        let path = toc.path.clone();
+        toc.map_srcs(|src| {
+            spine
+                .rewrite_link(&path, src)
+                .unwrap_or_else(|| src.to_string())
+        });
+    }
+
     Ok(toc)
 }
 
@@ -68,41 +106,889 @@ pub fn get_rootfile(app: AppHandle, id: &str) -> Result<EpubRootfile, CommandErr
     Ok(rootfile)
 }
 
+/// Returns the problems found in a book, e.g. repaired documents, recording those found since it
+/// was opened in the library.
 #[tauri::command]
-pub fn get_progress(app: AppHandle, id: &str) -> Result<Option<String>, CommandError> {
+pub fn get_book_warnings(app: AppHandle, id: &str) -> Result<Vec<String>, CommandError> {
     let state = app.state::<AppState>();
-    let library = state.library().lock();
-    let location = library.books().get(id).and_then(|x| x.location.to_owned());
-    Ok(location)
+    Ok(state.record_warnings(id)?)
+}
+
+/// Returns the plain text of the document at an index of the original spine. CFIs point into
+/// the virtual chapters if large documents are split.
+#[tauri::command]
+pub async fn get_chapter_text(
+    app: AppHandle,
+    id: String,
+    index: usize,
+) -> Result<ChapterText, CommandError> {
+    let task = tauri::async_runtime::spawn_blocking(move || {
+        let state = app.state::<AppState>();
+        let spine = state.virtual_spine(&id)?;
+        let epub = state
+            .epubs()
+            .read()
+            .get(&id)
+            .cloned()
+            .context("Book not opened")?;
+
+        let (idref, path) = epub
+            .get_spine_path(index)
+            .context("Spine index out of range")?;
+        let text = epub.document_text(&path)?;
+
+        let mut chapter = ChapterText::new(index, idref, path, &text);
+        if let Some(spine) = spine {
+            for block in &mut chapter.blocks {
+                block.cfi = block.cfi.as_deref().map(|x| spine.to_virtual_cfi(x));
+            }
+        }
+        anyhow::Ok(chapter)
+    });
+
+    Ok(task.await.context("Failed to get the chapter text")??)
 }
 
+/// Searches an opened book in reading order. Results are emitted to the window as
+/// `search-result` events, followed by a `search-finished` event. Returns the ID of the search,
+/// which is cancelled when another search of the same book starts.
 #[tauri::command]
-pub fn save_progress(app: AppHandle, id: &str, location: &str) -> Result<(), CommandError> {
+pub fn search_book(
+    app: AppHandle,
+    window: Window,
+    id: &str,
+    query: &str,
+    options: SearchOptions,
+) -> Result<u64, CommandError> {
     let state = app.state::<AppState>();
-    let mut library = state.library().lock();
+    let settings = state.render_settings(id);
+    let spine = state.virtual_spine(id)?;
+    let epub = state
+        .epubs()
+        .read()
+        .get(id)
+        .cloned()
+        .context("Book not opened")?;
+
+    let matcher = Matcher::new(query, &options)?;
+    let book_id = id.to_string();
+    let search_id = search::begin_search(id);
+
+    std::thread::spawn(move || {
+        let converter = get_converter(&settings);
+
+        let result = search::search_book(&epub, &matcher, &book_id, search_id, |mut result| {
+            if search::is_cancelled(&book_id, search_id) {
+                return false;
+            }
+
+            if let Some(converter) = converter {
+                result.label = result.label.map(|x| converter.convert(&x));
+            }
+            if let Some(spine) = &spine {
+                result.cfi = result.cfi.map(|x| spine.to_virtual_cfi(&x));
+            }
+            window.emit("search-result", result).is_ok()
+        });
+        let cancelled = search::is_cancelled(&book_id, search_id);
+        search::end_search(&book_id, search_id);
+
+        let finished = SearchFinished {
+            search_id,
+            count: *result.as_ref().unwrap_or(&0),
+            cancelled,
+            error: result.err().map(|e| e.to_string()),
+        };
+        let _ = window.emit("search-finished", finished);
+    });
+
+    Ok(search_id)
+}
+
+/// Searches the full-text index of the library. Returns the best matching books, each with the
+/// best matching chapters.
+#[tauri::command]
+pub async fn search_library(
+    app: AppHandle,
+    query: String,
+    limit: Option<usize>,
+) -> Result<Vec<LibrarySearchResult>, CommandError> {
+    // Searching and opening the books to locate the hits block.
+    let task = tauri::async_runtime::spawn_blocking(move || {
+        let state = app.state::<AppState>();
+        let index = state.index().context("The library index is unavailable")?;
+        let books = state.library().lock().books().clone();
+        let mut results = index.search(&query, limit.unwrap_or(20), &books)?;
 
-    match library.books_mut().get_mut(id) {
-        Some(book) => {
-            book.location = Some(location.to_string());
-            book.last_read_at = SystemTime::now();
+        for result in &mut results {
+            let epub = state.epubs().read().get(&result.id).cloned();
+            let epub = match epub {
+                Some(epub) => epub,
+                None => match EpubFile::open(books[&result.id].path.clone().into()) {
+                    Ok(epub) => Arc::new(epub),
+                    Err(_) => continue,
+                },
+            };
+            index::locate_hits(&epub, result);
         }
-        None => {
-            // May happen when user removed book with the reader window opening.
-            if let Some(epub) = state.epubs().read().get(id) {
-                library.books_mut().insert(
-                    id.to_string(),
-                    Book {
-                        path: epub.path().to_string(),
-                        location: Some(location.to_string()),
-                        last_read_at: SystemTime::now(),
-                        metadata: BookMetadata::new(epub),
-                    },
-                );
+
+        anyhow::Ok(results)
+    });
+
+    Ok(task.await.context("Failed to search the library")??)
+}
+
+/// Estimates the time to finish the current chapter and the book from the saved location.
+#[tauri::command]
+pub async fn get_reading_time(app: AppHandle, id: String) -> Result<ReadingTime, CommandError> {
+    // Counting the words reads the whole book if the stats are missing.
+    let task = tauri::async_runtime::spawn_blocking(move || {
+        let state = app.state::<AppState>();
+        let epub = state
+            .epubs()
+            .read()
+            .get(&id)
+            .cloned()
+            .context("Book not opened")?;
+
+        let book = state.library().lock().books().get(&id).cloned();
+        let book = book.context("Book not found in library")?;
+        let stats = match book.stats {
+            Some(stats) => stats,
+            None => {
+                state.update_stats(&id)?;
+                let library = state.library().lock();
+                let book = library.books().get(&id);
+                book.and_then(|x| x.stats.clone()).unwrap_or_default()
+            }
+        };
+
+        anyhow::Ok(stats.reading_time(&epub, book.location.as_deref()))
+    });
+
+    Ok(task
+        .await
+        .context("Failed to estimate the reading time")??)
+}
+
+/// Returns the stable page numbers of an opened book. CFIs point into the virtual chapters if
+/// large documents are split.
+#[tauri::command]
+pub async fn get_page_list(app: AppHandle, id: String) -> Result<PageList, CommandError> {
+    // Building the list reads the whole book the first time.
+    let task = tauri::async_runtime::spawn_blocking(move || {
+        let state = app.state::<AppState>();
+        let spine = state.virtual_spine(&id)?;
+        let epub = state
+            .epubs()
+            .read()
+            .get(&id)
+            .cloned()
+            .context("Book not opened")?;
+
+        let mut list = (*epub.page_list()?).clone();
+        if let Some(spine) = spine {
+            for page in &mut list.pages {
+                page.cfi = spine.to_virtual_cfi(&page.cfi);
             }
         }
+        anyhow::Ok(list)
+    });
+
+    Ok(task.await.context("Failed to get the page list")??)
+}
+
+/// Returns the page containing a location, with its label and the number of pages.
+#[tauri::command]
+pub async fn get_page(
+    app: AppHandle,
+    id: String,
+    location: String,
+) -> Result<Option<PagePosition>, CommandError> {
+    let task = tauri::async_runtime::spawn_blocking(move || {
+        let state = app.state::<AppState>();
+        let location = match state.virtual_spine(&id)? {
+            Some(spine) => spine.to_original_cfi(&location),
+            None => location,
+        };
+        let cfi = Cfi::parse(&location).context("Invalid location")?;
+        let epub = state
+            .epubs()
+            .read()
+            .get(&id)
+            .cloned()
+            .context("Book not opened")?;
+
+        anyhow::Ok(epub.page_list()?.position(&cfi))
+    });
+
+    Ok(task.await.context("Failed to get the page")??)
+}
+
+/// Returns the location of the start of the page with a label, e.g. `120`.
+#[tauri::command]
+pub async fn get_page_location(
+    app: AppHandle,
+    id: String,
+    label: String,
+) -> Result<Option<String>, CommandError> {
+    let task = tauri::async_runtime::spawn_blocking(move || {
+        let state = app.state::<AppState>();
+        let spine = state.virtual_spine(&id)?;
+        let epub = state
+            .epubs()
+            .read()
+            .get(&id)
+            .cloned()
+            .context("Book not opened")?;
+
+        let list = epub.page_list()?;
+        let cfi = list.find_label(&label).map(|x| x.cfi.clone());
+        anyhow::Ok(match spine {
+            Some(spine) => cfi.map(|x| spine.to_virtual_cfi(&x)),
+            None => cfi,
+        })
+    });
+
+    Ok(task.await.context("Failed to find the page")??)
+}
+
+/// Returns the saved location of a book, once it has been placed again if the file changed.
+#[tauri::command]
+pub async fn get_progress(app: AppHandle, id: String) -> Result<Option<String>, CommandError> {
+    let task = tauri::async_runtime::spawn_blocking(move || {
+        let state = app.state::<AppState>();
+        state.wait_for_anchors(&id);
+
+        let spine = state.virtual_spine(&id)?;
+        let library = state.library().lock();
+        let location = library.books().get(&id).and_then(|x| x.location.to_owned());
+
+        // Locations are saved against the original spine.
+        anyhow::Ok(match spine {
+            Some(spine) => location.map(|x| spine.to_virtual_cfi(&x)),
+            None => location,
+        })
+    });
+
+    Ok(task.await.context("Failed to get the location")??)
+}
+
+/// Saves the location of a book, and where it is in the book for the library.
+#[tauri::command]
+pub async fn save_progress(
+    app: AppHandle,
+    id: String,
+    location: String,
+) -> Result<(), CommandError> {
+    Cfi::parse(&location).context("Invalid location")?;
+    let saved_at = SystemTime::now();
+
+    // Resolving the progress and the quote may read documents.
+    let task = tauri::async_runtime::spawn_blocking(move || {
+        let state = app.state::<AppState>();
+        let settings = state.render_settings(&id);
+        let location = match state.virtual_spine(&id)? {
+            Some(spine) => spine.to_original_cfi(&location),
+            None => location,
+        };
+        let cfi = Cfi::parse(&location).context("Invalid location")?;
+        let location = location.as_str();
+
+        let epub = state.epubs().read().get(&id).cloned();
+        let stats = {
+            let library = state.library().lock();
+            library.books().get(&id).and_then(|x| x.stats.clone())
+        };
+        // Resolved without holding the lock of the library, as documents may need to be read.
+        let mut progress = epub
+            .as_ref()
+            .and_then(|epub| ReadingProgress::new(epub, stats.as_ref(), &cfi));
+        let quote = epub.as_ref().and_then(|epub| TextQuote::new(epub, &cfi));
+        if let (Some(progress), Some(converter)) = (&mut progress, get_converter(&settings)) {
+            progress.toc_label = progress.toc_label.as_deref().map(|x| converter.convert(x));
+        }
+
+        let mut library = state.library().lock();
+
+        match library.books_mut().get_mut(&id) {
+            // A later location may have been saved while this one was being resolved.
+            Some(book) if book.last_read_at > saved_at => return Ok(()),
+            Some(book) => {
+                book.location = Some(location.to_string());
+                book.location_quote = quote;
+                book.progress = progress;
+                book.last_read_at = saved_at;
+            }
+            None => {
+                // May happen when user removed book with the reader window opening.
+                if let Some(epub) = &epub {
+                    library.books_mut().insert(
+                        id.clone(),
+                        Book {
+                            path: epub.path().to_string(),
+                            location: Some(location.to_string()),
+                            location_quote: quote,
+                            progress,
+                            last_read_at: saved_at,
+                            metadata: BookMetadata::new(epub),
+                            render: RenderSettings::default(),
+                            stats: None,
+                            bookmarks: Vec::new(),
+                            signature: get_file_signature(Path::new(epub.path().as_str())),
+                            lost_anchors: Vec::new(),
+                            warnings: epub.warnings(),
+                        },
+                    );
+                }
+            }
+        }
+
+        library.persist()?;
+        anyhow::Ok(())
+    });
+
+    Ok(task.await.context("Failed to save the progress")??)
+}
+
+/// Bookmarks the location in an opened book. The label is generated from the chapter and the
+/// text at the location.
+#[tauri::command]
+pub fn add_bookmark(
+    app: AppHandle,
+    id: &str,
+    location: &str,
+    title: Option<String>,
+) -> Result<Bookmark, CommandError> {
+    let state = app.state::<AppState>();
+    let spine = state.virtual_spine(id)?;
+    let location = match &spine {
+        Some(spine) => spine.to_original_cfi(location),
+        None => location.to_string(),
+    };
+    let cfi = Cfi::parse(&location).context("Invalid location")?;
+    let epub = state
+        .epubs()
+        .read()
+        .get(id)
+        .cloned()
+        .context("Book not opened")?;
+
+    let mut bookmark = Bookmark::new(&epub, &cfi, title);
+
+    let mut library = state.library().lock();
+    let book = library
+        .books_mut()
+        .get_mut(id)
+        .context("Book not found in library")?;
+    book.add_bookmark(bookmark.clone());
+    library.persist()?;
+
+    if let Some(spine) = spine {
+        bookmark.cfi = spine.to_virtual_cfi(&bookmark.cfi);
     }
+    Ok(bookmark)
+}
+
+/// Returns the bookmarks of a book sorted by location. CFIs point into the virtual chapters if
+/// large documents are split.
+#[tauri::command]
+pub fn list_bookmarks(app: AppHandle, id: &str) -> Result<Vec<Bookmark>, CommandError> {
+    let state = app.state::<AppState>();
+    let settings = state.render_settings(id);
+    let spine = state.virtual_spine(id)?;
+    let mut bookmarks = {
+        let library = state.library().lock();
+        let book = library
+            .books()
+            .get(id)
+            .context("Book not found in library")?;
+        book.bookmarks.clone()
+    };
 
+    let converter = get_converter(&settings);
+    for bookmark in &mut bookmarks {
+        if let Some(spine) = &spine {
+            bookmark.cfi = spine.to_virtual_cfi(&bookmark.cfi);
+        }
+        if let Some(converter) = converter {
+            bookmark.label = converter.convert(&bookmark.label);
+        }
+    }
+
+    Ok(bookmarks)
+}
+
+/// Sets the title of a bookmark, or removes it if `title` is empty.
+#[tauri::command]
+pub fn rename_bookmark(
+    app: AppHandle,
+    id: &str,
+    bookmark_id: &str,
+    title: Option<String>,
+) -> Result<(), CommandError> {
+    let state = app.state::<AppState>();
+    let mut library = state.library().lock();
+    let book = library
+        .books_mut()
+        .get_mut(id)
+        .context("Book not found in library")?;
+    let bookmark = book
+        .bookmarks
+        .iter_mut()
+        .find(|x| x.id == bookmark_id)
+        .context("Bookmark not found")?;
+    bookmark.title = title.filter(|x| !x.trim().is_empty());
     library.persist()?;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn remove_bookmark(app: AppHandle, id: &str, bookmark_id: &str) -> Result<(), CommandError> {
+    let state = app.state::<AppState>();
+    let mut library = state.library().lock();
+    let book = library
+        .books_mut()
+        .get_mut(id)
+        .context("Book not found in library")?;
+    book.bookmarks.retain(|x| x.id != bookmark_id);
+    library.persist()?;
+    Ok(())
+}
+
+/// Annotates a range in an opened book, quoting its text.
+#[tauri::command]
+pub fn create_annotation(
+    app: AppHandle,
+    id: &str,
+    location: &str,
+    changes: AnnotationChanges,
+) -> Result<Annotation, CommandError> {
+    let state = app.state::<AppState>();
+    let spine = state.virtual_spine(id)?;
+    let location = match &spine {
+        Some(spine) => spine.to_original_cfi(location),
+        None => location.to_string(),
+    };
+    let cfi = Cfi::parse(&location).context("Invalid location")?;
+    let epub = state
+        .epubs()
+        .read()
+        .get(id)
+        .cloned()
+        .context("Book not opened")?;
 
+    let mut annotation = Annotation::new(&epub, &cfi, changes)?;
+
+    let mut annotations = state.annotations().lock();
+    annotations.insert(id, annotation.clone());
+    annotations.persist()?;
+
+    if let Some(spine) = spine {
+        annotation.cfi = spine.to_virtual_cfi(&annotation.cfi);
+    }
+    Ok(annotation)
+}
+
+/// Changes the color, the style, the note or the tags of an annotation.
+#[tauri::command]
+pub fn update_annotation(
+    app: AppHandle,
+    id: &str,
+    annotation_id: &str,
+    changes: AnnotationChanges,
+) -> Result<(), CommandError> {
+    let state = app.state::<AppState>();
+    let mut annotations = state.annotations().lock();
+    let annotation = annotations
+        .get_mut(id, annotation_id)
+        .context("Annotation not found")?;
+    annotation.update(changes);
+    annotations.persist()?;
+    Ok(())
+}
+
+#[tauri::command]
+pub fn delete_annotation(
+    app: AppHandle,
+    id: &str,
+    annotation_id: &str,
+) -> Result<(), CommandError> {
+    let state = app.state::<AppState>();
+    let mut annotations = state.annotations().lock();
+    annotations
+        .remove(id, annotation_id)
+        .context("Annotation not found")?;
+    annotations.persist()?;
+    Ok(())
+}
+
+/// Returns the annotations of a book sorted by location. CFIs point into the virtual chapters
+/// if large documents are split.
+#[tauri::command]
+pub fn list_annotations(app: AppHandle, id: &str) -> Result<Vec<Annotation>, CommandError> {
+    let state = app.state::<AppState>();
+    let spine = state.virtual_spine(id)?;
+    let mut annotations = state.annotations().lock().get(id).to_vec();
+
+    if let Some(spine) = spine {
+        for annotation in &mut annotations {
+            annotation.cfi = spine.to_virtual_cfi(&annotation.cfi);
+        }
+    }
+
+    Ok(annotations)
+}
+
+/// Finds the annotations with a tag in all books. CFIs are against the original spines.
+#[tauri::command]
+pub fn find_annotations_by_tag(
+    app: AppHandle,
+    tag: &str,
+) -> Result<Vec<BookAnnotation>, CommandError> {
+    let state = app.state::<AppState>();
+    let annotations = state.annotations().lock();
+    Ok(annotations.find_by_tag(tag))
+}
+
+/// Exports the annotations of a book, grouped by chapter, to a file picked by the user. The
+/// format is chosen by the extension of the file. Returns whether a file was picked.
+#[tauri::command]
+pub async fn export_annotations(
+    app: AppHandle,
+    window: Window,
+    id: String,
+) -> Result<bool, CommandError> {
+    let task = tauri::async_runtime::spawn_blocking(move || {
+        let state = app.state::<AppState>();
+        let book = {
+            let library = state.library().lock();
+            let book = library.books().get(&id);
+            book.cloned().context("Book not found in library")?
+        };
+        let filters = [
+            ("Markdown", &["md"][..]),
+            ("JSON", &["json"]),
+            ("Readwise CSV", &["csv"]),
+            ("Web Annotations", &["jsonld"]),
+        ];
+        let Some(path) = pick_save_path(&window, &filters) else {
+            return Ok(false);
+        };
+
+        let extension = path.extension().map(|x| x.to_string_lossy());
+        let format = extension.and_then(|x| x.parse().ok()).unwrap_or_default();
+        let options = ExportOptions {
+            format,
+            links: format != ExportFormat::Csv,
+        };
+        let annotations = state.annotations().lock().get(&id).to_vec();
+        let epub = match state.epubs().read().get(&id).cloned() {
+            Some(epub) => epub,
+            None => Arc::new(EpubFile::open(Utf8NativePathBuf::from(book.path.clone()))?),
+        };
+
+        let output = export::export(&epub, &id, &book, &annotations, &options)?;
+        std::fs::write(path, output).context("Failed to write the export")?;
+        anyhow::Ok(true)
+    });
+
+    Ok(task.await.context("Failed to export the annotations")??)
+}
+
+/// The result of importing annotations into a book, by the app they come from.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum AnnotationImportReport {
+    WebAnnotation(ImportReport),
+    Koreader(KoreaderImportReport),
+}
+
+/// Imports annotations of a book from a file picked by the user: W3C Web Annotations, or the
+/// sidecar of KOReader. Returns `None` if no file was picked.
+#[tauri::command]
+pub async fn import_annotations(
+    app: AppHandle,
+    window: Window,
+    id: String,
+) -> Result<Option<AnnotationImportReport>, CommandError> {
+    let task = tauri::async_runtime::spawn_blocking(move || {
+        let filters = [
+            ("Web Annotations", &["jsonld", "json"][..]),
+            ("KOReader", &["lua"]),
+        ];
+        let Some(path) = pick_file(&window, &filters) else {
+            return Ok(None);
+        };
+
+        let state = app.state::<AppState>();
+        let is_lua = path
+            .extension()
+            .is_some_and(|x| x.eq_ignore_ascii_case("lua"));
+        let report = match is_lua {
+            true => AnnotationImportReport::Koreader(import_koreader_file(&state, &id, &path)?),
+            false => {
+                AnnotationImportReport::WebAnnotation(import_web_annotations(&state, &id, &path)?)
+            }
+        };
+        anyhow::Ok(Some(report))
+    });
+
+    Ok(task.await.context("Failed to import the annotations")??)
+}
+
+/// Imports annotations of a book from a file of W3C Web Annotations. They're placed by their
+/// CFIs, or by their quoted text if the CFIs are missing or point elsewhere.
+fn import_web_annotations(state: &AppState, id: &str, path: &Path) -> anyhow::Result<ImportReport> {
+    let json = std::fs::read_to_string(path).context("Failed to read the annotations")?;
+    let document: WebAnnotationDocument =
+        serde_json::from_str(&json).context("Invalid Web Annotation JSON")?;
+
+    let book = {
+        let library = state.library().lock();
+        let book = library.books().get(id);
+        book.cloned().context("Book not found in library")?
+    };
+    let existing = state.annotations().lock().get(id).to_vec();
+    let epub = match state.epubs().read().get(id).cloned() {
+        Some(epub) => epub,
+        None => Arc::new(EpubFile::open(Utf8NativePathBuf::from(book.path.clone()))?),
+    };
+
+    // Placed without holding the lock of the annotations, as documents may need to be read.
+    let (annotations, report) = document.import(&epub, &book, &existing);
+
+    let mut store = state.annotations().lock();
+    store.extend(id, annotations);
+    store.persist()?;
+    Ok(report)
+}
+
+/// Imports the highlights and notes of a Kindle's `My Clippings.txt`, picked by the user, into
+/// the books of the library with matching titles and authors. Returns `None` if no file was
+/// picked.
+#[tauri::command]
+pub async fn import_kindle_clippings(
+    app: AppHandle,
+    window: Window,
+) -> Result<Option<KindleImportReport>, CommandError> {
+    let task = tauri::async_runtime::spawn_blocking(move || {
+        let filters = [("Kindle Clippings", &["txt"][..])];
+        let Some(path) = pick_file(&window, &filters) else {
+            return Ok(None);
+        };
+        import_kindle_clippings_file(&app.state::<AppState>(), &path).map(Some)
+    });
+
+    Ok(task.await.context("Failed to import the clippings")??)
+}
+
+fn import_kindle_clippings_file(
+    state: &AppState,
+    path: &Path,
+) -> anyhow::Result<KindleImportReport> {
+    let content = std::fs::read(path).context("Failed to read the clippings")?;
+    let clippings = kindle::parse(&String::from_utf8_lossy(&content));
+
+    let books = state.library().lock().books().clone();
+
+    let mut result = KindleImportReport::default();
+    for (title, author, clippings) in kindle::group_by_book(&clippings) {
+        let label = match author {
+            Some(author) => format!("{} ({})", title, author),
+            None => title.to_string(),
+        };
+        let metadata = books.iter().map(|(id, book)| (id, &book.metadata));
+        let (id, book) = match kindle::find_books(title, author, metadata)[..] {
+            [id] => (id, &books[id]),
+            [] => {
+                result.unmatched.push(label);
+                continue;
+            }
+            _ => {
+                result.ambiguous.push(label);
+                continue;
+            }
+        };
+
+        let existing = state.annotations().lock().get(id).to_vec();
+        let epub = match state.epubs().read().get(id).cloned() {
+            Some(epub) => epub,
+            None => Arc::new(EpubFile::open(Utf8NativePathBuf::from(book.path.clone()))?),
+        };
+        // Placed without holding the lock of the annotations, as documents may need to be read.
+        let (annotations, report) = kindle::import(&epub, &clippings, &existing);
+
+        let mut store = state.annotations().lock();
+        store.extend(id, annotations);
+        store.persist()?;
+        result.books.push(KindleBookReport {
+            book_id: id.clone(),
+            title: title.to_string(),
+            report,
+        });
+    }
+
+    Ok(result)
+}
+
+/// Imports the highlights, bookmarks and progress of a book in KOReader from its sidecar next
+/// to the book.
+#[tauri::command]
+pub async fn import_koreader(
+    app: AppHandle,
+    id: String,
+) -> Result<KoreaderImportReport, CommandError> {
+    let task = tauri::async_runtime::spawn_blocking(move || {
+        let state = app.state::<AppState>();
+        let path = {
+            let library = state.library().lock();
+            let book = library
+                .books()
+                .get(&id)
+                .context("Book not found in library")?;
+            koreader::find_sidecar(Path::new(&book.path))
+                .context("No KOReader metadata found next to the book")?
+        };
+        import_koreader_file(&state, &id, &path)
+    });
+
+    Ok(task
+        .await
+        .context("Failed to import the KOReader metadata")??)
+}
+
+/// Imports the highlights, bookmarks and progress of a book in KOReader from a sidecar. The
+/// location of the book is only moved if KOReader was used since it was last read here.
+fn import_koreader_file(
+    state: &AppState,
+    id: &str,
+    path: &Path,
+) -> anyhow::Result<KoreaderImportReport> {
+    let settings = state.render_settings(id);
+    let book = {
+        let library = state.library().lock();
+        let book = library.books().get(id);
+        book.cloned().context("Book not found in library")?
+    };
+
+    let content = std::fs::read_to_string(path).context("Failed to read the KOReader metadata")?;
+    let modified = std::fs::metadata(path).and_then(|x| x.modified()).ok();
+    let sidecar = Sidecar::parse(&content)?;
+    if !sidecar.matches(&book.metadata) {
+        bail!("The KOReader metadata is of another book");
+    }
+
+    let existing = state.annotations().lock().get(id).to_vec();
+    let epub = match state.epubs().read().get(id).cloned() {
+        Some(epub) => epub,
+        None => Arc::new(EpubFile::open(Utf8NativePathBuf::from(book.path.clone()))?),
+    };
+    // Placed without holding the locks, as documents may need to be read.
+    let mut import = sidecar.import(&epub, book.stats.as_ref(), &existing, &book.bookmarks);
+    let location = match (import.location.take(), modified) {
+        (Some(cfi), Some(modified)) if modified > book.last_read_at => Some((cfi, modified)),
+        _ => None,
+    };
+    let location = location.map(|(cfi, modified)| {
+        let mut progress = ReadingProgress::new(&epub, book.stats.as_ref(), &cfi);
+        if let (Some(progress), Some(converter)) = (&mut progress, get_converter(&settings)) {
+            progress.toc_label = progress.toc_label.as_deref().map(|x| converter.convert(x));
+        }
+        let quote = TextQuote::new(&epub, &cfi);
+        (cfi.to_string(), quote, progress, modified)
+    });
+
+    let mut library = state.library().lock();
+    let book = library
+        .books_mut()
+        .get_mut(id)
+        .context("Book not found in library")?;
+    for bookmark in import.bookmarks {
+        book.add_bookmark(bookmark);
+    }
+    if let Some((location, quote, progress, modified)) = location {
+        book.location = Some(location);
+        book.location_quote = quote;
+        book.progress = progress;
+        book.last_read_at = modified;
+        import.report.location = book.location.clone();
+    }
+    library.persist()?;
+    drop(library);
+
+    let mut store = state.annotations().lock();
+    store.extend(id, import.annotations);
+    store.persist()?;
+    drop(store);
+
+    if let (Some(location), Some(spine)) = (&mut import.report.location, state.virtual_spine(id)?) {
+        *location = spine.to_virtual_cfi(location);
+    }
+    Ok(import.report)
+}
+
+/// Asks the user for a file to read, with filters of names and extensions. Files are picked here
+/// rather than by the webview, so that it can't have any path read.
+fn pick_file(window: &Window, filters: &[(&str, &[&str])]) -> Option<PathBuf> {
+    let mut dialog = FileDialogBuilder::new().set_parent(window);
+    for (name, extensions) in filters {
+        dialog = dialog.add_filter(name, extensions);
+    }
+    dialog.pick_file()
+}
+
+/// Asks the user for a file to write, with filters of names and extensions.
+fn pick_save_path(window: &Window, filters: &[(&str, &[&str])]) -> Option<PathBuf> {
+    let mut dialog = FileDialogBuilder::new().set_parent(window);
+    for (name, extensions) in filters {
+        dialog = dialog.add_filter(name, extensions);
+    }
+    dialog.save_file()
+}
+
+#[tauri::command]
+pub fn get_settings(app: AppHandle) -> Result<Settings, CommandError> {
+    let state = app.state::<AppState>();
+    let settings = state.settings().read().clone();
+    Ok(settings)
+}
+
+/// Changes of the transport only take effect on reader windows opened afterwards.
+#[tauri::command]
+pub fn save_settings(app: AppHandle, settings: Settings) -> Result<(), CommandError> {
+    let state = app.state::<AppState>();
+    let mut current = state.settings().write();
+    settings.persist()?;
+    *current = settings;
+    Ok(())
+}
+
+/// Returns the per-book render settings, without the global ones merged.
+#[tauri::command]
+pub fn get_book_settings(app: AppHandle, id: &str) -> Result<RenderSettings, CommandError> {
+    let state = app.state::<AppState>();
+    let library = state.library().lock();
+    let book = library
+        .books()
+        .get(id)
+        .context("Book not found in library")?;
+    Ok(book.render.clone())
+}
+
+#[tauri::command]
+pub fn save_book_settings(
+    app: AppHandle,
+    id: &str,
+    settings: RenderSettings,
+) -> Result<(), CommandError> {
+    let state = app.state::<AppState>();
+    let mut library = state.library().lock();
+    let book = library
+        .books_mut()
+        .get_mut(id)
+        .context("Book not found in library")?;
+    book.render = settings;
+    library.persist()?;
     Ok(())
 }
@@ -1,18 +1,34 @@
 use std::collections::HashMap;
 use std::io::BufReader;
+use std::sync::Arc;
 
 use anyhow::{bail, Context, Result};
+use parking_lot::Mutex;
 use serde::de::DeserializeOwned;
 use typed_path::{Utf8NativePath, Utf8NativePathBuf};
 
-use crate::zip::SharedZip;
+use crate::zip::{SharedZip, SharedZipEntry, ZipError};
 
 use self::container::EpubContainer;
-use self::rootfile::EpubRootfile;
-use self::toc::EpubToc;
+use self::pages::PageList;
+use self::rootfile::{EpubRootfile, EpubRootfileManifestItem};
+use self::split::{SplitDocument, VirtualSpine};
+use self::stats::BookStats;
+use self::text::DocumentText;
+use self::toc::{nav, EpubToc};
 
+pub mod anchor;
+pub mod cfi;
 pub mod container;
+pub mod encoding;
+pub mod media_type;
+pub mod pages;
+pub mod progress;
+pub mod repair;
 pub mod rootfile;
+pub mod split;
+pub mod stats;
+pub mod text;
 pub mod toc;
 
 #[derive(Debug)]
@@ -22,7 +38,22 @@ pub struct EpubFile {
     container: EpubContainer,
     rootfile: EpubRootfile,
     toc: EpubToc,
-    media_types: HashMap<String, String>,
+    /// Maps the resolved paths of manifest items to their indices in the manifest.
+    manifest: HashMap<String, usize>,
+    /// Maps the resolved paths of spine items to their first indices in the spine.
+    spine: HashMap<String, usize>,
+    /// Repaired documents, or `None` for the well-formed ones.
+    repairs: Mutex<HashMap<String, Option<Arc<str>>>>,
+    /// Problems found in the book while reading it.
+    warnings: Mutex<Vec<String>>,
+    /// The spine split with the last threshold asked for.
+    virtual_spine: Mutex<Option<Arc<VirtualSpine>>>,
+    /// The text of the last document asked for, which is usually the one being read.
+    document_text: Mutex<Option<(String, Arc<DocumentText>)>>,
+    /// The texts of all the documents asked for while any `KeepDocumentTexts` guard is alive,
+    /// with the number of guards.
+    kept_texts: Mutex<(usize, HashMap<String, Arc<DocumentText>>)>,
+    page_list: Mutex<Option<Arc<PageList>>>,
 }
 
 impl EpubFile {
@@ -37,16 +68,36 @@ impl EpubFile {
         let container = read_container(&zip).context("Invalid EPUB file")?;
         let rootfile = read_rootfile(&zip, &container).context("Invalid EPUB file")?;
 
-        let mut media_types = HashMap::new();
-        for item in &rootfile.package.manifest.children {
+        let mut manifest = HashMap::new();
+        for (index, item) in rootfile.package.manifest.children.iter().enumerate() {
             let path = rootfile.resolve_href(&item.href);
-            media_types.insert(path, item.media_type.clone());
+            manifest.insert(path, index);
+        }
+
+        let ids: HashMap<_, _> = rootfile
+            .package
+            .manifest
+            .children
+            .iter()
+            .map(|x| (&*x.id, &*x.href))
+            .collect();
+        let mut spine = HashMap::new();
+        for (index, item) in rootfile.package.spine.children.iter().enumerate() {
+            if let Some(href) = ids.get(&*item.idref) {
+                spine.entry(rootfile.resolve_href(href)).or_insert(index);
+            }
         }
 
         let toc = match &*rootfile.package.version {
             "2.0" => read_toc_ncx(&zip, &rootfile)?,
             // TODO: EPUB 3.0 new TOC
-            "3.0" => read_toc_ncx(&zip, &rootfile)?,
+            "3.0" => {
+                let mut toc = read_toc_ncx(&zip, &rootfile)?;
+                if let Some((path, pages)) = read_nav_page_list(&zip, &rootfile) {
+                    toc.set_nav_pages(&path, pages);
+                }
+                toc
+            }
             x => bail!("Unsupported EPUB version: {x}"),
         };
 
@@ -56,7 +107,14 @@ impl EpubFile {
             container,
             rootfile,
             toc,
-            media_types,
+            manifest,
+            spine,
+            repairs: Mutex::new(HashMap::new()),
+            warnings: Mutex::new(Vec::new()),
+            virtual_spine: Mutex::new(None),
+            document_text: Mutex::new(None),
+            kept_texts: Mutex::new((0, HashMap::new())),
+            page_list: Mutex::new(None),
         })
     }
 
@@ -76,13 +134,188 @@ impl EpubFile {
         &self.toc
     }
 
+    pub fn warnings(&self) -> Vec<String> {
+        self.warnings.lock().clone()
+    }
+
+    pub fn get_manifest_item(&self, path: &str) -> Option<&EpubRootfileManifestItem> {
+        let index = *self.manifest.get(path)?;
+        self.rootfile.package.manifest.children.get(index)
+    }
+
     pub fn get_media_type(&self, path: &str) -> Option<&str> {
-        self.media_types.get(path).map(String::as_str)
+        self.get_manifest_item(path).map(|item| &*item.media_type)
+    }
+
+    /// Returns the `idref` and the path of a spine item.
+    pub fn get_spine_path(&self, index: usize) -> Option<(&str, String)> {
+        let idref = &self.rootfile.package.spine.children.get(index)?.idref;
+        let manifest = &self.rootfile.package.manifest.children;
+        let item = manifest.iter().find(|x| x.id == *idref)?;
+        Some((idref, self.rootfile.resolve_href(&item.href)))
+    }
+
+    /// Returns the index of the first spine item with a path.
+    pub fn get_spine_index(&self, path: &str) -> Option<usize> {
+        self.spine.get(path).copied()
+    }
+
+    pub fn entry(&self, path: &str) -> Result<SharedZipEntry<'_>, ZipError> {
+        self.zip.entry(path)
     }
 
     pub fn read_file(&self, path: &str) -> Result<Vec<u8>> {
         self.zip.entry(path)?.bytes()
     }
+
+    /// Reads a text file, transcoded to UTF-8 if necessary.
+    pub fn read_text(&self, path: &str) -> Result<String> {
+        let bytes = self.read_file(path)?;
+        let media_type = match self.get_media_type(path) {
+            Some(media_type) => media_type,
+            None => media_type::sniff(path, &bytes),
+        };
+        Ok(encoding::decode(&bytes, media_type).into_owned())
+    }
+
+    /// Reads an XHTML document, repaired if necessary.
+    pub fn read_document(&self, path: &str) -> Result<String> {
+        let content = self.read_text(path)?;
+        Ok(self.repair_document(path, content))
+    }
+
+    /// Returns the plain text of an XHTML document. The last one is cached, and all of them
+    /// while `keep_document_texts` is in effect.
+    pub fn document_text(&self, path: &str) -> Result<Arc<DocumentText>> {
+        if let Some(text) = self.kept_texts.lock().1.get(path) {
+            return Ok(text.clone());
+        }
+        if let Some((cached, text)) = &*self.document_text.lock() {
+            if cached == path {
+                return Ok(text.clone());
+            }
+        }
+
+        let text = Arc::new(DocumentText::parse(&self.read_document(path)?)?);
+        *self.document_text.lock() = Some((path.to_string(), text.clone()));
+        let mut kept = self.kept_texts.lock();
+        if kept.0 > 0 {
+            kept.1.insert(path.to_string(), text.clone());
+        }
+        Ok(text)
+    }
+
+    /// Keeps the text of every document asked for until the guard is dropped, for passes which
+    /// read the whole book more than once.
+    pub fn keep_document_texts(&self) -> KeepDocumentTexts<'_> {
+        self.kept_texts.lock().0 += 1;
+        KeepDocumentTexts { epub: self }
+    }
+
+    /// Returns the stable page numbers of the book, computed once.
+    pub fn page_list(&self) -> Result<Arc<PageList>> {
+        if let Some(pages) = &*self.page_list.lock() {
+            return Ok(pages.clone());
+        }
+
+        let pages = Arc::new(PageList::new(self)?);
+        *self.page_list.lock() = Some(pages.clone());
+        Ok(pages)
+    }
+
+    /// Counts the words, characters and images of the spine documents, and scores readability.
+    pub fn stats(&self) -> Result<BookStats> {
+        BookStats::new(self)
+    }
+
+    /// Returns the spine with the XHTML documents larger than `threshold` bytes split into
+    /// virtual chapters. The result is cached until another threshold is asked for.
+    pub fn virtual_spine(&self, threshold: u64) -> Result<Arc<VirtualSpine>> {
+        if let Some(spine) = &*self.virtual_spine.lock() {
+            if spine.threshold == threshold {
+                return Ok(spine.clone());
+            }
+        }
+
+        let mut items = Vec::new();
+        for itemref in &self.rootfile.package.spine.children {
+            let item = self
+                .rootfile
+                .package
+                .manifest
+                .children
+                .iter()
+                .find(|x| x.id == itemref.idref);
+
+            let split = match item {
+                Some(item) if item.media_type == "application/xhtml+xml" => {
+                    let path = self.rootfile.resolve_href(&item.href);
+                    match self.entry(&path) {
+                        Ok(entry) if entry.size() > threshold => {
+                            let content = self.read_document(&path)?;
+                            SplitDocument::split(&path, &content, threshold as usize)?
+                        }
+                        _ => None,
+                    }
+                }
+                _ => None,
+            };
+
+            items.push((itemref.idref.clone(), split));
+        }
+
+        let spine = Arc::new(VirtualSpine::new(threshold, items));
+        *self.virtual_spine.lock() = Some(spine.clone());
+        Ok(spine)
+    }
+
+    /// Returns the content of a document, re-serialized from an HTML parser if it's not
+    /// well-formed. Repairs are cached and recorded in the warnings.
+    pub fn repair_document(&self, path: &str, content: String) -> String {
+        if let Some(repaired) = self.repairs.lock().get(path) {
+            return match repaired {
+                Some(repaired) => repaired.to_string(),
+                None => content,
+            };
+        }
+
+        // Don't hold the lock while parsing, which may take a while for large documents.
+        let repaired = match repair::is_well_formed(&content) {
+            true => None,
+            false => Some(Arc::from(repair::repair(&content))),
+        };
+
+        let first = self
+            .repairs
+            .lock()
+            .insert(path.to_string(), repaired.clone())
+            .is_none();
+
+        match repaired {
+            Some(repaired) => {
+                if first {
+                    let warning = format!("{path} is not well-formed XHTML and has been repaired");
+                    self.warnings.lock().push(warning);
+                }
+                repaired.to_string()
+            }
+            None => content,
+        }
+    }
+}
+
+pub struct KeepDocumentTexts<'a> {
+    epub: &'a EpubFile,
+}
+
+impl Drop for KeepDocumentTexts<'_> {
+    fn drop(&mut self) {
+        let mut kept = self.epub.kept_texts.lock();
+        kept.0 -= 1;
+        if kept.0 == 0 {
+            kept.1.clear();
+        }
+    }
 }
 
 pub fn read_xml<T: DeserializeOwned>(zip: &SharedZip, path: &str) -> Result<T> {
@@ -116,3 +349,24 @@ fn read_toc_ncx(zip: &SharedZip, rootfile: &EpubRootfile) -> Result<EpubToc> {
 
     Ok(EpubToc::new(path, ncx))
 }
+
+/// Reads the page list of the EPUB 3 navigation document, if any, and returns it with the path
+/// of the document.
+fn read_nav_page_list(
+    zip: &SharedZip,
+    rootfile: &EpubRootfile,
+) -> Option<(String, Vec<(String, String)>)> {
+    let manifest = &rootfile.package.manifest.children;
+    let item = manifest.iter().find(|x| x.has_property("nav"))?;
+    let path = rootfile.resolve_href(&item.href);
+
+    let bytes = zip.entry(&path).ok()?.bytes().ok()?;
+    let content = encoding::decode(&bytes, &item.media_type);
+    match nav::read_page_list(&content) {
+        Ok(pages) => Some((path, pages)),
+        Err(e) => {
+            eprintln!("Failed to read the page list of {path}:\n{:?}", e);
+            None
+        }
+    }
+}
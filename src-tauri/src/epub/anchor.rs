@@ -0,0 +1,235 @@
+use std::ops::Range;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use super::cfi::Cfi;
+use super::media_type::is_document;
+use super::text::DocumentText;
+use super::EpubFile;
+
+/// The number of characters of context quoted before and after a location.
+const CONTEXT_LENGTH: usize = 32;
+
+/// The characters of a quote, or of the context found around it, needed to place it without the
+/// rest, as shorter text is likely to appear more than once.
+const MIN_MATCH_LENGTH: usize = 16;
+
+/// The text at a location with the text around it, to find the location again if the book
+/// changes. Locations which aren't ranges quote no text.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TextQuote {
+    pub text: String,
+    pub prefix: String,
+    pub suffix: String,
+}
+
+/// How well a quote matches some text: whether the quoted text matches, and the characters of
+/// context that match.
+type Score = (bool, usize);
+
+impl TextQuote {
+    /// Quotes the text at a location saved against the original spine.
+    pub fn new(epub: &EpubFile, cfi: &Cfi) -> Option<Self> {
+        let (_, path) = epub.get_spine_path(cfi.spine_index()?)?;
+        let text = epub.document_text(&path).ok()?;
+        let range = cfi.resolve(&text)?;
+        Some(Self::from_text(&text.text, range))
+    }
+
+    fn from_text(text: &str, range: Range<usize>) -> Self {
+        let before = &text[..range.start];
+        let skipped = before.chars().rev().take(CONTEXT_LENGTH);
+        let prefix = &before[before.len() - skipped.map(char::len_utf8).sum::<usize>()..];
+
+        let after = &text[range.end..];
+        let taken = after.chars().take(CONTEXT_LENGTH);
+        let suffix = &after[..taken.map(char::len_utf8).sum::<usize>()];
+
+        Self {
+            text: text[range].to_string(),
+            prefix: prefix.to_string(),
+            suffix: suffix.to_string(),
+        }
+    }
+
+    /// Finds the quote in a book, and returns its location against the original spine. `hint` is
+    /// where the quote was before, and its document is searched first.
+    pub fn find(&self, epub: &EpubFile, hint: Option<&Cfi>) -> Option<Cfi> {
+        let count = epub.rootfile().package.spine.children.len();
+        // The document may have moved in the spine.
+        let first = hint.and_then(|hint| {
+            let idref = hint.spine_idref();
+            let index = idref.and_then(|idref| {
+                (0..count).find(|x| epub.get_spine_path(*x).is_some_and(|(id, _)| id == idref))
+            });
+            index.or(hint.spine_index())
+        });
+
+        let current = first.and_then(|x| Some((x, epub.get_spine_path(x)?)));
+        if let (Some(hint), Some((index, (idref, path)))) = (hint, current) {
+            let text = epub.document_text(&path).ok();
+            let range = text.as_ref().and_then(|text| {
+                let range = hint.resolve(text)?;
+                self.is_at(&text.text, range.clone()).then_some(range)
+            });
+            if let (Some(text), Some(range)) = (text, range) {
+                return to_cfi(index, idref, &text, range);
+            }
+        }
+
+        let first = first.unwrap_or_default();
+        let mut order: Vec<usize> = (0..count).collect();
+        order.sort_by_key(|x| (x.abs_diff(first), *x < first));
+
+        let best_score = (
+            !self.text.is_empty(),
+            self.prefix.chars().count() + self.suffix.chars().count(),
+        );
+        let mut best: Option<(Score, usize, Arc<DocumentText>, Range<usize>)> = None;
+
+        for index in order {
+            let Some((_, path)) = epub.get_spine_path(index) else {
+                continue;
+            };
+            if !epub.get_media_type(&path).is_some_and(is_document) {
+                continue;
+            }
+            let Ok(text) = epub.document_text(&path) else {
+                continue;
+            };
+
+            if let Some((score, range)) = self.find_in(&text.text) {
+                if best.as_ref().is_none_or(|x| score > x.0) {
+                    best = Some((score, index, text, range));
+                }
+                if score == best_score {
+                    break;
+                }
+            }
+        }
+
+        let (_, index, text, range) = best?;
+        let (idref, _) = epub.get_spine_path(index)?;
+        to_cfi(index, idref, &text, range)
+    }
+
+    /// Whether the quote is at a range of some text, with all of its context on at least one side,
+    /// or on both sides if it quotes no text.
+    fn is_at(&self, text: &str, range: Range<usize>) -> bool {
+        let (Some(before), Some(quoted), Some(after)) = (
+            text.get(..range.start),
+            text.get(range.clone()),
+            text.get(range.end..),
+        ) else {
+            return false;
+        };
+        let (prefix, suffix) = (
+            before.ends_with(&self.prefix),
+            after.starts_with(&self.suffix),
+        );
+        quoted == self.text
+            && match self.text.is_empty() {
+                true => prefix && suffix,
+                false => prefix || suffix,
+            }
+    }
+
+    /// Finds the best match of the quote in some text. The quoted text is looked for with its
+    /// context around it, and if it's not found, as it may have been corrected, the range between
+    /// its prefix and suffix is used instead.
+    fn find_in(&self, text: &str) -> Option<(Score, Range<usize>)> {
+        let mut best: Option<(Score, Range<usize>)> = None;
+
+        if !self.text.is_empty() {
+            let length = self.text.chars().count();
+            for (start, _) in text.match_indices(&self.text) {
+                let range = start..start + self.text.len();
+                let context = common_suffix(&text[..start], &self.prefix)
+                    + common_prefix(&text[range.end..], &self.suffix);
+                if context > 0 || length >= MIN_MATCH_LENGTH {
+                    consider(&mut best, (true, context), range);
+                }
+            }
+            if best.is_some() {
+                return best;
+            }
+        }
+
+        let prefix_length = self.prefix.chars().count();
+        let suffix_length = self.suffix.chars().count();
+        // The quoted text may have changed, but not beyond recognition.
+        let window = self.text.len() * 2 + CONTEXT_LENGTH;
+
+        // A prefix shorter than the context means that the quote was at the start of the text,
+        // and a shorter suffix that it was at the end.
+        let starts: Vec<usize> = match prefix_length < CONTEXT_LENGTH {
+            true => Vec::from_iter(text.starts_with(&self.prefix).then_some(self.prefix.len())),
+            false => text
+                .match_indices(&self.prefix)
+                .map(|(x, _)| x + self.prefix.len())
+                .collect(),
+        };
+
+        for start in starts {
+            let Some(rest) = text.get(start..) else {
+                continue;
+            };
+            let end = match suffix_length < CONTEXT_LENGTH {
+                true => rest
+                    .ends_with(&self.suffix)
+                    .then_some(text.len() - self.suffix.len()),
+                false => rest
+                    .match_indices(&self.suffix)
+                    .map(|(x, _)| start + x)
+                    .next(),
+            };
+            match end {
+                Some(end) if end - start <= window => {
+                    let context = prefix_length + suffix_length;
+                    // Locations which aren't ranges are placed before their suffix.
+                    let range = match self.text.is_empty() {
+                        true => end..end,
+                        false => start..end,
+                    };
+                    if context >= MIN_MATCH_LENGTH {
+                        consider(&mut best, (false, context), range);
+                    }
+                }
+                // Or after their prefix, if the suffix isn't found.
+                _ if self.text.is_empty() && prefix_length >= MIN_MATCH_LENGTH => {
+                    consider(&mut best, (false, prefix_length), start..start);
+                }
+                _ => {}
+            }
+        }
+
+        best
+    }
+}
+
+fn consider(best: &mut Option<(Score, Range<usize>)>, score: Score, range: Range<usize>) {
+    if best.as_ref().is_none_or(|x| score > x.0) {
+        *best = Some((score, range));
+    }
+}
+
+/// Returns the CFI of a range of a spine document, or of its start if it's empty.
+fn to_cfi(index: usize, idref: &str, text: &DocumentText, range: Range<usize>) -> Option<Cfi> {
+    match range.is_empty() {
+        true => Some(Cfi::new(index, idref, &text.position(range.start, false)?)),
+        false => Cfi::from_text_range(index, idref, text, range),
+    }
+}
+
+/// Counts the characters at the end of `text` which match the end of `context`.
+fn common_suffix(text: &str, context: &str) -> usize {
+    let pairs = text.chars().rev().zip(context.chars().rev());
+    pairs.take_while(|(a, b)| a == b).count()
+}
+
+/// Counts the characters at the start of `text` which match the start of `context`.
+fn common_prefix(text: &str, context: &str) -> usize {
+    let pairs = text.chars().zip(context.chars());
+    pairs.take_while(|(a, b)| a == b).count()
+}
@@ -0,0 +1,743 @@
+use std::cmp::Ordering;
+use std::fmt::{self, Display, Formatter, Write};
+use std::ops::Range;
+use std::str::FromStr;
+
+use anyhow::{bail, Error, Result};
+
+use super::text::{DocumentText, DomPosition};
+
+/// Characters escaped with `^` in assertions.
+const SPECIAL_CHARACTERS: &[char] = &['^', '[', ']', '(', ')', ',', ';', '='];
+
+/// An EPUB Canonical Fragment Identifier, e.g. `epubcfi(/6/4[chap01]!/4/2/1:3)`, or a range,
+/// e.g. `epubcfi(/6/4[chap01]!/4/2,/1:3,/3:5)`.
+///
+/// See https://idpf.org/epub/linking/cfi/epub-cfi.html
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cfi {
+    /// The path, or the parent path of a range.
+    pub path: CfiPath,
+    /// The start and end paths of a range, relative to the parent path.
+    pub range: Option<(CfiPath, CfiPath)>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CfiPath {
+    pub steps: Vec<CfiStep>,
+    pub offset: Option<CfiOffset>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CfiStep {
+    /// Even indices select elements, and odd ones the text before, between or after them.
+    pub index: usize,
+    /// Whether the step is preceded by `!`, i.e. goes into the document referenced by the
+    /// previous step.
+    pub indirect: bool,
+    /// The assertion in brackets, e.g. the ID of the element, escaped as in the CFI.
+    pub assertion: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CfiOffset {
+    /// An offset in a text node, in UTF-16 code units, with an optional text location assertion.
+    Character {
+        offset: usize,
+        assertion: Option<String>,
+    },
+    /// An offset in seconds into audio or video, with an optional point in its frames.
+    Temporal {
+        seconds: f64,
+        point: Option<(f64, f64)>,
+    },
+    /// A point in an image or a video, as percentages of its width and height.
+    Spatial { x: f64, y: f64 },
+}
+
+impl Cfi {
+    pub fn parse(cfi: &str) -> Result<Self> {
+        let Some(inner) = cfi
+            .strip_prefix("epubcfi(")
+            .and_then(|x| x.strip_suffix(')'))
+        else {
+            bail!("Invalid CFI {cfi:?}: expected epubcfi(...)");
+        };
+
+        let mut parser = Parser {
+            input: inner,
+            position: 0,
+        };
+        let path = parser.parse_path(false)?;
+
+        let range = match parser.peek() {
+            Some(',') => {
+                parser.next();
+                let start = parser.parse_path(true)?;
+                parser.expect(',')?;
+                let end = parser.parse_path(true)?;
+                Some((start, end))
+            }
+            _ => None,
+        };
+
+        if parser.peek().is_some() {
+            return Err(parser.error("unexpected character"));
+        }
+
+        Ok(Self { path, range })
+    }
+
+    /// Returns the CFI of a position in the document at a spine index.
+    pub fn new(spine_index: usize, idref: &str, position: &DomPosition) -> Self {
+        let mut path = make_spine_path(spine_index, idref);
+        path.steps.extend(make_steps(&position.steps, true));
+        path.offset = Some(CfiOffset::Character {
+            offset: position.offset,
+            assertion: None,
+        });
+
+        Self { path, range: None }
+    }
+
+    /// Returns the CFI of a range between two positions in the document at a spine index. The
+    /// parent path is the text node itself if the range doesn't cross nodes.
+    pub fn new_range(
+        spine_index: usize,
+        idref: &str,
+        start: &DomPosition,
+        end: &DomPosition,
+    ) -> Self {
+        let common = start
+            .steps
+            .iter()
+            .zip(&end.steps)
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        let mut path = make_spine_path(spine_index, idref);
+        path.steps.extend(make_steps(&start.steps[..common], true));
+
+        let make_local_path = |position: &DomPosition| CfiPath {
+            steps: make_steps(&position.steps[common..], common == 0).collect(),
+            offset: Some(CfiOffset::Character {
+                offset: position.offset,
+                assertion: None,
+            }),
+        };
+
+        Self {
+            path,
+            range: Some((make_local_path(start), make_local_path(end))),
+        }
+    }
+
+    /// Returns the CFI of a range of the text of the document at a spine index.
+    pub fn from_text_range(
+        spine_index: usize,
+        idref: &str,
+        text: &DocumentText,
+        range: Range<usize>,
+    ) -> Option<Self> {
+        let start = text.position(range.start, false)?;
+        let end = text.position(range.end, true)?;
+        Some(Self::new_range(spine_index, idref, &start, &end))
+    }
+
+    pub fn is_range(&self) -> bool {
+        self.range.is_some()
+    }
+
+    /// Returns the full path to the start of a range, or the path itself.
+    pub fn start(&self) -> CfiPath {
+        match &self.range {
+            Some((start, _)) => self.path.join(start),
+            None => self.path.clone(),
+        }
+    }
+
+    /// Returns the full path to the end of a range, or the path itself.
+    pub fn end(&self) -> CfiPath {
+        match &self.range {
+            Some((_, end)) => self.path.join(end),
+            None => self.path.clone(),
+        }
+    }
+
+    /// Returns the CFI of the start or the end of a range.
+    pub fn collapse(&self, to_start: bool) -> Self {
+        let path = match to_start {
+            true => self.start(),
+            false => self.end(),
+        };
+        Self { path, range: None }
+    }
+
+    /// Returns the index of the spine item the CFI points into, from the step followed by the
+    /// first indirection, or the last step if it points to the spine item itself.
+    pub fn spine_index(&self) -> Option<usize> {
+        (self.spine_step()?.index / 2).checked_sub(1)
+    }
+
+    /// Returns the ID asserted on the spine step, i.e. the `idref` of the spine item.
+    pub fn spine_idref(&self) -> Option<String> {
+        self.spine_step()?.id()
+    }
+
+    /// Points the CFI into another spine item, keeping the path in the document.
+    pub fn set_spine_item(&mut self, spine_index: usize, idref: &str) -> Option<()> {
+        let step = self.spine_step_index()?;
+        self.path.steps[step] = CfiStep {
+            index: (spine_index + 1) * 2,
+            indirect: false,
+            assertion: Some(escape(idref)),
+        };
+        Some(())
+    }
+
+    /// Returns the steps at a depth of the document the CFI points into, i.e. after the first
+    /// indirection: the step of the path, or else the steps of the start and end of a range.
+    pub fn document_steps_mut(&mut self, depth: usize) -> Vec<&mut CfiStep> {
+        let Some(indirection) = self.path.steps.iter().position(|x| x.indirect) else {
+            return Vec::new();
+        };
+        let index = indirection + depth;
+        if index < self.path.steps.len() {
+            return vec![&mut self.path.steps[index]];
+        }
+
+        let local = index - self.path.steps.len();
+        match &mut self.range {
+            Some((start, end)) => [start, end]
+                .into_iter()
+                .filter_map(|x| x.steps.get_mut(local))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn spine_step(&self) -> Option<&CfiStep> {
+        self.path.steps.get(self.spine_step_index()?)
+    }
+
+    fn spine_step_index(&self) -> Option<usize> {
+        let steps = &self.path.steps;
+        match steps.iter().position(|x| x.indirect) {
+            Some(indirection) => indirection.checked_sub(1),
+            None if steps.len() == 2 && self.range.is_none() => Some(1),
+            None => None,
+        }
+    }
+
+    /// Orders CFIs by the positions they point to in the book. Ranges are ordered by their
+    /// starts, then by their ends.
+    pub fn compare(&self, other: &Self) -> Ordering {
+        let start = self.start().compare(&other.start());
+        start.then_with(|| self.end().compare(&other.end()))
+    }
+
+    /// Maps the CFI to a range of the text of its document, which is empty if it isn't a range.
+    pub fn resolve(&self, text: &DocumentText) -> Option<Range<usize>> {
+        let start = text.offset(&self.start().position()?)?;
+        let end = match &self.range {
+            Some(_) => text.offset(&self.end().position()?)?,
+            None => start,
+        };
+        Some(start..end.max(start))
+    }
+}
+
+impl FromStr for Cfi {
+    type Err = Error;
+
+    fn from_str(cfi: &str) -> Result<Self> {
+        Self::parse(cfi)
+    }
+}
+
+impl Display for Cfi {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "epubcfi({}", self.path)?;
+        if let Some((start, end)) = &self.range {
+            write!(f, ",{start},{end}")?;
+        }
+        f.write_char(')')
+    }
+}
+
+impl CfiPath {
+    fn join(&self, local: &CfiPath) -> CfiPath {
+        let mut steps = self.steps.clone();
+        steps.extend(local.steps.iter().cloned());
+        CfiPath {
+            steps,
+            offset: local.offset.clone().or_else(|| self.offset.clone()),
+        }
+    }
+
+    /// Returns the position in the document the path points into, after the first indirection.
+    pub fn position(&self) -> Option<DomPosition> {
+        let indirection = self.steps.iter().position(|x| x.indirect)?;
+        let steps = self.steps[indirection..].iter().map(|x| x.index).collect();
+        let offset = match &self.offset {
+            Some(CfiOffset::Character { offset, .. }) => *offset,
+            _ => 0,
+        };
+        Some(DomPosition { steps, offset })
+    }
+
+    /// Orders paths in document order. An element comes before its content, and a position
+    /// without an offset comes before the ones with offsets.
+    pub fn compare(&self, other: &Self) -> Ordering {
+        for (a, b) in self.steps.iter().zip(&other.steps) {
+            match a.index.cmp(&b.index) {
+                Ordering::Equal => continue,
+                ordering => return ordering,
+            }
+        }
+
+        let steps = self.steps.len().cmp(&other.steps.len());
+        steps.then_with(|| match (&self.offset, &other.offset) {
+            (Some(a), Some(b)) => a.compare(b),
+            (a, b) => a.is_some().cmp(&b.is_some()),
+        })
+    }
+}
+
+impl Display for CfiPath {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for step in &self.steps {
+            if step.indirect {
+                f.write_char('!')?;
+            }
+            write!(f, "/{}", step.index)?;
+            if let Some(assertion) = &step.assertion {
+                write!(f, "[{assertion}]")?;
+            }
+        }
+
+        match &self.offset {
+            Some(CfiOffset::Character { offset, assertion }) => {
+                write!(f, ":{offset}")?;
+                if let Some(assertion) = assertion {
+                    write!(f, "[{assertion}]")?;
+                }
+            }
+            Some(CfiOffset::Temporal { seconds, point }) => {
+                write!(f, "~{seconds}")?;
+                if let Some((x, y)) = point {
+                    write!(f, "@{x}:{y}")?;
+                }
+            }
+            Some(CfiOffset::Spatial { x, y }) => write!(f, "@{x}:{y}")?,
+            None => {}
+        }
+
+        Ok(())
+    }
+}
+
+impl CfiStep {
+    /// Returns the ID in the assertion, without the parameters following `;`.
+    pub fn id(&self) -> Option<String> {
+        let assertion = self.assertion.as_ref()?;
+        let id = unescape(split_unescaped(assertion, ';').first()?);
+        Some(id).filter(|x| !x.is_empty())
+    }
+}
+
+impl CfiOffset {
+    fn compare(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Self::Character { offset: a, .. }, Self::Character { offset: b, .. }) => a.cmp(b),
+            (Self::Temporal { seconds: a, .. }, Self::Temporal { seconds: b, .. }) => {
+                a.total_cmp(b)
+            }
+            (Self::Spatial { x: ax, y: ay }, Self::Spatial { x: bx, y: by }) => {
+                ay.total_cmp(by).then_with(|| ax.total_cmp(bx))
+            }
+            _ => Ordering::Equal,
+        }
+    }
+}
+
+/// Orders two CFIs by the positions they point to. Malformed ones come last.
+pub fn compare_locations(a: &str, b: &str) -> Ordering {
+    match (Cfi::parse(a), Cfi::parse(b)) {
+        (Ok(a), Ok(b)) => a.compare(&b),
+        (a, b) => b.is_ok().cmp(&a.is_ok()),
+    }
+}
+
+/// Escapes the special characters of a value in an assertion.
+pub fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if SPECIAL_CHARACTERS.contains(&c) {
+            escaped.push('^');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+pub fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '^' => unescaped.extend(chars.next()),
+            c => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+/// Splits an escaped value at the separators which aren't escaped.
+fn split_unescaped(value: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+
+    for (index, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '^' => escaped = true,
+            _ if c == separator => {
+                parts.push(&value[start..index]);
+                start = index + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+
+    parts.push(&value[start..]);
+    parts
+}
+
+/// The steps from the package document to a spine item: `/6` selects `<spine>`.
+fn make_spine_path(spine_index: usize, idref: &str) -> CfiPath {
+    let steps = vec![
+        CfiStep {
+            index: 6,
+            indirect: false,
+            assertion: None,
+        },
+        CfiStep {
+            index: (spine_index + 1) * 2,
+            indirect: false,
+            assertion: Some(escape(idref)),
+        },
+    ];
+    CfiPath {
+        steps,
+        offset: None,
+    }
+}
+
+/// Makes steps from their indices. The first one goes into the document if `indirect` is true.
+fn make_steps(indices: &[usize], indirect: bool) -> impl Iterator<Item = CfiStep> + '_ {
+    indices.iter().enumerate().map(move |(i, index)| CfiStep {
+        index: *index,
+        indirect: indirect && i == 0,
+        assertion: None,
+    })
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<char> {
+        self.input[self.position..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += c.len_utf8();
+        Some(c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            _ => Err(self.error(&format!("expected {expected:?}"))),
+        }
+    }
+
+    fn error(&self, message: &str) -> Error {
+        anyhow::anyhow!(
+            "Invalid CFI \"epubcfi({})\" at {}: {message}",
+            self.input,
+            self.position
+        )
+    }
+
+    /// Parses steps followed by an optional offset. Only the local paths of ranges may be empty.
+    fn parse_path(&mut self, allow_empty: bool) -> Result<CfiPath> {
+        let mut path = CfiPath::default();
+
+        loop {
+            let indirect = match self.peek() {
+                Some('!') => {
+                    self.next();
+                    true
+                }
+                Some('/') => false,
+                _ => break,
+            };
+            self.expect('/')?;
+
+            let index = self.parse_integer()?;
+            let assertion = self.parse_assertion()?;
+            path.steps.push(CfiStep {
+                index,
+                indirect,
+                assertion,
+            });
+        }
+
+        path.offset = match self.peek() {
+            Some(':') => {
+                self.next();
+                let offset = self.parse_integer()?;
+                let assertion = self.parse_assertion()?;
+                Some(CfiOffset::Character { offset, assertion })
+            }
+            Some('~') => {
+                self.next();
+                let seconds = self.parse_number()?;
+                let point = match self.peek() {
+                    Some('@') => Some(self.parse_point()?),
+                    _ => None,
+                };
+                Some(CfiOffset::Temporal { seconds, point })
+            }
+            Some('@') => {
+                let (x, y) = self.parse_point()?;
+                Some(CfiOffset::Spatial { x, y })
+            }
+            _ => None,
+        };
+
+        if path.steps.is_empty() && (!allow_empty || path.offset.is_none()) {
+            return Err(self.error("expected a step"));
+        }
+        Ok(path)
+    }
+
+    fn parse_integer(&mut self) -> Result<usize> {
+        let digits = self.take_while(|c| c.is_ascii_digit());
+        digits
+            .parse()
+            .map_err(|_| self.error("expected an integer"))
+    }
+
+    fn parse_number(&mut self) -> Result<f64> {
+        let number = self.take_while(|c| c.is_ascii_digit() || c == '.');
+        number.parse().map_err(|_| self.error("expected a number"))
+    }
+
+    fn parse_point(&mut self) -> Result<(f64, f64)> {
+        self.expect('@')?;
+        let x = self.parse_number()?;
+        self.expect(':')?;
+        let y = self.parse_number()?;
+        Ok((x, y))
+    }
+
+    /// Parses an assertion in brackets, which is kept escaped.
+    fn parse_assertion(&mut self) -> Result<Option<String>> {
+        if self.peek() != Some('[') {
+            return Ok(None);
+        }
+        self.next();
+
+        let start = self.position;
+        let mut escaped = false;
+        loop {
+            match self.next() {
+                None => return Err(self.error("unterminated assertion")),
+                Some(_) if escaped => escaped = false,
+                Some('^') => escaped = true,
+                Some(']') => break,
+                Some(_) => {}
+            }
+        }
+
+        Ok(Some(self.input[start..self.position - 1].to_string()))
+    }
+
+    fn take_while(&mut self, predicate: impl Fn(char) -> bool) -> &'a str {
+        let start = self.position;
+        while self.peek().is_some_and(&predicate) {
+            self.next();
+        }
+        &self.input[start..self.position]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(cfi: &str) -> Cfi {
+        Cfi::parse(cfi).unwrap()
+    }
+
+    #[test]
+    fn parses_paths_and_ranges() {
+        let cfi = parse("epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/3:10)");
+        assert_eq!(cfi.path.steps.len(), 5);
+        assert!(cfi.path.steps[2].indirect);
+        assert_eq!(cfi.path.steps[3].assertion.as_deref(), Some("para05"));
+        assert_eq!(
+            cfi.path.offset,
+            Some(CfiOffset::Character {
+                offset: 10,
+                assertion: None
+            })
+        );
+        assert!(!cfi.is_range());
+
+        let cfi = parse("epubcfi(/6/4!/4/10,/2/1:1,/3:4)");
+        let (start, end) = cfi.range.as_ref().unwrap();
+        assert_eq!(start.steps.len(), 2);
+        assert_eq!(end.steps.len(), 1);
+        assert_eq!(cfi.start().to_string(), "/6/4!/4/10/2/1:1");
+        assert_eq!(cfi.end().to_string(), "/6/4!/4/10/3:4");
+    }
+
+    #[test]
+    fn parses_other_offsets() {
+        let cfi = parse("epubcfi(/6/4!/4/2~23.5@50:25.5)");
+        let offset = CfiOffset::Temporal {
+            seconds: 23.5,
+            point: Some((50.0, 25.5)),
+        };
+        assert_eq!(cfi.path.offset, Some(offset));
+
+        let cfi = parse("epubcfi(/6/4!/4/2@20:80)");
+        assert_eq!(
+            cfi.path.offset,
+            Some(CfiOffset::Spatial { x: 20.0, y: 80.0 })
+        );
+    }
+
+    #[test]
+    fn keeps_escaped_assertions() {
+        let cfi = parse("epubcfi(/6/4[id^[1^];s=b]!/4/2/1:3[yyy^,zzz;s=a])");
+        assert_eq!(cfi.spine_idref().as_deref(), Some("id[1]"));
+        let offset = CfiOffset::Character {
+            offset: 3,
+            assertion: Some("yyy^,zzz;s=a".into()),
+        };
+        assert_eq!(cfi.path.offset, Some(offset));
+
+        assert_eq!(escape("a[b]^c"), "a^[b^]^^c");
+        assert_eq!(unescape(&escape("a[b]^c")), "a[b]^c");
+    }
+
+    #[test]
+    fn round_trips() {
+        for cfi in [
+            "epubcfi(/6/4[chap01ref]!/4[body01]/10[para05]/3:10)",
+            "epubcfi(/6/4!/4/10,/2/1:1,/3:4)",
+            "epubcfi(/6/14[chap05]!/4/2,/1:0,/1:12[abc])",
+            "epubcfi(/6/4!/4/2~23.5@50:25.5)",
+            "epubcfi(/6/4)",
+        ] {
+            assert_eq!(parse(cfi).to_string(), cfi);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed() {
+        for cfi in [
+            "",
+            "/6/4!/4/2",
+            "epubcfi()",
+            "epubcfi(/6/4!/4/x)",
+            "epubcfi(/6/4[abc!/4)",
+            "epubcfi(/6/4!/4/2,/1:0)",
+            "epubcfi(/6/4!/4/2:3 )",
+        ] {
+            assert!(Cfi::parse(cfi).is_err(), "{cfi}");
+        }
+    }
+
+    #[test]
+    fn finds_spine_items() {
+        let cfi = parse("epubcfi(/6/14[chap05]!/4/2/1:0)");
+        assert_eq!(cfi.spine_index(), Some(6));
+        assert_eq!(cfi.spine_idref().as_deref(), Some("chap05"));
+        assert_eq!(parse("epubcfi(/6/2)").spine_index(), Some(0));
+        assert_eq!(parse("epubcfi(/6/2/4)").spine_index(), None);
+
+        let mut cfi = parse("epubcfi(/6/14[chap05]!/4/2,/1:0,/1:3)");
+        cfi.set_spine_item(1, "a[b]").unwrap();
+        assert_eq!(cfi.to_string(), "epubcfi(/6/4[a^[b^]]!/4/2,/1:0,/1:3)");
+    }
+
+    #[test]
+    fn finds_document_steps() {
+        let mut cfi = parse("epubcfi(/6/4!/4/10,/2/1:1,/4/3:4)");
+        let steps: Vec<_> = cfi.document_steps_mut(1).iter().map(|x| x.index).collect();
+        assert_eq!(steps, [10]);
+        let steps: Vec<_> = cfi.document_steps_mut(2).iter().map(|x| x.index).collect();
+        assert_eq!(steps, [2, 4]);
+        assert!(cfi.document_steps_mut(4).is_empty());
+        assert!(parse("epubcfi(/6/4)").document_steps_mut(0).is_empty());
+    }
+
+    #[test]
+    fn compares_in_document_order() {
+        let ordered = [
+            "epubcfi(/6/2!/4/2/1:5)",
+            "epubcfi(/6/4!/4)",
+            "epubcfi(/6/4!/4/2/1:0)",
+            "epubcfi(/6/4!/4/2,/1:0,/1:3)",
+            "epubcfi(/6/4!/4/2,/1:0,/3:1)",
+            "epubcfi(/6/4!/4/2/1:2)",
+            "epubcfi(/6/4!/4/2/1:10)",
+            "epubcfi(/6/4!/4/10/1:0)",
+            "epubcfi(/6/12!/4/2/1:0)",
+        ];
+        for (i, a) in ordered.iter().enumerate() {
+            for (j, b) in ordered.iter().enumerate() {
+                assert_eq!(parse(a).compare(&parse(b)), i.cmp(&j), "{a} {b}");
+            }
+        }
+
+        assert_eq!(compare_locations("epubcfi(/6/4!/4)", "x"), Ordering::Less);
+        assert_eq!(
+            compare_locations("x", "epubcfi(/6/4!/4)"),
+            Ordering::Greater
+        );
+        assert_eq!(compare_locations("x", "y"), Ordering::Equal);
+    }
+
+    #[test]
+    fn makes_ranges_from_positions() {
+        let start = DomPosition {
+            steps: vec![4, 2, 1],
+            offset: 3,
+        };
+        let end = DomPosition {
+            steps: vec![4, 2, 1],
+            offset: 8,
+        };
+        let cfi = Cfi::new_range(2, "c3", &start, &end);
+        assert_eq!(cfi.to_string(), "epubcfi(/6/6[c3]!/4/2/1,:3,:8)");
+
+        let end = DomPosition {
+            steps: vec![4, 6, 1],
+            offset: 0,
+        };
+        let cfi = Cfi::new_range(0, "c1", &start, &end);
+        assert_eq!(cfi.to_string(), "epubcfi(/6/2[c1]!/4,/2/1:3,/6/1:0)");
+        assert_eq!(cfi.start().position(), Some(start));
+    }
+}
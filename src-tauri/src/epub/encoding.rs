@@ -0,0 +1,82 @@
+use std::borrow::Cow;
+use std::ops::Range;
+use std::sync::LazyLock;
+
+use chardetng::EncodingDetector;
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+use regex::bytes::Regex;
+
+use super::media_type::is_document;
+
+/// Encoding declarations are only searched for at the beginning of files.
+const DECLARATION_LIMIT: usize = 1024;
+
+/// Detects the encoding of a text file, trying in order the BOM, UTF-8 validity, the encoding
+/// declaration, and finally a guess from the content.
+///
+/// UTF-8 takes precedence over the declaration since many books are converted to UTF-8 without
+/// updating the declarations.
+pub fn detect(bytes: &[u8], media_type: &str) -> &'static Encoding {
+    if let Some((encoding, _)) = Encoding::for_bom(bytes) {
+        return encoding;
+    }
+
+    if std::str::from_utf8(bytes).is_ok() {
+        return UTF_8;
+    }
+
+    let declared = find_declaration(bytes, media_type)
+        .and_then(|range| Encoding::for_label(&bytes[range]))
+        // A UTF-16 declaration readable as ASCII can't be right.
+        .filter(|x| *x != UTF_16BE && *x != UTF_16LE);
+    if let Some(encoding) = declared {
+        return encoding;
+    }
+
+    let mut detector = EncodingDetector::new();
+    detector.feed(bytes, true);
+    detector.guess(None, true)
+}
+
+/// Decodes a text file into UTF-8, and updates its encoding declaration accordingly.
+pub fn decode<'a>(bytes: &'a [u8], media_type: &str) -> Cow<'a, str> {
+    let encoding = detect(bytes, media_type);
+    let (text, _) = encoding.decode_with_bom_removal(bytes);
+
+    if encoding == UTF_8 {
+        return text;
+    }
+
+    let mut text = text.into_owned();
+    if let Some(range) = find_declaration(text.as_bytes(), media_type) {
+        text.replace_range(range, "utf-8");
+    }
+
+    Cow::Owned(text)
+}
+
+/// Finds the encoding label in the XML declaration, `<meta>` or `@charset` rule.
+fn find_declaration(bytes: &[u8], media_type: &str) -> Option<Range<usize>> {
+    static XML: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r#"^\s*<\?xml\s[^>]*?\bencoding\s*=\s*["']([A-Za-z0-9._:-]+)["']"#).unwrap()
+    });
+    static META: LazyLock<Regex> = LazyLock::new(|| {
+        Regex::new(r#"(?i)<meta\s[^>]*?\bcharset\s*=\s*["']?([A-Za-z0-9._:-]+)"#).unwrap()
+    });
+    static CSS: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r#"^@charset\s+"([A-Za-z0-9._:-]+)";"#).unwrap());
+
+    let head = &bytes[..bytes.len().min(DECLARATION_LIMIT)];
+    let find = |regex: &Regex| {
+        regex
+            .captures(head)
+            .and_then(|x| x.get(1))
+            .map(|x| x.range())
+    };
+
+    match media_type {
+        "text/css" => find(&CSS),
+        media_type if is_document(media_type) => find(&XML).or_else(|| find(&META)),
+        _ => find(&XML),
+    }
+}
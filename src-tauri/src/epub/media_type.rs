@@ -0,0 +1,99 @@
+/// Guesses the media type of a file missing from the manifest, from the magic bytes at the
+/// beginning of `head`, or else from the extension.
+pub fn sniff(path: &str, head: &[u8]) -> &'static str {
+    if let Some(media_type) = sniff_magic(head) {
+        return media_type;
+    }
+
+    let extension = match path.rsplit_once('.') {
+        Some((_, extension)) => extension.to_ascii_lowercase(),
+        None => String::new(),
+    };
+
+    match &*extension {
+        "xhtml" | "xht" => "application/xhtml+xml",
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "svg" => "image/svg+xml",
+        "xml" => "application/xml",
+        "ncx" => "application/x-dtbncx+xml",
+        "opf" => "application/oebps-package+xml",
+        "smil" => "application/smil+xml",
+        "js" => "text/javascript",
+        "json" => "application/json",
+        "txt" => "text/plain",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "mp3" => "audio/mpeg",
+        "m4a" => "audio/mp4",
+        "ogg" | "oga" => "audio/ogg",
+        "mp4" | "m4v" => "video/mp4",
+        "webm" => "video/webm",
+        _ => sniff_markup(head),
+    }
+}
+
+/// Whether the resource is text, and thus may need transcoding to UTF-8.
+pub fn is_text(media_type: &str) -> bool {
+    media_type.starts_with("text/")
+        || media_type.ends_with("+xml")
+        || matches!(media_type, "application/xml" | "application/json")
+}
+
+pub fn is_document(media_type: &str) -> bool {
+    matches!(media_type, "application/xhtml+xml" | "text/html")
+}
+
+fn sniff_magic(head: &[u8]) -> Option<&'static str> {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"wOFF", "font/woff"),
+        (b"wOF2", "font/woff2"),
+        (b"OTTO", "font/otf"),
+        (b"\x00\x01\x00\x00", "font/ttf"),
+        (b"ID3", "audio/mpeg"),
+        (b"OggS", "audio/ogg"),
+        (b"\x1a\x45\xdf\xa3", "video/webm"),
+    ];
+
+    if let Some((_, media_type)) = SIGNATURES.iter().find(|(x, _)| head.starts_with(x)) {
+        return Some(media_type);
+    }
+
+    match head.get(..12)? {
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P'] => Some("image/webp"),
+        [_, _, _, _, b'f', b't', b'y', b'p', b'M', b'4', b'A', _] => Some("audio/mp4"),
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => Some("video/mp4"),
+        _ => None,
+    }
+}
+
+fn sniff_markup(head: &[u8]) -> &'static str {
+    if head.contains(&0) {
+        return "application/octet-stream";
+    }
+
+    let head = String::from_utf8_lossy(head).to_ascii_lowercase();
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+
+    if head.contains("<svg") {
+        "image/svg+xml"
+    } else if head.contains("<html") && head.starts_with("<?xml") {
+        "application/xhtml+xml"
+    } else if head.contains("<html") || head.starts_with("<!doctype html") {
+        "text/html"
+    } else if head.starts_with("<?xml") {
+        "application/xml"
+    } else {
+        "text/plain"
+    }
+}
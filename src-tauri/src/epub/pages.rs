@@ -0,0 +1,160 @@
+use anyhow::Result;
+use serde::Serialize;
+
+use super::cfi::Cfi;
+use super::media_type::is_document;
+use super::EpubFile;
+
+/// The characters of a synthetic page, as in the page numbers of Adobe's readers.
+const CHARACTERS_PER_PAGE: usize = 1024;
+
+/// Page numbers which don't change with the size of the window: the pages of the print edition
+/// if the book has a page list, or else pages of a fixed number of characters.
+#[derive(Debug, Clone, Serialize)]
+pub struct PageList {
+    /// Whether the pages come from the page list of the book.
+    pub print: bool,
+    pub pages: Vec<Page>,
+    #[serde(skip)]
+    cfis: Vec<Cfi>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Page {
+    pub label: String,
+    /// The index of the document in the original spine.
+    pub index: usize,
+    /// The location of the start of the page.
+    pub cfi: String,
+}
+
+/// The page containing a location, with the number of pages of the book.
+#[derive(Debug, Clone, Serialize)]
+pub struct PagePosition {
+    pub index: usize,
+    pub label: String,
+    pub count: usize,
+}
+
+impl PageList {
+    pub fn new(epub: &EpubFile) -> Result<Self> {
+        let mut pages = get_print_pages(epub)?;
+        let print = !pages.is_empty();
+        if !print {
+            pages = get_synthetic_pages(epub)?;
+        }
+
+        let mut list = Self {
+            print,
+            pages: Vec::new(),
+            cfis: Vec::new(),
+        };
+        for (page, cfi) in pages {
+            list.pages.push(page);
+            list.cfis.push(cfi);
+        }
+        Ok(list)
+    }
+
+    pub fn len(&self) -> usize {
+        self.pages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    /// Returns the index of the page containing a location saved against the original spine.
+    pub fn find_page(&self, location: &Cfi) -> Option<usize> {
+        let index = self.cfis.partition_point(|x| x.compare(location).is_le());
+        index.checked_sub(1)
+    }
+
+    /// Returns the page containing a location saved against the original spine.
+    pub fn position(&self, location: &Cfi) -> Option<PagePosition> {
+        let index = self.find_page(location)?;
+        Some(PagePosition {
+            index,
+            label: self.pages[index].label.clone(),
+            count: self.len(),
+        })
+    }
+
+    /// Finds a page by its label, e.g. `120` or `xiv`.
+    pub fn find_label(&self, label: &str) -> Option<&Page> {
+        let label = label.trim();
+        self.pages
+            .iter()
+            .find(|x| x.label.eq_ignore_ascii_case(label))
+    }
+}
+
+/// Returns the pages of the page list of the book, in reading order.
+fn get_print_pages(epub: &EpubFile) -> Result<Vec<(Page, Cfi)>> {
+    let mut pages = Vec::new();
+
+    for target in epub.toc().pages() {
+        let index = epub.get_spine_index(&target.path);
+        let Some((index, (idref, path))) = index.and_then(|x| Some((x, epub.get_spine_path(x)?)))
+        else {
+            continue;
+        };
+
+        let text = epub.document_text(&path)?;
+        let offset = target.fragment.as_ref().and_then(|x| text.anchors.get(x));
+        let position = text.position(offset.copied().unwrap_or_default(), false);
+        // Targets after the last text of a document are placed at its end.
+        let Some(position) = position.or_else(|| text.position(text.text.len(), true)) else {
+            continue;
+        };
+
+        let cfi = Cfi::new(index, idref, &position);
+        let page = Page {
+            label: target.label,
+            index,
+            cfi: cfi.to_string(),
+        };
+        pages.push((page, cfi));
+    }
+
+    // Page lists should be in reading order, but some aren't.
+    pages.sort_by(|a, b| a.1.compare(&b.1));
+    Ok(pages)
+}
+
+/// Breaks the documents into pages of a fixed number of characters. Each document starts a new
+/// page, so that editing a document doesn't move the page breaks in the others.
+fn get_synthetic_pages(epub: &EpubFile) -> Result<Vec<(Page, Cfi)>> {
+    let mut pages = Vec::new();
+
+    for index in 0..epub.rootfile().package.spine.children.len() {
+        let Some((idref, path)) = epub.get_spine_path(index) else {
+            continue;
+        };
+        if !epub.get_media_type(&path).is_some_and(is_document) {
+            continue;
+        }
+
+        let text = epub.document_text(&path)?;
+        let starts = text
+            .text
+            .char_indices()
+            .step_by(CHARACTERS_PER_PAGE)
+            .map(|(offset, _)| offset);
+
+        for offset in starts {
+            let Some(position) = text.position(offset, false) else {
+                continue;
+            };
+            let cfi = Cfi::new(index, idref, &position);
+            let page = Page {
+                label: (pages.len() + 1).to_string(),
+                index,
+                cfi: cfi.to_string(),
+            };
+            pages.push((page, cfi));
+        }
+    }
+
+    Ok(pages)
+}
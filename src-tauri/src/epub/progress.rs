@@ -0,0 +1,162 @@
+use serde::{Deserialize, Serialize};
+
+use super::cfi::Cfi;
+use super::stats::BookStats;
+use super::text::DocumentText;
+use super::toc::EpubTocEntry;
+use super::EpubFile;
+
+/// The position of a saved location in the book, computed when it's saved.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReadingProgress {
+    /// The index of the current document in the original spine.
+    pub index: usize,
+    /// The number of documents in the spine.
+    pub count: usize,
+    /// The position in the current document, from 0 to 1.
+    pub fraction: f64,
+    /// The position in the book, from 0 to 1, weighted by the characters of the documents.
+    pub percentage: f64,
+    /// The current entry of the table of contents, as an index into its entries in reading
+    /// order, and the number of entries.
+    pub toc_index: Option<usize>,
+    pub toc_count: usize,
+    pub toc_label: Option<String>,
+}
+
+impl ReadingProgress {
+    /// Resolves a location saved against the original spine. The documents are weighted by
+    /// their characters if the stats of the book are known, or else by their sizes.
+    pub fn new(epub: &EpubFile, stats: Option<&BookStats>, cfi: &Cfi) -> Option<Self> {
+        let index = cfi.spine_index()?;
+        let count = epub.rootfile().package.spine.children.len();
+        if index >= count {
+            return None;
+        }
+
+        let (_, path) = epub.get_spine_path(index)?;
+        let text = epub.document_text(&path).ok()?;
+        let offset = cfi.start().position().and_then(|x| text.offset(&x));
+        let offset = offset.unwrap_or_default();
+
+        let read = count_characters(&text.text[..offset]);
+        let fraction = match count_characters(&text.text) {
+            0 => 0.0,
+            total => read as f64 / total as f64,
+        };
+
+        let weights = get_weights(epub, stats);
+        let before: f64 = weights[..index].iter().sum();
+        let total: f64 = weights.iter().sum();
+        let percentage = match total > 0.0 {
+            true => (before + fraction * weights[index]) / total,
+            false => 0.0,
+        };
+
+        let entries = epub.toc().entries();
+        let toc_index = find_toc_entry(epub, &entries, index, &text, offset);
+
+        Some(Self {
+            index,
+            count,
+            fraction,
+            percentage,
+            toc_index,
+            toc_count: entries.len(),
+            toc_label: toc_index.map(|x| entries[x].label.clone()),
+        })
+    }
+}
+
+/// Finds the location at a position in the book from 0 to 1, weighted as in `percentage`, e.g. to
+/// place the progress of other apps.
+pub fn find_location(epub: &EpubFile, stats: Option<&BookStats>, percentage: f64) -> Option<Cfi> {
+    let weights = get_weights(epub, stats);
+    let total: f64 = weights.iter().sum();
+    if total <= 0.0 {
+        return None;
+    }
+
+    let mut target = percentage.clamp(0.0, 1.0) * total;
+    let last = weights.iter().rposition(|x| *x > 0.0)?;
+    let mut index = 0;
+    while index < last && target >= weights[index] {
+        target -= weights[index];
+        index += 1;
+    }
+
+    let (idref, path) = epub.get_spine_path(index)?;
+    let text = epub.document_text(&path).ok()?;
+    let fraction = (target / weights[index]).min(1.0);
+    let read = (fraction * count_characters(&text.text) as f64) as usize;
+    let offset = text
+        .text
+        .char_indices()
+        .filter(|(_, c)| !c.is_whitespace())
+        .nth(read)
+        .map_or(text.text.len(), |(i, _)| i);
+    Some(Cfi::new(index, idref, &text.position(offset, false)?))
+}
+
+/// Returns the index of the current entry of the table of contents at an offset in the text of
+/// a spine document, which is the last one before it in the documents up to this one.
+pub fn find_toc_entry(
+    epub: &EpubFile,
+    entries: &[EpubTocEntry],
+    index: usize,
+    text: &DocumentText,
+    offset: usize,
+) -> Option<usize> {
+    let mut current = None;
+    let mut last = (0, 0);
+
+    for (i, entry) in entries.iter().enumerate() {
+        let entry_index = epub.get_spine_index(&entry.path);
+        let Some(entry_index) = entry_index.filter(|x| *x <= index) else {
+            continue;
+        };
+
+        let entry_offset = match entry_index == index {
+            true => entry.fragment.as_ref().and_then(|x| text.anchors.get(x)),
+            false => None,
+        };
+        let position = (entry_index, entry_offset.copied().unwrap_or_default());
+        if position <= (index, offset) && position >= last {
+            current = Some(i);
+            last = position;
+        }
+    }
+
+    current
+}
+
+/// Returns the weight of each spine item in the progress through the book.
+fn get_weights(epub: &EpubFile, stats: Option<&BookStats>) -> Vec<f64> {
+    let count = epub.rootfile().package.spine.children.len();
+    let mut weights = vec![0.0; count];
+
+    match stats {
+        Some(stats) if !stats.chapters.is_empty() => {
+            for chapter in &stats.chapters {
+                if let Some(weight) = weights.get_mut(chapter.index) {
+                    *weight = chapter.characters as f64;
+                }
+            }
+        }
+        _ => {
+            for (index, weight) in weights.iter_mut().enumerate() {
+                let entry = epub.get_spine_path(index).map(|(_, x)| epub.entry(&x));
+                if let Some(Ok(entry)) = entry {
+                    *weight = entry.size() as f64;
+                }
+            }
+        }
+    }
+
+    weights
+}
+
+/// Counts the characters other than whitespace, as in the stats of books.
+fn count_characters(text: &str) -> usize {
+    text.chars().filter(|x| !x.is_whitespace()).count()
+}
@@ -0,0 +1,167 @@
+use html5ever::tendril::TendrilSink;
+use html5ever::{local_name, namespace_url, ns, parse_document, Namespace};
+use markup5ever_rcdom::{Handle, NodeData, RcDom};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+
+/// Checks whether a document can be parsed as XHTML. Tag names in uppercase are rejected too,
+/// since they don't match the lowercase HTML elements in XHTML.
+pub fn is_well_formed(content: &str) -> bool {
+    let mut reader = Reader::from_str(content);
+    let mut depth = 0usize;
+
+    loop {
+        let start = match reader.read_event() {
+            Ok(Event::Eof) => return depth == 0,
+            Ok(Event::Start(start)) => {
+                depth += 1;
+                start
+            }
+            Ok(Event::Empty(start)) => start,
+            Ok(Event::End(_)) => {
+                depth = depth.saturating_sub(1);
+                continue;
+            }
+            Ok(Event::Text(text)) if text.unescape().is_ok() => continue,
+            Ok(Event::Text(_)) | Err(_) => return false,
+            Ok(_) => continue,
+        };
+
+        let name = start.local_name();
+        let name = name.as_ref();
+        if name.iter().any(u8::is_ascii_uppercase) && !name.iter().any(u8::is_ascii_lowercase) {
+            return false;
+        }
+
+        for attr in start.attributes() {
+            if attr.map_or(true, |attr| attr.unescape_value().is_err()) {
+                return false;
+            }
+        }
+    }
+}
+
+/// Parses a document the way browsers parse HTML, and serializes it back as XHTML.
+pub fn repair(content: &str) -> String {
+    let dom = parse_document(RcDom::default(), Default::default()).one(content);
+
+    let mut output = String::with_capacity(content.len() + 256);
+    output.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n<!DOCTYPE html>\n");
+
+    for child in dom.document.children.borrow().iter() {
+        // Also skips the XML declaration, which is parsed as a comment.
+        if let NodeData::Element { .. } = child.data {
+            write_node(&mut output, child, &ns!());
+        }
+    }
+
+    output
+}
+
+fn write_node(output: &mut String, node: &Handle, parent_ns: &Namespace) {
+    match &node.data {
+        NodeData::Text { contents } => escape(output, &contents.borrow(), false),
+        NodeData::Comment { contents } => {
+            // `--` is not allowed in XML comments.
+            let contents = contents.replace("--", "- -");
+            output.push_str("<!--");
+            output.push_str(contents.trim_end_matches('-'));
+            output.push_str("-->");
+        }
+        NodeData::Element {
+            name,
+            attrs,
+            template_contents,
+            ..
+        } => {
+            let children = match &*template_contents.borrow() {
+                Some(contents) => contents.children.borrow().clone(),
+                None => node.children.borrow().clone(),
+            };
+
+            // Prefixed elements, e.g. `<o:p>` from Microsoft Word, have no declared namespace.
+            if !is_name(&name.local) || name.local.contains(':') {
+                for child in &children {
+                    write_node(output, child, parent_ns);
+                }
+                return;
+            }
+
+            output.push('<');
+            output.push_str(&name.local);
+
+            if name.ns != *parent_ns {
+                output.push_str(" xmlns=\"");
+                escape(output, &name.ns, true);
+                output.push('"');
+            }
+
+            if name.local == local_name!("html") {
+                output.push_str(" xmlns:epub=\"http://www.idpf.org/2007/ops\"");
+                output.push_str(" xmlns:xlink=\"http://www.w3.org/1999/xlink\"");
+            }
+
+            for attr in attrs.borrow().iter() {
+                let local = &*attr.name.local;
+                let prefix = match attr.name.ns {
+                    ns!() => match local.split_once(':') {
+                        Some(("xml" | "epub", _)) => "",
+                        Some(_) => continue,
+                        None if local == "xmlns" => continue,
+                        None => "",
+                    },
+                    ns!(xml) => "xml:",
+                    ns!(xlink) => "xlink:",
+                    _ => continue,
+                };
+
+                if !is_name(local) {
+                    continue;
+                }
+
+                output.push(' ');
+                output.push_str(prefix);
+                output.push_str(local);
+                output.push_str("=\"");
+                escape(output, &attr.value, true);
+                output.push('"');
+            }
+
+            if children.is_empty() {
+                output.push_str("/>");
+                return;
+            }
+
+            output.push('>');
+            for child in &children {
+                write_node(output, child, &name.ns);
+            }
+            output.push_str("</");
+            output.push_str(&name.local);
+            output.push('>');
+        }
+        _ => {}
+    }
+}
+
+/// Escapes text or attribute values, dropping the characters not allowed in XML.
+fn escape(output: &mut String, text: &str, attribute: bool) {
+    for c in text.chars() {
+        match c {
+            '&' => output.push_str("&amp;"),
+            '<' => output.push_str("&lt;"),
+            '>' => output.push_str("&gt;"),
+            '"' if attribute => output.push_str("&quot;"),
+            '\t' | '\n' | '\r' => output.push(c),
+            c if c.is_control() || c == '\u{fffe}' || c == '\u{ffff}' => {}
+            c => output.push(c),
+        }
+    }
+}
+
+/// A conservative check of XML names, which are less permissive than HTML ones.
+fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    let first = chars.next().is_some_and(|c| c.is_alphabetic() || c == '_');
+    first && chars.all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
@@ -76,6 +76,8 @@ pub struct EpubRootfileMetadata {
     pub creator: Vec<String>,
     #[serde(rename(deserialize = "publisher"), default)]
     pub publisher: Vec<String>,
+    #[serde(rename(deserialize = "language"), default)]
+    pub language: Vec<String>,
     #[serde(rename(deserialize = "meta"))]
     pub meta: Vec<EpubRootfileMetadataMeta>,
 }
@@ -112,6 +114,15 @@ pub struct EpubRootfileManifestItem {
     pub href: String,
     #[serde(rename(deserialize = "@media-type"))]
     pub media_type: String,
+    #[serde(rename(deserialize = "@properties"))]
+    pub properties: Option<String>,
+}
+
+impl EpubRootfileManifestItem {
+    pub fn has_property(&self, property: &str) -> bool {
+        let properties = self.properties.as_deref().unwrap_or_default();
+        properties.split_ascii_whitespace().any(|x| x == property)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -0,0 +1,419 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+use anyhow::Result;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use typed_path::Utf8UnixPathBuf;
+
+use super::cfi::Cfi;
+use crate::path::Utf8PathExtClean;
+
+/// Elements wrapping the whole content of `<body>`, which are descended into to find the
+/// children to split.
+const WRAPPER_ELEMENTS: &[&str] = &["div", "section", "article", "main"];
+
+/// Wrappers nested deeper than this are not descended into.
+const MAX_WRAPPER_DEPTH: usize = 4;
+
+/// The spine with the large documents split into several virtual chapters.
+///
+/// The first part of a split document keeps the path of the original document, while the others
+/// are served from synthetic paths next to it, so that relative URLs still resolve.
+#[derive(Debug)]
+pub struct VirtualSpine {
+    /// Documents larger than this many bytes are split.
+    pub threshold: u64,
+    /// The `idref` and the split (if any) of each original spine item.
+    items: Vec<(String, Option<SplitDocument>)>,
+    /// Maps the paths of parts, including the first ones, to their spine and part indices.
+    parts: HashMap<String, (usize, usize)>,
+    /// The original spine index and the part index of each virtual spine item.
+    virtual_items: Vec<(usize, usize)>,
+    /// The first virtual spine index of each original spine item.
+    firsts: Vec<usize>,
+}
+
+impl VirtualSpine {
+    /// `items` lists the `idref` and the split (if any) of each spine item.
+    pub fn new(threshold: u64, items: Vec<(String, Option<SplitDocument>)>) -> Self {
+        let mut parts = HashMap::new();
+        let mut virtual_items = Vec::new();
+        let mut firsts = Vec::new();
+
+        for (index, (_, split)) in items.iter().enumerate() {
+            firsts.push(virtual_items.len());
+            match split {
+                Some(split) => {
+                    for (part, path) in split.paths.iter().enumerate() {
+                        parts.insert(path.clone(), (index, part));
+                        virtual_items.push((index, part));
+                    }
+                }
+                None => virtual_items.push((index, 0)),
+            }
+        }
+
+        Self {
+            threshold,
+            items,
+            parts,
+            virtual_items,
+            firsts,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parts.is_empty()
+    }
+
+    /// Finds the split document of a spine item by its `idref`.
+    pub fn get_document_by_idref(&self, idref: &str) -> Option<&SplitDocument> {
+        let (_, split) = self.items.iter().find(|(x, _)| x == idref)?;
+        split.as_ref()
+    }
+
+    /// Finds the split document and the part index of a path, which may be the original path.
+    pub fn find_part(&self, path: &str) -> Option<(&SplitDocument, usize)> {
+        let (index, part) = *self.parts.get(path)?;
+        let split = self.items[index].1.as_ref()?;
+        Some((split, part))
+    }
+
+    /// Points a link to a fragment of a split document to the part containing the fragment,
+    /// resolving the link against the file at `base`. Returns `None` if the link doesn't need to
+    /// be changed.
+    pub fn rewrite_link(&self, base: &str, link: &str) -> Option<String> {
+        let (target, fragment) = link.split_once('#')?;
+        if target.contains(':') {
+            return None;
+        }
+
+        let path = match target {
+            "" => base.to_string(),
+            target => {
+                let mut path = Utf8UnixPathBuf::from(base);
+                // Remove the filename to get the base dir.
+                path.pop();
+                path.push(target);
+                path.clean().to_string()
+            }
+        };
+
+        let (split, current) = self.find_part(&path)?;
+        let part = split.find_id(fragment)?;
+        if part == current {
+            return None;
+        }
+
+        let filename = split.paths[part].rsplit('/').next()?;
+        let target = match target.rfind('/') {
+            Some(index) => format!("{}/{filename}", &target[..index]),
+            None => filename.to_string(),
+        };
+
+        Some(format!("{target}#{fragment}"))
+    }
+
+    /// Maps a CFI in the virtual spine to the original spine. Malformed CFIs are kept as is.
+    pub fn to_original_cfi(&self, cfi: &str) -> String {
+        self.map_cfi(cfi, true).unwrap_or_else(|| cfi.to_string())
+    }
+
+    /// Maps a CFI in the original spine to the virtual spine. Malformed CFIs are kept as is.
+    pub fn to_virtual_cfi(&self, cfi: &str) -> String {
+        self.map_cfi(cfi, false).unwrap_or_else(|| cfi.to_string())
+    }
+
+    fn map_cfi(&self, cfi: &str, to_original: bool) -> Option<String> {
+        let mut cfi = Cfi::parse(cfi).ok()?;
+
+        let spine_index = cfi.spine_index()?;
+        let (index, part) = match to_original {
+            true => *self.virtual_items.get(spine_index)?,
+            false => (spine_index, 0),
+        };
+        let (idref, split) = self.items.get(index)?;
+
+        let part = match split {
+            Some(split) => {
+                let mut steps = cfi.document_steps_mut(split.depth);
+                let part = match to_original {
+                    true => part,
+                    false => split.find_part_of_step(steps.first()?.index),
+                };
+                // A part lacks the children of the parts before it.
+                let shift = 2 * split.starts[part];
+                for step in &mut steps {
+                    step.index = match to_original {
+                        true => step.index + shift,
+                        false => step.index.saturating_sub(shift),
+                    };
+                }
+                part
+            }
+            None => 0,
+        };
+
+        match part {
+            _ if to_original => cfi.set_spine_item(index, idref)?,
+            0 => cfi.set_spine_item(self.firsts[index], idref)?,
+            part => {
+                cfi.set_spine_item(self.firsts[index] + part, &format!("{idref}-part{part}"))?
+            }
+        }
+
+        Some(cfi.to_string())
+    }
+}
+
+/// A document split at the boundaries of the children of `<body>`, or of the elements wrapping
+/// the whole content of `<body>`.
+#[derive(Debug)]
+pub struct SplitDocument {
+    /// The paths of the parts. The first one is the path of the original document.
+    pub paths: Vec<String>,
+    contents: Vec<String>,
+    /// Maps the IDs of elements (and the names of anchors) to the parts containing them.
+    ids: HashMap<String, usize>,
+    /// The index of the CFI steps selecting the children being split, i.e. the number of
+    /// elements from `<body>` to the element containing the children.
+    depth: usize,
+    /// The index of the first child of each part, among the children being split.
+    starts: Vec<usize>,
+}
+
+impl SplitDocument {
+    /// Splits a well-formed document into parts of about `size` bytes, preferring the boundaries
+    /// before headings. Returns `None` if the document can't be split.
+    pub fn split(path: &str, content: &str, size: usize) -> Result<Option<Self>> {
+        let Some(SplitChildren {
+            depth,
+            inner,
+            children,
+        }) = find_split_children(content)?
+        else {
+            return Ok(None);
+        };
+
+        let mut starts = vec![0];
+        let mut part_start = inner.start;
+        for (index, child) in children.iter().enumerate().skip(1) {
+            let length = child.range.start - part_start;
+            if length >= size || (is_heading(&child.name) && length >= size / 2) {
+                starts.push(index);
+                part_start = child.range.start;
+            }
+        }
+
+        if starts.len() == 1 {
+            return Ok(None);
+        }
+
+        let mut paths = Vec::new();
+        let mut contents = Vec::new();
+        let mut ids = HashMap::new();
+
+        for (part, start) in starts.iter().enumerate() {
+            let slice_start = match part {
+                0 => inner.start,
+                _ => children[*start].range.start,
+            };
+            let slice_end = match starts.get(part + 1) {
+                Some(next) => children[*next].range.start,
+                None => inner.end,
+            };
+            let slice = &content[slice_start..slice_end];
+
+            for id in find_ids(slice) {
+                ids.entry(id).or_insert(part);
+            }
+
+            paths.push(match part {
+                0 => path.to_string(),
+                _ => format!("{path}.part{part}.xhtml"),
+            });
+
+            let mut part_content = String::with_capacity(content.len() - inner.len() + slice.len());
+            part_content.push_str(&content[..inner.start]);
+            part_content.push_str(slice);
+            part_content.push_str(&content[inner.end..]);
+            contents.push(part_content);
+        }
+
+        Ok(Some(Self {
+            paths,
+            contents,
+            ids,
+            depth,
+            starts,
+        }))
+    }
+
+    pub fn len(&self) -> usize {
+        self.paths.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.is_empty()
+    }
+
+    pub fn content(&self, part: usize) -> &str {
+        &self.contents[part]
+    }
+
+    /// Finds the part containing the element with an ID.
+    pub fn find_id(&self, id: &str) -> Option<usize> {
+        self.ids.get(id).copied()
+    }
+
+    /// Finds the part containing the node selected by a CFI step among the children being split.
+    fn find_part_of_step(&self, step: usize) -> usize {
+        // Even steps select elements, and odd steps select the text after the previous element.
+        let element = (step / 2).saturating_sub(1);
+        self.starts.iter().rposition(|x| *x <= element).unwrap_or(0)
+    }
+}
+
+struct Child {
+    name: String,
+    range: Range<usize>,
+    inner: Option<Range<usize>>,
+}
+
+/// The children to split, and the element containing them.
+struct SplitChildren {
+    /// The index of the CFI steps selecting the children.
+    depth: usize,
+    /// The range of the content of the element containing the children.
+    inner: Range<usize>,
+    children: Vec<Child>,
+}
+
+fn find_split_children(content: &str) -> Result<Option<SplitChildren>> {
+    let find = |children: Vec<Child>, name: &str| {
+        children
+            .into_iter()
+            .find(|x| x.name == name)
+            .and_then(|x| x.inner)
+    };
+
+    let Some(html) = find(find_children(content, 0..content.len())?, "html") else {
+        return Ok(None);
+    };
+    let Some(mut inner) = find(find_children(content, html)?, "body") else {
+        return Ok(None);
+    };
+
+    let mut depth = 1;
+    let mut children = find_children(content, inner.clone())?;
+
+    while depth <= MAX_WRAPPER_DEPTH {
+        let [child] = children.as_slice() else {
+            break;
+        };
+        let Some(child_inner) = &child.inner else {
+            break;
+        };
+        if !WRAPPER_ELEMENTS.contains(&&*child.name) {
+            break;
+        }
+
+        let before = &content[inner.start..child.range.start];
+        let after = &content[child.range.end..inner.end];
+        if !before.trim().is_empty() || !after.trim().is_empty() {
+            break;
+        }
+
+        inner = child_inner.clone();
+        children = find_children(content, inner.clone())?;
+        depth += 1;
+    }
+
+    Ok(Some(SplitChildren {
+        depth,
+        inner,
+        children,
+    }))
+}
+
+/// Finds the child elements of the range of a well-formed document.
+fn find_children(content: &str, range: Range<usize>) -> Result<Vec<Child>> {
+    let mut reader = Reader::from_str(&content[range.clone()]);
+    reader.check_end_names(false);
+
+    let mut children = Vec::new();
+    let mut depth = 0usize;
+
+    loop {
+        let before = range.start + reader.buffer_position();
+        let event = reader.read_event()?;
+        let after = range.start + reader.buffer_position();
+
+        match event {
+            Event::Eof => break,
+            Event::Start(start) => {
+                if depth == 0 {
+                    children.push(Child {
+                        name: get_local_name(&start),
+                        range: before..after,
+                        inner: Some(after..after),
+                    });
+                }
+                depth += 1;
+            }
+            Event::End(_) => {
+                depth = depth.saturating_sub(1);
+                if let Some(child) = children.last_mut().filter(|_| depth == 0) {
+                    child.range.end = after;
+                    child.inner = child.inner.take().map(|x| x.start..before);
+                }
+            }
+            Event::Empty(start) if depth == 0 => {
+                children.push(Child {
+                    name: get_local_name(&start),
+                    range: before..after,
+                    inner: None,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    Ok(children)
+}
+
+/// Finds the IDs of elements and the names of anchors, which may be the targets of links.
+fn find_ids(content: &str) -> Vec<String> {
+    let mut reader = Reader::from_str(content);
+    reader.check_end_names(false);
+
+    let mut ids = Vec::new();
+    loop {
+        let start = match reader.read_event() {
+            Ok(Event::Start(start) | Event::Empty(start)) => start,
+            Ok(Event::Eof) | Err(_) => break,
+            Ok(_) => continue,
+        };
+
+        let is_anchor = start.local_name().as_ref() == b"a";
+        for attr in start.attributes().flatten() {
+            let key = attr.key.as_ref();
+            if key == b"id" || (is_anchor && key == b"name") {
+                if let Ok(value) = attr.unescape_value() {
+                    ids.push(value.into_owned());
+                }
+            }
+        }
+    }
+
+    ids
+}
+
+fn get_local_name(start: &BytesStart) -> String {
+    String::from_utf8_lossy(start.local_name().as_ref()).to_ascii_lowercase()
+}
+
+fn is_heading(name: &str) -> bool {
+    matches!(name, "h1" | "h2" | "h3" | "h4" | "h5" | "h6")
+}
@@ -0,0 +1,260 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use super::cfi::Cfi;
+use super::media_type::is_document;
+use super::text::{DocumentText, DomPosition};
+use super::EpubFile;
+use crate::search::is_cjk;
+
+/// Average reading speeds of adults, in words and in CJK characters per minute.
+const WORDS_PER_MINUTE: f64 = 238.0;
+const CJK_CHARACTERS_PER_MINUTE: f64 = 260.0;
+
+/// Sentence terminators, including the full-width ones of CJK text.
+const SENTENCE_TERMINATORS: &[char] = &['.', '!', '?', '…', '。', '！', '？'];
+
+/// Counts of the content of a book, cached in the library.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BookStats {
+    /// Words, counting each CJK character as a word.
+    pub words: usize,
+    /// Characters other than whitespace.
+    pub characters: usize,
+    pub images: usize,
+    pub readability: Option<Readability>,
+    /// The estimated time to read the whole book, in minutes.
+    pub minutes: f64,
+    pub chapters: Vec<ChapterStats>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChapterStats {
+    /// The index of the document in the original spine.
+    pub index: usize,
+    pub words: usize,
+    pub characters: usize,
+    pub images: usize,
+    pub minutes: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+pub enum Readability {
+    /// The Flesch–Kincaid grade level and reading ease of English text.
+    FleschKincaid { grade: f64, reading_ease: f64 },
+    /// The average length of sentences of CJK text, in characters.
+    CharacterBased { characters_per_sentence: f64 },
+}
+
+/// The estimated reading time of a book from a location, in minutes.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReadingTime {
+    pub total: f64,
+    pub remaining: f64,
+    /// The index of the current document in the original spine, if the location is known.
+    pub index: Option<usize>,
+    pub chapter_remaining: Option<f64>,
+}
+
+impl BookStats {
+    pub fn new(epub: &EpubFile) -> Result<Self> {
+        let mut total = Counter::default();
+        let mut stats = Self::default();
+
+        for index in 0..epub.rootfile().package.spine.children.len() {
+            let Some((_, path)) = epub.get_spine_path(index) else {
+                continue;
+            };
+            if !epub.get_media_type(&path).is_some_and(is_document) {
+                continue;
+            }
+
+            let text = DocumentText::parse(&epub.read_document(&path)?)?;
+            let mut counter = Counter::default();
+            counter.count(&text.text);
+
+            stats.chapters.push(ChapterStats {
+                index,
+                words: counter.words(),
+                characters: counter.characters,
+                images: text.images,
+                minutes: counter.minutes(),
+            });
+            stats.images += text.images;
+            total.add(&counter);
+        }
+
+        let language = epub.rootfile().package.metadata.language.first();
+        let is_english = language.is_none_or(|x| x.to_ascii_lowercase().starts_with("en"));
+
+        stats.words = total.words();
+        stats.characters = total.characters;
+        stats.minutes = total.minutes();
+        stats.readability = total.readability(is_english);
+        Ok(stats)
+    }
+
+    /// Estimates the remaining reading time from a location saved against the original spine.
+    pub fn reading_time(&self, epub: &EpubFile, location: Option<&str>) -> ReadingTime {
+        let mut time = ReadingTime {
+            total: self.minutes,
+            remaining: self.minutes,
+            index: None,
+            chapter_remaining: None,
+        };
+
+        // Ranges are located at their start.
+        let cfi = location.and_then(|x| Cfi::parse(x).ok());
+        let Some((index, position)) =
+            cfi.and_then(|x| Some((x.spine_index()?, x.start().position()?)))
+        else {
+            return time;
+        };
+        let Some(current) = self.chapters.iter().position(|x| x.index == index) else {
+            return time;
+        };
+
+        let chapter = &self.chapters[current];
+        let read = get_minutes_before(epub, index, &position).unwrap_or_default();
+        let chapter_remaining = (chapter.minutes - read).max(0.0);
+        let following: f64 = self.chapters[current + 1..].iter().map(|x| x.minutes).sum();
+
+        time.remaining = chapter_remaining + following;
+        time.index = Some(index);
+        time.chapter_remaining = Some(chapter_remaining);
+        time
+    }
+}
+
+/// Returns the reading time of a document before a position in it.
+fn get_minutes_before(epub: &EpubFile, index: usize, position: &DomPosition) -> Option<f64> {
+    let (_, path) = epub.get_spine_path(index)?;
+    let text = epub.document_text(&path).ok()?;
+    let offset = text.offset(position)?;
+
+    let mut counter = Counter::default();
+    counter.count(&text.text[..offset]);
+    Some(counter.minutes())
+}
+
+#[derive(Default)]
+struct Counter {
+    /// Words other than CJK characters.
+    words: usize,
+    characters: usize,
+    cjk_characters: usize,
+    sentences: usize,
+    syllables: usize,
+}
+
+impl Counter {
+    fn count(&mut self, text: &str) {
+        let mut word = String::new();
+        // Whether there are words since the end of the last sentence.
+        let mut in_sentence = false;
+
+        for c in text.chars() {
+            let continues_word =
+                c.is_alphanumeric() || (matches!(c, '\'' | '’') && !word.is_empty());
+            if continues_word && !is_cjk(c) {
+                word.push(c);
+            } else if !word.is_empty() {
+                self.end_word(&word);
+                word.clear();
+                in_sentence = true;
+            }
+
+            if is_cjk(c) {
+                self.cjk_characters += 1;
+                in_sentence = true;
+            }
+            if !c.is_whitespace() {
+                self.characters += 1;
+            }
+
+            // Blocks end sentences as well, e.g. headings.
+            if (SENTENCE_TERMINATORS.contains(&c) || c == '\n') && in_sentence {
+                self.sentences += 1;
+                in_sentence = false;
+            }
+        }
+
+        if !word.is_empty() {
+            self.end_word(&word);
+            in_sentence = true;
+        }
+        if in_sentence {
+            self.sentences += 1;
+        }
+    }
+
+    fn end_word(&mut self, word: &str) {
+        self.words += 1;
+        self.syllables += count_syllables(word);
+    }
+
+    fn add(&mut self, other: &Counter) {
+        self.words += other.words;
+        self.characters += other.characters;
+        self.cjk_characters += other.cjk_characters;
+        self.sentences += other.sentences;
+        self.syllables += other.syllables;
+    }
+
+    fn words(&self) -> usize {
+        self.words + self.cjk_characters
+    }
+
+    fn minutes(&self) -> f64 {
+        self.words as f64 / WORDS_PER_MINUTE
+            + self.cjk_characters as f64 / CJK_CHARACTERS_PER_MINUTE
+    }
+
+    /// Scores the text in the script it's mostly written in. Flesch–Kincaid only applies to
+    /// English.
+    fn readability(&self, is_english: bool) -> Option<Readability> {
+        if self.sentences == 0 {
+            return None;
+        }
+
+        if self.cjk_characters > self.words {
+            let characters_per_sentence = self.characters as f64 / self.sentences as f64;
+            return Some(Readability::CharacterBased {
+                characters_per_sentence,
+            });
+        }
+
+        if !is_english || self.words == 0 {
+            return None;
+        }
+
+        let words_per_sentence = self.words as f64 / self.sentences as f64;
+        let syllables_per_word = self.syllables as f64 / self.words as f64;
+        Some(Readability::FleschKincaid {
+            grade: 0.39 * words_per_sentence + 11.8 * syllables_per_word - 15.59,
+            reading_ease: 206.835 - 1.015 * words_per_sentence - 84.6 * syllables_per_word,
+        })
+    }
+}
+
+/// Estimates the syllables of an English word from its groups of vowels.
+fn count_syllables(word: &str) -> usize {
+    let word = word.to_lowercase();
+    let mut count = 0;
+    let mut previous_vowel = false;
+
+    for c in word.chars() {
+        let vowel = matches!(c, 'a' | 'e' | 'i' | 'o' | 'u' | 'y');
+        if vowel && !previous_vowel {
+            count += 1;
+        }
+        previous_vowel = vowel;
+    }
+
+    // A final `e` is usually silent, as in "make", but not in "table".
+    if word.ends_with('e') && !word.ends_with("le") && count > 1 {
+        count -= 1;
+    }
+    count.max(1)
+}
@@ -0,0 +1,437 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+use anyhow::Result;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use serde::Serialize;
+
+use super::cfi::Cfi;
+
+/// Elements whose text isn't part of the content.
+const SKIPPED_ELEMENTS: &[&[u8]] = &[
+    b"head",
+    b"script",
+    b"style",
+    b"noscript",
+    b"template",
+    b"rt",
+    b"rp",
+];
+
+/// Elements counted as images.
+const IMAGE_ELEMENTS: &[&[u8]] = &[b"img", b"image"];
+
+/// Elements starting a new block of text.
+const BLOCK_ELEMENTS: &[&[u8]] = &[
+    b"body",
+    b"p",
+    b"div",
+    b"h1",
+    b"h2",
+    b"h3",
+    b"h4",
+    b"h5",
+    b"h6",
+    b"li",
+    b"ul",
+    b"ol",
+    b"dl",
+    b"dt",
+    b"dd",
+    b"blockquote",
+    b"pre",
+    b"section",
+    b"article",
+    b"aside",
+    b"header",
+    b"footer",
+    b"nav",
+    b"main",
+    b"figure",
+    b"figcaption",
+    b"table",
+    b"caption",
+    b"tr",
+    b"td",
+    b"th",
+    b"address",
+    b"hr",
+];
+
+/// The plain text of a document, with whitespace collapsed as in rendering and blocks separated
+/// by line breaks. Offsets into the text are byte offsets, and can be mapped back to positions in
+/// the DOM, where offsets in text nodes skip soft hyphens.
+#[derive(Debug, Clone)]
+pub struct DocumentText {
+    pub text: String,
+    pub blocks: Vec<TextBlock>,
+    /// Maps the IDs of elements to the offsets of the text following them.
+    pub anchors: HashMap<String, usize>,
+    /// The number of images, including SVG images.
+    pub images: usize,
+    /// The CFI steps of the text nodes, e.g. `[4, 2, 1]` for `/4/2/1`.
+    nodes: Vec<Vec<usize>>,
+    /// Ranges of the text that are contiguous in a text node.
+    runs: Vec<TextRun>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TextBlock {
+    pub range: Range<usize>,
+    /// The level of headings, from 1 to 6.
+    pub heading: Option<u8>,
+    /// The CFI steps of the block element.
+    pub steps: Vec<usize>,
+}
+
+#[derive(Debug, Clone)]
+struct TextRun {
+    range: Range<usize>,
+    node: usize,
+    /// The offset of the start of the run in the text node, in UTF-16 code units as in CFIs.
+    offset: usize,
+}
+
+/// The text of a spine document as sent to the frontend, where offsets are in UTF-16 code units.
+#[derive(Debug, Clone, Serialize)]
+pub struct ChapterText {
+    pub index: usize,
+    pub path: String,
+    pub text: String,
+    pub blocks: Vec<ChapterBlock>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ChapterBlock {
+    pub start: usize,
+    pub end: usize,
+    pub heading: Option<u8>,
+    pub cfi: Option<String>,
+}
+
+impl ChapterText {
+    pub fn new(index: usize, idref: &str, path: String, text: &DocumentText) -> Self {
+        let mut blocks = Vec::new();
+        let mut last = (0, 0);
+
+        for block in &text.blocks {
+            let start = last.1 + utf16_len(&text.text[last.0..block.range.start]);
+            let end = start + utf16_len(&text.text[block.range.clone()]);
+            last = (block.range.end, end);
+
+            blocks.push(ChapterBlock {
+                start,
+                end,
+                heading: block.heading,
+                cfi: text.range_to_cfi(index, idref, block.range.clone()),
+            });
+        }
+
+        Self {
+            index,
+            path,
+            text: text.text.clone(),
+            blocks,
+        }
+    }
+}
+
+/// A position in the DOM: the CFI steps of a text node and an offset in it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DomPosition {
+    pub steps: Vec<usize>,
+    pub offset: usize,
+}
+
+impl DocumentText {
+    /// Extracts the text of a well-formed document.
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut builder = Builder::default();
+        let mut anchors = HashMap::new();
+        let mut images = 0;
+
+        let mut reader = Reader::from_str(content);
+        reader.check_end_names(false);
+
+        // The number of child elements of the open elements, and the depth of skipped elements.
+        let mut children: Vec<usize> = Vec::new();
+        let mut steps: Vec<usize> = Vec::new();
+        let mut skipped = 0usize;
+        // The open block elements and the levels of headings.
+        let mut blocks: Vec<(Vec<usize>, Option<u8>)> = Vec::new();
+        let mut preformatted = 0usize;
+
+        loop {
+            match reader.read_event()? {
+                Event::Eof => break,
+                Event::Start(start) => {
+                    let name = start.local_name().as_ref().to_ascii_lowercase();
+                    if let Some(count) = children.last_mut() {
+                        *count += 1;
+                        steps.push(*count * 2);
+                    }
+                    children.push(0);
+                    builder.node = None;
+
+                    if skipped > 0 || SKIPPED_ELEMENTS.contains(&&*name) {
+                        skipped += 1;
+                        continue;
+                    }
+                    if name == b"pre" {
+                        preformatted += 1;
+                    }
+                    if let Some(id) = get_id(&start) {
+                        anchors.entry(id).or_insert(builder.text.len());
+                    }
+                    if IMAGE_ELEMENTS.contains(&&*name) {
+                        images += 1;
+                    }
+                    if BLOCK_ELEMENTS.contains(&&*name) {
+                        builder.end_block(blocks.last());
+                        blocks.push((steps.clone(), get_heading_level(&name)));
+                    }
+                }
+                Event::End(end) => {
+                    let name = end.local_name().as_ref().to_ascii_lowercase();
+                    if children.pop().is_some() && !children.is_empty() {
+                        steps.pop();
+                    }
+                    builder.node = None;
+
+                    if skipped > 0 {
+                        skipped -= 1;
+                        continue;
+                    }
+                    if name == b"pre" {
+                        preformatted = preformatted.saturating_sub(1);
+                    }
+                    if BLOCK_ELEMENTS.contains(&&*name) {
+                        builder.end_block(blocks.last());
+                        blocks.pop();
+                    }
+                }
+                Event::Empty(start) => {
+                    if let Some(count) = children.last_mut() {
+                        *count += 1;
+                    }
+                    builder.node = None;
+
+                    if let Some(id) = get_id(&start).filter(|_| skipped == 0) {
+                        anchors.entry(id).or_insert(builder.text.len());
+                    }
+
+                    let name = start.local_name().as_ref().to_ascii_lowercase();
+                    match &*name {
+                        _ if skipped > 0 => {}
+                        b"br" => builder.push_space(),
+                        name if IMAGE_ELEMENTS.contains(&name) => images += 1,
+                        name if BLOCK_ELEMENTS.contains(&name) => builder.end_block(blocks.last()),
+                        _ => {}
+                    }
+                }
+                Event::Text(text) if skipped == 0 && !children.is_empty() => {
+                    let text = text.unescape()?;
+                    builder.push_text(&steps, *children.last().unwrap(), &text, preformatted > 0);
+                }
+                Event::CData(cdata) if skipped == 0 && !children.is_empty() => {
+                    let text = String::from_utf8_lossy(&cdata);
+                    builder.push_text(&steps, *children.last().unwrap(), &text, preformatted > 0);
+                }
+                _ => {}
+            }
+        }
+
+        builder.end_block(blocks.last());
+        // Blocks are followed by line breaks, which are only needed between them.
+        builder.text.pop();
+
+        Ok(Self {
+            text: builder.text,
+            blocks: builder.blocks,
+            anchors,
+            images,
+            nodes: builder.nodes,
+            runs: builder.runs,
+        })
+    }
+
+    /// Maps an offset in the text to a position in the DOM. An offset between two text nodes is
+    /// mapped to the end of the first one if `end` is true, or else to the start of the second.
+    pub fn position(&self, offset: usize, end: bool) -> Option<DomPosition> {
+        let index = match end {
+            true => self.runs.partition_point(|x| x.range.start < offset),
+            false => self.runs.partition_point(|x| x.range.start <= offset),
+        };
+        let run = self.runs.get(index.checked_sub(1)?)?;
+
+        let offset = offset.min(run.range.end);
+        let skipped = self.text.get(run.range.start..offset)?;
+        Some(DomPosition {
+            steps: self.nodes[run.node].clone(),
+            offset: run.offset + utf16_len(skipped),
+        })
+    }
+
+    /// Maps a position in the DOM to an offset in the text. A position in an element, or in text
+    /// which isn't part of the content, is mapped to the start of the text following it.
+    pub fn offset(&self, position: &DomPosition) -> Option<usize> {
+        let node = self.nodes.iter().position(|x| *x == position.steps);
+        let runs = node.map(|node| self.runs.iter().filter(move |x| x.node == node));
+
+        if let Some(run) = runs.and_then(|x| x.take_while(|x| x.offset <= position.offset).last()) {
+            let mut offset = run.range.start;
+            let mut length = run.offset;
+            for c in self.text[run.range.clone()].chars() {
+                if length >= position.offset {
+                    break;
+                }
+                offset += c.len_utf8();
+                length += c.len_utf16();
+            }
+            return Some(offset);
+        }
+
+        // Steps compare in document order, with elements before their descendants.
+        let index = self
+            .runs
+            .partition_point(|x| self.nodes[x.node] < position.steps);
+        match self.runs.get(index) {
+            Some(run) => Some(run.range.start),
+            None => Some(self.text.len()),
+        }
+    }
+
+    /// Returns the CFI of a range of the text, in the document at a spine index.
+    pub fn range_to_cfi(
+        &self,
+        spine_index: usize,
+        idref: &str,
+        range: Range<usize>,
+    ) -> Option<String> {
+        let cfi = Cfi::from_text_range(spine_index, idref, self, range)?;
+        Some(cfi.to_string())
+    }
+
+    /// Returns the CFI of an offset in the text, in the document at a spine index.
+    pub fn offset_to_cfi(&self, spine_index: usize, idref: &str, offset: usize) -> Option<String> {
+        let position = self.position(offset, false)?;
+        Some(Cfi::new(spine_index, idref, &position).to_string())
+    }
+}
+
+#[derive(Default)]
+struct Builder {
+    text: String,
+    blocks: Vec<TextBlock>,
+    nodes: Vec<Vec<usize>>,
+    runs: Vec<TextRun>,
+    /// The text node being read, and the UTF-16 offset in it.
+    node: Option<(usize, usize)>,
+    block_start: usize,
+    /// The position of the whitespace to be written before the next character.
+    pending_space: Option<Option<(usize, usize)>>,
+}
+
+impl Builder {
+    fn push_text(&mut self, steps: &[usize], children: usize, text: &str, preformatted: bool) {
+        let (node, mut offset) = match self.node {
+            Some(node) => node,
+            None => {
+                let mut node = steps.to_vec();
+                node.push(children * 2 + 1);
+                self.nodes.push(node);
+                (self.nodes.len() - 1, 0)
+            }
+        };
+
+        // Soft hyphens, which may be inserted when hyphenating, don't count towards offsets.
+        for c in text.chars().filter(|&c| c != '\u{ad}') {
+            if is_collapsible(c) && !preformatted {
+                if self.text.len() > self.block_start && self.pending_space.is_none() {
+                    self.pending_space = Some(Some((node, offset)));
+                }
+            } else {
+                if let Some(position) = self.pending_space.take() {
+                    self.push_char(' ', position);
+                }
+                self.push_char(c, Some((node, offset)));
+            }
+            offset += c.len_utf16();
+        }
+
+        self.node = Some((node, offset));
+    }
+
+    /// Pushes a space for `<br>`, which has no position in a text node.
+    fn push_space(&mut self) {
+        if self.text.len() > self.block_start && self.pending_space.is_none() {
+            self.pending_space = Some(None);
+        }
+    }
+
+    fn push_char(&mut self, c: char, position: Option<(usize, usize)>) {
+        let start = self.text.len();
+        self.text.push(c);
+
+        let Some((node, offset)) = position else {
+            return;
+        };
+
+        if let Some(run) = self.runs.last_mut() {
+            let length: usize = utf16_len(&self.text[run.range.clone()]);
+            if run.node == node && run.range.end == start && run.offset + length == offset {
+                run.range.end = self.text.len();
+                return;
+            }
+        }
+
+        self.runs.push(TextRun {
+            range: start..self.text.len(),
+            node,
+            offset,
+        });
+    }
+
+    /// Ends the current block, if any text has been written since its start.
+    fn end_block(&mut self, block: Option<&(Vec<usize>, Option<u8>)>) {
+        self.pending_space = None;
+        if self.text.len() == self.block_start {
+            return;
+        }
+
+        let (steps, heading) = block.cloned().unwrap_or_default();
+        self.blocks.push(TextBlock {
+            range: self.block_start..self.text.len(),
+            heading,
+            steps,
+        });
+
+        self.text.push('\n');
+        self.block_start = self.text.len();
+    }
+}
+
+/// Whitespace collapsed in rendering. Other whitespace, e.g. no-break spaces, is kept.
+fn is_collapsible(c: char) -> bool {
+    matches!(c, ' ' | '\t' | '\n' | '\r' | '\x0c')
+}
+
+fn get_id(start: &BytesStart) -> Option<String> {
+    let attr = start
+        .attributes()
+        .flatten()
+        .find(|x| x.key.as_ref() == b"id")?;
+    Some(attr.unescape_value().ok()?.into_owned())
+}
+
+fn get_heading_level(name: &[u8]) -> Option<u8> {
+    match name {
+        [b'h', level @ b'1'..=b'6'] => Some(level - b'0'),
+        _ => None,
+    }
+}
+
+fn utf16_len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
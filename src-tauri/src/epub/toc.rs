@@ -1,17 +1,93 @@
 use serde::Serialize;
+use typed_path::Utf8UnixPathBuf;
+
+use crate::path::Utf8PathExtClean;
 
 use ncx::EpubTocNcx;
 
+pub mod nav;
 pub mod ncx;
 
 #[derive(Debug, Clone, Serialize)]
 pub struct EpubToc {
     pub path: String,
     pub ncx: EpubTocNcx,
+    /// The page list of the EPUB 3 navigation document, which takes precedence over the one of
+    /// the NCX.
+    #[serde(skip)]
+    nav_pages: Option<Vec<EpubTocEntry>>,
 }
 
 impl EpubToc {
     pub fn new(path: String, ncx: EpubTocNcx) -> Self {
-        Self { path, ncx }
+        Self {
+            path,
+            ncx,
+            nav_pages: None,
+        }
+    }
+
+    /// Sets the page list read from the navigation document at a path.
+    pub fn set_nav_pages(&mut self, path: &str, pages: Vec<(String, String)>) {
+        let pages = pages
+            .iter()
+            .map(|(label, src)| make_entry(path, label, src));
+        self.nav_pages = Some(pages.collect()).filter(|x: &Vec<_>| !x.is_empty());
+    }
+
+    /// Replaces the title and the labels of all entries.
+    pub fn map_labels(&mut self, f: impl Fn(&str) -> String) {
+        self.ncx.map_labels(&f);
+    }
+
+    /// Replaces the sources of all entries, which are relative to `path`.
+    pub fn map_srcs(&mut self, f: impl Fn(&str) -> String) {
+        self.ncx.map_srcs(&f);
+    }
+
+    /// Returns all entries in reading order, with the paths they point to resolved.
+    pub fn entries(&self) -> Vec<EpubTocEntry> {
+        let mut entries = Vec::new();
+        self.ncx
+            .for_each_entry(&mut |label, src| entries.push(make_entry(&self.path, label, src)));
+        entries
+    }
+
+    /// Returns the pages of the print edition, if the book has a page list.
+    pub fn pages(&self) -> Vec<EpubTocEntry> {
+        if let Some(pages) = &self.nav_pages {
+            return pages.clone();
+        }
+
+        let mut pages = Vec::new();
+        self.ncx
+            .for_each_page(&mut |label, src| pages.push(make_entry(&self.path, label, src)));
+        pages
+    }
+}
+
+/// Makes an entry from a link in the NCX or the navigation document at `base`.
+fn make_entry(base: &str, label: &str, src: &str) -> EpubTocEntry {
+    let (href, fragment) = match src.split_once('#') {
+        Some((href, fragment)) => (href, Some(fragment.to_string())),
+        None => (src, None),
+    };
+
+    let mut path = Utf8UnixPathBuf::from(base);
+    // `base` is the NCX file or the navigation document. Remove the filename to get the base dir.
+    path.pop();
+    path.push(href);
+
+    EpubTocEntry {
+        label: label.trim().to_string(),
+        path: path.clean().to_string(),
+        fragment,
     }
 }
+
+#[derive(Debug, Clone)]
+pub struct EpubTocEntry {
+    pub label: String,
+    pub path: String,
+    pub fragment: Option<String>,
+}
@@ -0,0 +1,75 @@
+use anyhow::Result;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+
+/// Reads the labels and the sources of the links of the `<nav epub:type="page-list">` of an
+/// EPUB 3 navigation document, in order.
+pub fn read_page_list(content: &str) -> Result<Vec<(String, String)>> {
+    let mut reader = Reader::from_str(content);
+    reader.check_end_names(false);
+
+    let mut pages = Vec::new();
+    // The depth of the elements in the page list, once it's found.
+    let mut depth: Option<usize> = None;
+    // The label and the source of the link being read.
+    let mut link: Option<(String, String)> = None;
+
+    loop {
+        match reader.read_event()? {
+            Event::Eof => break,
+            Event::Start(start) => match &mut depth {
+                Some(depth) => {
+                    *depth += 1;
+                    if start.local_name().as_ref() == b"a" {
+                        link = get_href(&start).map(|href| (String::new(), href));
+                    }
+                }
+                None if start.local_name().as_ref() == b"nav" && is_page_list(&start) => {
+                    depth = Some(0);
+                }
+                None => {}
+            },
+            Event::End(end) => match &mut depth {
+                Some(0) => break,
+                Some(depth) => {
+                    *depth -= 1;
+                    if end.local_name().as_ref() == b"a" {
+                        pages.extend(link.take());
+                    }
+                }
+                None => {}
+            },
+            Event::Text(text) => {
+                if let Some((label, _)) = &mut link {
+                    label.push_str(&text.unescape()?);
+                }
+            }
+            Event::CData(cdata) => {
+                if let Some((label, _)) = &mut link {
+                    label.push_str(&String::from_utf8_lossy(&cdata));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(pages)
+}
+
+fn is_page_list(start: &BytesStart) -> bool {
+    start.attributes().flatten().any(|attr| {
+        attr.key.local_name().as_ref() == b"type"
+            && attr
+                .value
+                .split(u8::is_ascii_whitespace)
+                .any(|x| x == b"page-list")
+    })
+}
+
+fn get_href(start: &BytesStart) -> Option<String> {
+    let attr = start
+        .attributes()
+        .flatten()
+        .find(|x| x.key.as_ref() == b"href")?;
+    Some(attr.unescape_value().ok()?.into_owned())
+}
@@ -6,6 +6,39 @@ pub struct EpubTocNcx {
     doc_title: EpubTocNcxDocTitle,
     #[serde(rename(deserialize = "navMap"))]
     nav_map: EpubTocNcxNavMap,
+    #[serde(rename(deserialize = "pageList"), default)]
+    page_list: Option<EpubTocNcxPageList>,
+}
+
+impl EpubTocNcx {
+    pub fn map_labels(&mut self, f: &impl Fn(&str) -> String) {
+        self.doc_title.text = f(&self.doc_title.text);
+        for point in &mut self.nav_map.children {
+            point.map_labels(f);
+        }
+    }
+
+    /// Replaces the sources of all entries.
+    pub fn map_srcs(&mut self, f: &impl Fn(&str) -> String) {
+        for point in &mut self.nav_map.children {
+            point.map_srcs(f);
+        }
+    }
+
+    /// Calls `f` with the label and the source of every entry, in reading order.
+    pub fn for_each_entry(&self, f: &mut impl FnMut(&str, &str)) {
+        for point in &self.nav_map.children {
+            point.for_each_entry(f);
+        }
+    }
+
+    /// Calls `f` with the label and the source of every page of the print edition, in order.
+    pub fn for_each_page(&self, f: &mut impl FnMut(&str, &str)) {
+        let targets = self.page_list.iter().flat_map(|x| &x.children);
+        for target in targets {
+            f(&target.nav_label.text, &target.content.src);
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -34,6 +67,29 @@ pub struct EpubTocNcxNavPoint {
     children: Vec<EpubTocNcxNavPoint>,
 }
 
+impl EpubTocNcxNavPoint {
+    fn map_labels(&mut self, f: &impl Fn(&str) -> String) {
+        self.nav_label.text = f(&self.nav_label.text);
+        for point in &mut self.children {
+            point.map_labels(f);
+        }
+    }
+
+    fn map_srcs(&mut self, f: &impl Fn(&str) -> String) {
+        self.content.src = f(&self.content.src);
+        for point in &mut self.children {
+            point.map_srcs(f);
+        }
+    }
+
+    fn for_each_entry(&self, f: &mut impl FnMut(&str, &str)) {
+        f(&self.nav_label.text, &self.content.src);
+        for point in &self.children {
+            point.for_each_entry(f);
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EpubTocNcxNavLabel {
     #[serde(rename(deserialize = "text"))]
@@ -45,3 +101,17 @@ pub struct EpubTocNcxNavPointContent {
     #[serde(rename(deserialize = "@src"))]
     src: String,
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EpubTocNcxPageList {
+    #[serde(rename(deserialize = "pageTarget"), default)]
+    children: Vec<EpubTocNcxPageTarget>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EpubTocNcxPageTarget {
+    #[serde(rename(deserialize = "navLabel"))]
+    nav_label: EpubTocNcxNavLabel,
+    #[serde(rename(deserialize = "content"))]
+    content: EpubTocNcxNavPointContent,
+}
@@ -0,0 +1,379 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use parking_lot::Mutex;
+use serde::Serialize;
+use tantivy::collector::TopDocs;
+use tantivy::directory::MmapDirectory;
+use tantivy::query::QueryParser;
+use tantivy::schema::{
+    Field, IndexRecordOption, Schema, TextFieldIndexing, TextOptions, Value, STORED, STRING,
+};
+use tantivy::snippet::SnippetGenerator;
+use tantivy::tokenizer::{RemoveLongFilter, TextAnalyzer, TokenizerManager};
+use tantivy::{doc, Index, IndexReader, IndexWriter, TantivyDocument, Term};
+use tauri::{AppHandle, Manager};
+use typed_path::Utf8NativePathBuf;
+
+use crate::epub::media_type::is_document;
+use crate::epub::text::DocumentText;
+use crate::epub::EpubFile;
+use crate::library::Book;
+use crate::state::AppState;
+use crate::utils::{get_config_dir_path, get_file_signature};
+
+use self::tokenizer::CjkTokenizer;
+
+pub mod tokenizer;
+
+/// Renamed when the tokens change, so that older indexes are rebuilt.
+const TOKENIZER: &str = "cjk_unigrams";
+const WRITER_MEMORY: usize = 50_000_000;
+
+const TITLE_BOOST: f32 = 4.0;
+const AUTHOR_BOOST: f32 = 2.0;
+
+/// The number of chapters with snippets returned for each book.
+const HITS_PER_BOOK: usize = 3;
+
+/// A full-text index of the books in the library. Each book is indexed as a document holding
+/// its title and author, and a document for each spine item holding its text.
+pub struct LibraryIndex {
+    index: Index,
+    reader: IndexReader,
+    writer: Mutex<IndexWriter>,
+    /// The tokenizers of queries, which differ from those of the index.
+    query_tokenizers: TokenizerManager,
+    fields: IndexFields,
+    /// The signatures of the indexed book files, to find the books to reindex.
+    signatures: Mutex<HashMap<String, String>>,
+}
+
+struct IndexFields {
+    book_id: Field,
+    chapter: Field,
+    title: Field,
+    author: Field,
+    body: Field,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LibrarySearchResult {
+    pub id: String,
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub score: f32,
+    pub hits: Vec<LibrarySearchHit>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LibrarySearchHit {
+    /// The index of the document in the original spine.
+    pub index: usize,
+    pub snippet: String,
+    /// The ranges of the matches in the snippet, in UTF-16 code units.
+    pub highlights: Vec<(usize, usize)>,
+    pub cfi: Option<String>,
+    /// The range of the snippet in the text of the document, in bytes.
+    #[serde(skip)]
+    range: (usize, usize),
+}
+
+impl LibraryIndex {
+    fn get_dir() -> Result<PathBuf> {
+        let dir = get_config_dir_path()?;
+        Ok(dir.join("index"))
+    }
+
+    fn get_signatures_path() -> Result<PathBuf> {
+        Ok(Self::get_dir()?.join("books.json"))
+    }
+
+    pub fn open() -> Result<Self> {
+        let (schema, fields) = build_schema();
+        let dir = Self::get_dir()?;
+        std::fs::create_dir_all(&dir).context("Failed to create index directory")?;
+
+        let index = match open_index(&dir, schema.clone()) {
+            Ok(index) => index,
+            Err(e) => {
+                // The index is rebuilt from the books if it's from another version or corrupted.
+                eprintln!("Failed to open the library index, rebuilding:\n{:?}", e);
+                std::fs::remove_dir_all(&dir).context("Failed to remove the library index")?;
+                std::fs::create_dir_all(&dir).context("Failed to create index directory")?;
+                open_index(&dir, schema)?
+            }
+        };
+
+        let tokenizer = TextAnalyzer::builder(CjkTokenizer::for_indexing())
+            .filter(RemoveLongFilter::limit(40))
+            .build();
+        index.tokenizers().register(TOKENIZER, tokenizer);
+
+        let query_tokenizers = TokenizerManager::default();
+        let tokenizer = TextAnalyzer::builder(CjkTokenizer::default())
+            .filter(RemoveLongFilter::limit(40))
+            .build();
+        query_tokenizers.register(TOKENIZER, tokenizer);
+
+        let reader = index.reader()?;
+        let writer = index.writer(WRITER_MEMORY)?;
+
+        let signatures = File::open(Self::get_signatures_path()?)
+            .ok()
+            .and_then(|file| serde_json::from_reader(BufReader::new(file)).ok())
+            .unwrap_or_default();
+
+        Ok(Self {
+            index,
+            reader,
+            writer: Mutex::new(writer),
+            query_tokenizers,
+            fields,
+            signatures: Mutex::new(signatures),
+        })
+    }
+
+    /// Indexes the books which are new or whose files have changed since they were indexed, and
+    /// removes the books no longer in the library.
+    pub fn sync(&self, books: &HashMap<String, Book>) -> Result<()> {
+        let removed: Vec<_> = {
+            let signatures = self.signatures.lock();
+            let removed = signatures.keys().filter(|x| !books.contains_key(*x));
+            removed.cloned().collect()
+        };
+        for id in removed {
+            self.remove_book(&id)?;
+        }
+
+        for (id, book) in books {
+            let Some(signature) = get_file_signature(Path::new(&book.path)) else {
+                continue;
+            };
+            if self.signatures.lock().get(id) == Some(&signature) {
+                continue;
+            }
+
+            if let Err(e) = self.index_book(id, book, signature) {
+                eprintln!("Failed to index {}:\n{:?}", book.path, e);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn index_book(&self, id: &str, book: &Book, signature: String) -> Result<()> {
+        let epub = EpubFile::open(Utf8NativePathBuf::from(&book.path))?;
+        let fields = &self.fields;
+
+        let creators = &epub.rootfile().package.metadata.creator;
+        let mut documents = vec![doc!(
+            fields.book_id => id,
+            fields.title => book.metadata.title.clone().unwrap_or_default(),
+            fields.author => creators.join("\n"),
+        )];
+
+        for index in 0..epub.rootfile().package.spine.children.len() {
+            let Some((_, path)) = epub.get_spine_path(index) else {
+                continue;
+            };
+            if !epub.get_media_type(&path).is_some_and(is_document) {
+                continue;
+            }
+
+            let text = DocumentText::parse(&epub.read_document(&path)?)?;
+            documents.push(doc!(
+                fields.book_id => id,
+                fields.chapter => index as u64,
+                fields.body => text.text,
+            ));
+        }
+
+        let mut writer = self.writer.lock();
+        writer.delete_term(Term::from_field_text(fields.book_id, id));
+        for document in documents {
+            writer.add_document(document)?;
+        }
+        writer.commit()?;
+        drop(writer);
+
+        self.signatures.lock().insert(id.to_string(), signature);
+        self.persist_signatures()
+    }
+
+    fn remove_book(&self, id: &str) -> Result<()> {
+        let mut writer = self.writer.lock();
+        writer.delete_term(Term::from_field_text(self.fields.book_id, id));
+        writer.commit()?;
+        drop(writer);
+
+        self.signatures.lock().remove(id);
+        self.persist_signatures()
+    }
+
+    fn persist_signatures(&self) -> Result<()> {
+        let file = File::create(Self::get_signatures_path()?)?;
+        serde_json::to_writer(file, &*self.signatures.lock())?;
+        Ok(())
+    }
+
+    /// Finds the books matching a query, in the syntax of tantivy, e.g. `"exact phrase"` or
+    /// `title:dune`. Returns at most `limit` books with the best matching chapters.
+    pub fn search(
+        &self,
+        query: &str,
+        limit: usize,
+        books: &HashMap<String, Book>,
+    ) -> Result<Vec<LibrarySearchResult>> {
+        let fields = &self.fields;
+        let searcher = self.reader.searcher();
+
+        let mut parser = QueryParser::new(
+            self.index.schema(),
+            vec![fields.title, fields.author, fields.body],
+            self.query_tokenizers.clone(),
+        );
+        parser.set_conjunction_by_default();
+        parser.set_field_boost(fields.title, TITLE_BOOST);
+        parser.set_field_boost(fields.author, AUTHOR_BOOST);
+        let (query, _) = parser.parse_query_lenient(query);
+
+        let mut generator = SnippetGenerator::create(&searcher, &*query, fields.body)?;
+        generator.set_max_num_chars(160);
+
+        let top_docs = searcher.search(&*query, &TopDocs::with_limit(limit * 20))?;
+
+        let mut results: Vec<LibrarySearchResult> = Vec::new();
+        let mut positions = HashMap::new();
+
+        for (score, address) in top_docs {
+            let doc: TantivyDocument = searcher.doc(address)?;
+            let Some(id) = doc.get_first(fields.book_id).and_then(|x| x.as_str()) else {
+                continue;
+            };
+            let Some(book) = books.get(id) else {
+                continue;
+            };
+
+            let position = *positions.entry(id.to_string()).or_insert_with(|| {
+                results.push(LibrarySearchResult {
+                    id: id.to_string(),
+                    title: book.metadata.title.clone(),
+                    author: book.metadata.author.clone(),
+                    score: 0.0,
+                    hits: Vec::new(),
+                });
+                results.len() - 1
+            });
+            let result = &mut results[position];
+
+            // A book scores the best of its metadata plus the best of its chapters.
+            let Some(chapter) = doc.get_first(fields.chapter).and_then(|x| x.as_u64()) else {
+                result.score += score;
+                continue;
+            };
+            if result.hits.is_empty() {
+                result.score += score;
+            }
+            if result.hits.len() >= HITS_PER_BOOK {
+                continue;
+            }
+
+            let body = doc.get_first(fields.body).and_then(|x| x.as_str());
+            let body = body.unwrap_or_default();
+            let snippet = generator.snippet(body);
+            if snippet.is_empty() {
+                continue;
+            }
+
+            let fragment = snippet.fragment();
+            let start = body.find(fragment).unwrap_or_default();
+            let highlights = snippet.highlighted().iter().map(|range| {
+                let start = utf16_len(&fragment[..range.start]);
+                (start, start + utf16_len(&fragment[range.clone()]))
+            });
+
+            result.hits.push(LibrarySearchHit {
+                index: chapter as usize,
+                snippet: fragment.to_string(),
+                highlights: highlights.collect(),
+                cfi: None,
+                range: (start, start + fragment.len()),
+            });
+        }
+
+        results.sort_by(|a, b| b.score.total_cmp(&a.score));
+        results.truncate(limit);
+        Ok(results)
+    }
+}
+
+/// Fills in the CFIs of the hits, which needs the text of their documents.
+pub fn locate_hits(epub: &EpubFile, result: &mut LibrarySearchResult) {
+    for hit in &mut result.hits {
+        let Some((idref, path)) = epub.get_spine_path(hit.index) else {
+            continue;
+        };
+        let text = epub
+            .read_document(&path)
+            .and_then(|x| DocumentText::parse(&x));
+        if let Ok(text) = text {
+            let (start, end) = hit.range;
+            hit.cfi = text.range_to_cfi(hit.index, idref, start..end);
+        }
+    }
+}
+
+/// Starts the thread indexing the library in the background. Sending to the returned channel
+/// makes it sync the index with the library.
+pub fn start_indexer(app: AppHandle, index: Arc<LibraryIndex>) -> Sender<()> {
+    let (sender, receiver) = mpsc::channel::<()>();
+
+    std::thread::spawn(move || {
+        while receiver.recv().is_ok() {
+            // Requests sent while syncing are handled by the next sync.
+            while receiver.try_recv().is_ok() {}
+
+            let state = app.state::<AppState>();
+            let books = state.library().lock().books().clone();
+            if let Err(e) = index.sync(&books) {
+                eprintln!("Failed to sync the library index:\n{:?}", e);
+            }
+        }
+    });
+
+    sender
+}
+
+fn build_schema() -> (Schema, IndexFields) {
+    let indexing = TextFieldIndexing::default()
+        .set_tokenizer(TOKENIZER)
+        .set_index_option(IndexRecordOption::WithFreqsAndPositions);
+    let text = TextOptions::default().set_indexing_options(indexing);
+
+    let mut builder = Schema::builder();
+    let fields = IndexFields {
+        book_id: builder.add_text_field("book_id", STRING | STORED),
+        chapter: builder.add_u64_field("chapter", STORED),
+        title: builder.add_text_field("title", text.clone()),
+        author: builder.add_text_field("author", text.clone()),
+        // Stored for snippets.
+        body: builder.add_text_field("body", text | STORED),
+    };
+
+    (builder.build(), fields)
+}
+
+fn open_index(dir: &Path, schema: Schema) -> Result<Index> {
+    let directory = MmapDirectory::open(dir)?;
+    Ok(Index::open_or_create(directory, schema)?)
+}
+
+fn utf16_len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
@@ -0,0 +1,141 @@
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+use tantivy::tokenizer::{Token, TokenStream, Tokenizer};
+use unicode_normalization::char::{decompose_canonical, is_combining_mark};
+
+use crate::search::is_cjk;
+
+/// Splits text into words folded to lowercase without diacritics, and CJK text, which has no
+/// word boundaries, into overlapping bigrams. An isolated CJK character is a token by itself.
+///
+/// For indexing, every CJK character is a token too, at the position of the bigram it starts,
+/// so that queries of a single character match while longer queries still match as phrases.
+#[derive(Clone, Default)]
+pub struct CjkTokenizer {
+    token: Token,
+    unigrams: bool,
+}
+
+pub struct CjkTokenStream<'a> {
+    text: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+    token: &'a mut Token,
+    unigrams: bool,
+    /// Whether the previous character was a CJK character.
+    after_cjk: bool,
+    /// The range of the bigram to emit at the position of the last token.
+    bigram: Option<(usize, usize)>,
+}
+
+impl CjkTokenizer {
+    pub fn for_indexing() -> Self {
+        Self {
+            token: Token::default(),
+            unigrams: true,
+        }
+    }
+}
+
+impl Tokenizer for CjkTokenizer {
+    type TokenStream<'a> = CjkTokenStream<'a>;
+
+    fn token_stream<'a>(&'a mut self, text: &'a str) -> CjkTokenStream<'a> {
+        self.token.reset();
+        CjkTokenStream {
+            text,
+            chars: text.char_indices().peekable(),
+            token: &mut self.token,
+            unigrams: self.unigrams,
+            after_cjk: false,
+            bigram: None,
+        }
+    }
+}
+
+impl CjkTokenStream<'_> {
+    fn emit(&mut self, offset_from: usize, offset_to: usize) -> bool {
+        self.token.text.clear();
+        self.token.position = self.token.position.wrapping_add(1);
+        self.token.offset_from = offset_from;
+        self.token.offset_to = offset_to;
+        true
+    }
+}
+
+impl TokenStream for CjkTokenStream<'_> {
+    fn advance(&mut self) -> bool {
+        if let Some((start, end)) = self.bigram.take() {
+            self.token.text.clear();
+            self.token.text.push_str(&self.text[start..end]);
+            self.token.offset_from = start;
+            self.token.offset_to = end;
+            return true;
+        }
+
+        loop {
+            let Some((start, c)) = self.chars.next() else {
+                return false;
+            };
+
+            if is_cjk(c) {
+                let after_cjk = std::mem::replace(&mut self.after_cjk, true);
+                let next = self.chars.peek().copied().filter(|(_, c)| is_cjk(*c));
+                let end = start + c.len_utf8();
+
+                match next {
+                    Some((index, next)) => {
+                        let bigram = (start, index + next.len_utf8());
+                        match self.unigrams {
+                            true => self.bigram = Some(bigram),
+                            false => {
+                                self.emit(bigram.0, bigram.1);
+                                self.token.text.push_str(&self.text[bigram.0..bigram.1]);
+                                return true;
+                            }
+                        }
+                    }
+                    // The last character of CJK text is already in a bigram.
+                    None if after_cjk && !self.unigrams => continue,
+                    None => {}
+                }
+
+                self.emit(start, end);
+                self.token.text.push(c);
+                return true;
+            }
+            self.after_cjk = false;
+
+            if !c.is_alphanumeric() {
+                continue;
+            }
+
+            let mut end = start + c.len_utf8();
+            while let Some(&(index, c)) = self.chars.peek() {
+                if !c.is_alphanumeric() || is_cjk(c) {
+                    break;
+                }
+                self.chars.next();
+                end = index + c.len_utf8();
+            }
+
+            self.emit(start, end);
+            for c in self.text[start..end].chars() {
+                decompose_canonical(c, |c| {
+                    if !is_combining_mark(c) {
+                        self.token.text.extend(c.to_lowercase());
+                    }
+                });
+            }
+            return true;
+        }
+    }
+
+    fn token(&self) -> &Token {
+        self.token
+    }
+
+    fn token_mut(&mut self) -> &mut Token {
+        self.token
+    }
+}
@@ -0,0 +1,339 @@
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use humantime_serde::re::humantime;
+use regex::Regex;
+use serde::Serialize;
+
+use self::lua::LuaTable;
+use self::xpointer::XPointer;
+use crate::annotations::{kindle, Annotation, AnnotationChanges, AnnotationStyle, ImportReport};
+use crate::epub::cfi::Cfi;
+use crate::epub::progress::find_location;
+use crate::epub::stats::BookStats;
+use crate::epub::text::DocumentText;
+use crate::epub::EpubFile;
+use crate::library::bookmark::Bookmark;
+use crate::library::BookMetadata;
+
+pub mod lua;
+pub mod xpointer;
+
+/// Matches the text older versions of KOReader gave highlights without notes, e.g.
+/// `Page 12 It was a bright cold day @ 2024-01-01 10:00:00`.
+static GENERATED_NOTE: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"(?s)^Page \S+ .* @ \d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}$").unwrap()
+});
+
+/// The reading state of a book in KOReader, which keeps it in `metadata.epub.lua` in a `.sdr`
+/// directory next to the book.
+#[derive(Debug, Clone, Default)]
+pub struct Sidecar {
+    pub title: Option<String>,
+    /// The authors, one per line.
+    pub authors: Option<String>,
+    pub percent_finished: Option<f64>,
+    pub last_xpointer: Option<String>,
+    pub highlights: Vec<SidecarHighlight>,
+    pub bookmarks: Vec<SidecarBookmark>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SidecarHighlight {
+    /// The XPointers of the start and end.
+    pub pos0: String,
+    pub pos1: String,
+    pub text: String,
+    pub note: Option<String>,
+    pub color: Option<String>,
+    /// How it's drawn, e.g. `lighten` or `underscore`.
+    pub drawer: Option<String>,
+    pub created_at: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SidecarBookmark {
+    pub page: String,
+    pub note: Option<String>,
+    pub created_at: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct KoreaderImportReport {
+    #[serde(flatten)]
+    pub annotations: ImportReport,
+    pub bookmarks: usize,
+    /// Where the book was last read in KOReader, if its location was moved there.
+    pub location: Option<String>,
+}
+
+/// The highlights, bookmarks and location of a sidecar placed in a book.
+#[derive(Debug, Clone)]
+pub struct KoreaderImport {
+    pub annotations: Vec<Annotation>,
+    pub bookmarks: Vec<Bookmark>,
+    pub location: Option<Cfi>,
+    pub report: KoreaderImportReport,
+}
+
+/// Returns the path of the sidecar of a book file if it exists, e.g.
+/// `Book.sdr/metadata.epub.lua` for `Book.epub`.
+pub fn find_sidecar(book_path: &Path) -> Option<PathBuf> {
+    let stem = book_path.file_stem()?.to_string_lossy();
+    let extension = book_path.extension()?.to_string_lossy().to_lowercase();
+    let dir = book_path.with_file_name(format!("{}.sdr", stem));
+    let path = dir.join(format!("metadata.{}.lua", extension));
+    path.is_file().then_some(path)
+}
+
+impl Sidecar {
+    /// Reads a sidecar, with the annotations of KOReader 2024.07 and later, or the highlights and
+    /// bookmarks of older versions.
+    pub fn parse(content: &str) -> Result<Self> {
+        let value = lua::parse(content).context("Failed to parse the KOReader metadata")?;
+        let table = value.as_table().context("Not KOReader metadata")?;
+        let props = table.get_table("doc_props");
+
+        let mut sidecar = Self {
+            title: props.and_then(|x| x.get_str("title")).map(str::to_string),
+            authors: props.and_then(|x| x.get_str("authors")).map(str::to_string),
+            percent_finished: table.get("percent_finished").and_then(|x| x.as_f64()),
+            last_xpointer: table.get_str("last_xpointer").map(str::to_string),
+            ..Default::default()
+        };
+
+        match table.get_table("annotations") {
+            Some(annotations) => sidecar.read_annotations(annotations),
+            None => sidecar.read_legacy(table),
+        }
+        Ok(sidecar)
+    }
+
+    fn read_annotations(&mut self, annotations: &LuaTable) {
+        for item in annotations.sequence() {
+            let Some(item) = item.as_table() else {
+                continue;
+            };
+            let note = get_text(item, "note");
+            let created_at = get_time(item, "datetime");
+
+            match (item.get_str("pos0"), item.get_str("pos1")) {
+                (Some(pos0), Some(pos1)) => self.highlights.push(SidecarHighlight {
+                    pos0: pos0.to_string(),
+                    pos1: pos1.to_string(),
+                    text: item.get_str("text").unwrap_or_default().to_string(),
+                    note,
+                    color: get_text(item, "color"),
+                    drawer: get_text(item, "drawer"),
+                    created_at,
+                }),
+                _ => {
+                    if let Some(page) = item.get_str("page") {
+                        self.bookmarks.push(SidecarBookmark {
+                            page: page.to_string(),
+                            note,
+                            created_at,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reads the highlights by page of older versions, whose notes are in their bookmarks.
+    fn read_legacy(&mut self, table: &LuaTable) {
+        let pages = table.get_table("highlight");
+        for (_, page) in pages.map_or(&[][..], |x| &x.fields) {
+            let Some(page) = page.as_table() else {
+                continue;
+            };
+            for item in page.sequence() {
+                let Some(item) = item.as_table() else {
+                    continue;
+                };
+                let (Some(pos0), Some(pos1)) = (item.get_str("pos0"), item.get_str("pos1")) else {
+                    continue;
+                };
+                self.highlights.push(SidecarHighlight {
+                    pos0: pos0.to_string(),
+                    pos1: pos1.to_string(),
+                    text: item.get_str("text").unwrap_or_default().to_string(),
+                    note: None,
+                    color: get_text(item, "color"),
+                    drawer: get_text(item, "drawer"),
+                    created_at: get_time(item, "datetime"),
+                });
+            }
+        }
+
+        let bookmarks = table.get_table("bookmarks");
+        for item in bookmarks.map_or_else(Vec::new, |x| x.sequence()) {
+            let Some(item) = item.as_table() else {
+                continue;
+            };
+            let note = get_text(item, "text").filter(|x| !GENERATED_NOTE.is_match(x));
+
+            match item.get_str("pos0") {
+                Some(pos0) => {
+                    let highlight = self.highlights.iter_mut().find(|x| x.pos0 == pos0);
+                    if let Some(highlight) = highlight {
+                        highlight.note = note;
+                    }
+                }
+                None => {
+                    if let Some(page) = item.get_str("page") {
+                        self.bookmarks.push(SidecarBookmark {
+                            page: page.to_string(),
+                            note,
+                            created_at: get_time(item, "datetime"),
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns whether the sidecar is of a book, if it has the title.
+    pub fn matches(&self, metadata: &BookMetadata) -> bool {
+        let Some(title) = &self.title else {
+            return true;
+        };
+        let author = self.authors.as_deref().and_then(|x| x.lines().next());
+        kindle::matches_book(title, author, metadata)
+    }
+
+    /// Places the highlights, bookmarks and last location in a book, skipping the highlights and
+    /// bookmarks already in it. The location falls back to the percentage read.
+    pub fn import(
+        &self,
+        epub: &EpubFile,
+        stats: Option<&BookStats>,
+        existing_annotations: &[Annotation],
+        existing_bookmarks: &[Bookmark],
+    ) -> KoreaderImport {
+        let mut report = KoreaderImportReport::default();
+
+        let mut annotations: Vec<Annotation> = Vec::new();
+        for highlight in &self.highlights {
+            let changes = AnnotationChanges {
+                color: highlight
+                    .color
+                    .clone()
+                    .filter(|x| x.chars().all(|c| c.is_ascii_alphabetic())),
+                style: Some(match highlight.drawer.as_deref() {
+                    Some("underscore") => AnnotationStyle::Underline,
+                    Some("strikeout") => AnnotationStyle::Strikethrough,
+                    _ => AnnotationStyle::Highlight,
+                }),
+                note: highlight.note.clone(),
+                tags: None,
+            };
+            let cfi = place_highlight(epub, highlight);
+            let Some(Ok(mut annotation)) = cfi.map(|x| Annotation::new(epub, &x, changes)) else {
+                report.annotations.unplaced.push(highlight.text.clone());
+                continue;
+            };
+            if let Some(created_at) = highlight.created_at {
+                annotation.created_at = created_at;
+                annotation.updated_at = created_at;
+            }
+
+            let duplicate = existing_annotations
+                .iter()
+                .chain(&annotations)
+                .any(|x| x.cfi == annotation.cfi);
+            if duplicate {
+                report.annotations.duplicates += 1;
+                continue;
+            }
+            annotations.push(annotation);
+        }
+        report.annotations.imported = annotations.len();
+
+        let mut bookmarks: Vec<Bookmark> = Vec::new();
+        for sidecar_bookmark in &self.bookmarks {
+            let Some(cfi) = place(epub, &sidecar_bookmark.page) else {
+                continue;
+            };
+            let location = cfi.to_string();
+            if existing_bookmarks
+                .iter()
+                .chain(&bookmarks)
+                .any(|x| x.cfi == location)
+            {
+                continue;
+            }
+            let mut bookmark = Bookmark::new(epub, &cfi, sidecar_bookmark.note.clone());
+            if let Some(created_at) = sidecar_bookmark.created_at {
+                bookmark.created_at = created_at;
+            }
+            bookmarks.push(bookmark);
+        }
+        report.bookmarks = bookmarks.len();
+
+        let location = self.last_xpointer.as_deref().and_then(|x| place(epub, x));
+        let location = location.or_else(|| find_location(epub, stats, self.percent_finished?));
+
+        KoreaderImport {
+            annotations,
+            bookmarks,
+            location,
+            report,
+        }
+    }
+}
+
+/// Returns the CFI of an XPointer.
+fn place(epub: &EpubFile, xpointer: &str) -> Option<Cfi> {
+    let xpointer: XPointer = xpointer.parse().ok()?;
+    let (text, offset) = xpointer.resolve(epub)?;
+    let (idref, _) = epub.get_spine_path(xpointer.spine_index)?;
+    Some(Cfi::new(
+        xpointer.spine_index,
+        idref,
+        &text.position(offset, false)?,
+    ))
+}
+
+/// Returns the CFI of a highlight. As KOReader may count characters in text nodes differently,
+/// its text is looked for around the start, and the XPointers are only used if it's not found.
+fn place_highlight(epub: &EpubFile, highlight: &SidecarHighlight) -> Option<Cfi> {
+    let start: XPointer = highlight.pos0.parse().ok()?;
+    let end: Option<XPointer> = highlight.pos1.parse().ok();
+    let index = start.spine_index;
+    let end = end
+        .filter(|x| x.spine_index == index)
+        .and_then(|x| x.resolve(epub))
+        .map(|(_, offset)| offset);
+    let (text, start) = start.resolve(epub)?;
+    let end = end.unwrap_or(text.text.len()).max(start);
+
+    let range = find_near(&text, &highlight.text, start).unwrap_or(start..end);
+    let (idref, _) = epub.get_spine_path(index)?;
+    Cfi::from_text_range(index, idref, &text, range)
+}
+
+/// Finds the occurrence of some text closest to an offset, ignoring differences in whitespace.
+fn find_near(text: &DocumentText, needle: &str, offset: usize) -> Option<Range<usize>> {
+    let words: Vec<String> = needle.split_whitespace().map(regex::escape).collect();
+    if words.is_empty() {
+        return None;
+    }
+    let pattern = Regex::new(&words.join(r"\s*")).ok()?;
+    let found = pattern.find_iter(&text.text);
+    let found = found.min_by_key(|x| x.start().abs_diff(offset))?;
+    Some(found.range())
+}
+
+fn get_text(table: &LuaTable, key: &str) -> Option<String> {
+    let text = table.get_str(key)?.trim();
+    (!text.is_empty()).then(|| text.to_string())
+}
+
+/// Reads a time written as `2024-01-01 10:00:00`, in local time, which is taken as UTC.
+fn get_time(table: &LuaTable, key: &str) -> Option<SystemTime> {
+    humantime::parse_rfc3339_weak(table.get_str(key)?.trim()).ok()
+}
@@ -0,0 +1,543 @@
+use anyhow::{bail, Context, Result};
+
+/// The depth of nested tables allowed, as KOReader's settings are only a few levels deep.
+const MAX_DEPTH: usize = 64;
+
+/// A value of the subset of Lua that KOReader writes its settings in: literals and tables.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LuaValue {
+    Nil,
+    Boolean(bool),
+    Number(f64),
+    String(String),
+    Table(LuaTable),
+}
+
+/// The fields of a table in the order they're written, as numbered keys aren't always in order.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LuaTable {
+    pub fields: Vec<(LuaValue, LuaValue)>,
+}
+
+impl LuaValue {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Self::Number(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_table(&self) -> Option<&LuaTable> {
+        match self {
+            Self::Table(table) => Some(table),
+            _ => None,
+        }
+    }
+}
+
+impl LuaTable {
+    /// Returns the value of a field with a string key.
+    pub fn get(&self, key: &str) -> Option<&LuaValue> {
+        let field = self.fields.iter().find(|(x, _)| x.as_str() == Some(key));
+        field.map(|(_, value)| value)
+    }
+
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.get(key)?.as_str()
+    }
+
+    pub fn get_table(&self, key: &str) -> Option<&LuaTable> {
+        self.get(key)?.as_table()
+    }
+
+    /// Returns the values of the fields with number keys, sorted by key.
+    pub fn sequence(&self) -> Vec<&LuaValue> {
+        let mut values: Vec<(f64, &LuaValue)> = self
+            .fields
+            .iter()
+            .filter_map(|(key, value)| Some((key.as_f64()?, value)))
+            .collect();
+        values.sort_by(|a, b| a.0.total_cmp(&b.0));
+        values.into_iter().map(|(_, value)| value).collect()
+    }
+}
+
+/// Parses a file of the form `return { ... }` without running it. Anything other than literals
+/// and tables, e.g. function calls, is an error.
+pub fn parse(content: &str) -> Result<LuaValue> {
+    let mut parser = Parser {
+        input: content
+            .strip_prefix('\u{feff}')
+            .unwrap_or(content)
+            .as_bytes(),
+        index: 0,
+        depth: 0,
+    };
+
+    parser.skip_whitespace()?;
+    if parser.eat_word("return") {
+        parser.skip_whitespace()?;
+    }
+    let value = parser.parse_value()?;
+    parser.skip_whitespace()?;
+    parser.eat(b';');
+    parser.skip_whitespace()?;
+    if parser.index < parser.input.len() {
+        bail!("Unexpected content at byte {}", parser.index);
+    }
+
+    Ok(value)
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    index: usize,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.index).copied()
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<u8> {
+        self.input.get(self.index + offset).copied()
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        let matches = self.peek() == Some(byte);
+        if matches {
+            self.index += 1;
+        }
+        matches
+    }
+
+    /// Consumes a keyword, if it's not the start of a longer name.
+    fn eat_word(&mut self, word: &str) -> bool {
+        let end = self.index + word.len();
+        let matches = self.input[self.index..].starts_with(word.as_bytes())
+            && !self.input.get(end).is_some_and(|x| is_name_byte(*x));
+        if matches {
+            self.index = end;
+        }
+        matches
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<()> {
+        if !self.eat(byte) {
+            bail!("Expected '{}' at byte {}", byte as char, self.index);
+        }
+        Ok(())
+    }
+
+    /// Skips whitespace and comments.
+    fn skip_whitespace(&mut self) -> Result<()> {
+        loop {
+            match self.peek() {
+                Some(x) if x.is_ascii_whitespace() => self.index += 1,
+                Some(b'-') if self.peek_at(1) == Some(b'-') => {
+                    self.index += 2;
+                    if self.peek() == Some(b'[') && self.long_bracket_level().is_some() {
+                        self.parse_long_string()?;
+                    } else {
+                        while self.peek().is_some_and(|x| x != b'\n') {
+                            self.index += 1;
+                        }
+                    }
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<LuaValue> {
+        match self.peek() {
+            Some(b'{') => self.parse_table(),
+            Some(b'"' | b'\'') => Ok(LuaValue::String(self.parse_string()?)),
+            Some(b'[') if self.long_bracket_level().is_some() => {
+                Ok(LuaValue::String(self.parse_long_string()?))
+            }
+            Some(b'-' | b'.' | b'0'..=b'9') => Ok(LuaValue::Number(self.parse_number()?)),
+            _ if self.eat_word("nil") => Ok(LuaValue::Nil),
+            _ if self.eat_word("true") => Ok(LuaValue::Boolean(true)),
+            _ if self.eat_word("false") => Ok(LuaValue::Boolean(false)),
+            Some(_) => bail!("Unsupported expression at byte {}", self.index),
+            None => bail!("Unexpected end of file"),
+        }
+    }
+
+    fn parse_table(&mut self) -> Result<LuaValue> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            bail!("Tables nested too deeply at byte {}", self.index);
+        }
+
+        self.expect(b'{')?;
+        let mut table = LuaTable::default();
+        let mut position = 0;
+
+        loop {
+            self.skip_whitespace()?;
+            if self.eat(b'}') {
+                break;
+            }
+
+            let key = match self.peek() {
+                Some(b'[') if self.long_bracket_level().is_none() => {
+                    self.index += 1;
+                    self.skip_whitespace()?;
+                    let key = self.parse_value()?;
+                    self.skip_whitespace()?;
+                    self.expect(b']')?;
+                    self.skip_whitespace()?;
+                    self.expect(b'=')?;
+                    Some(key)
+                }
+                Some(x) if is_name_start(x) && self.is_field_name() => {
+                    let name = self.parse_name();
+                    self.skip_whitespace()?;
+                    self.expect(b'=')?;
+                    Some(LuaValue::String(name))
+                }
+                _ => None,
+            };
+            self.skip_whitespace()?;
+            let value = self.parse_value()?;
+            let key = key.unwrap_or_else(|| {
+                position += 1;
+                LuaValue::Number(position as f64)
+            });
+            if key == LuaValue::Nil {
+                bail!("Table key is nil at byte {}", self.index);
+            }
+            table.fields.push((key, value));
+
+            self.skip_whitespace()?;
+            if !self.eat(b',') && !self.eat(b';') {
+                self.skip_whitespace()?;
+                self.expect(b'}')?;
+                break;
+            }
+        }
+
+        self.depth -= 1;
+        Ok(LuaValue::Table(table))
+    }
+
+    /// Whether a name at the current position is the key of a field, i.e. followed by `=`.
+    fn is_field_name(&self) -> bool {
+        let rest = &self.input[self.index..];
+        let length = rest.iter().take_while(|x| is_name_byte(**x)).count();
+        let rest = &rest[length..];
+        let after = rest.iter().position(|x| !x.is_ascii_whitespace());
+        after.is_some_and(|x| rest[x] == b'=' && rest.get(x + 1) != Some(&b'='))
+    }
+
+    fn parse_name(&mut self) -> String {
+        let start = self.index;
+        while self.peek().is_some_and(is_name_byte) {
+            self.index += 1;
+        }
+        String::from_utf8_lossy(&self.input[start..self.index]).into_owned()
+    }
+
+    fn parse_number(&mut self) -> Result<f64> {
+        let start = self.index;
+        let negative = self.eat(b'-');
+        self.skip_whitespace()?;
+
+        let number = if self.peek() == Some(b'0') && matches!(self.peek_at(1), Some(b'x' | b'X')) {
+            self.index += 2;
+            let digits_start = self.index;
+            while self.peek().is_some_and(|x| x.is_ascii_hexdigit()) {
+                self.index += 1;
+            }
+            let digits = std::str::from_utf8(&self.input[digits_start..self.index])?;
+            u64::from_str_radix(digits, 16)
+                .with_context(|| format!("Invalid number at byte {}", start))? as f64
+        } else {
+            let digits_start = self.index;
+            while self
+                .peek()
+                .is_some_and(|x| x.is_ascii_digit() || matches!(x, b'.' | b'e' | b'E'))
+            {
+                let exponent = matches!(self.peek(), Some(b'e' | b'E'));
+                self.index += 1;
+                if exponent && matches!(self.peek(), Some(b'+' | b'-')) {
+                    self.index += 1;
+                }
+            }
+            let digits = std::str::from_utf8(&self.input[digits_start..self.index])?;
+            digits
+                .parse::<f64>()
+                .with_context(|| format!("Invalid number at byte {}", start))?
+        };
+
+        Ok(if negative { -number } else { number })
+    }
+
+    /// Parses a quoted string. Escapes are of bytes, which are decoded as UTF-8 at the end.
+    fn parse_string(&mut self) -> Result<String> {
+        let start = self.index;
+        let quote = self.input[self.index];
+        self.index += 1;
+        let mut bytes = Vec::new();
+
+        loop {
+            let Some(byte) = self.peek() else {
+                bail!("Unfinished string at byte {}", start);
+            };
+            self.index += 1;
+            match byte {
+                _ if byte == quote => break,
+                b'\n' => bail!("Unfinished string at byte {}", start),
+                b'\\' => self.parse_escape(&mut bytes)?,
+                _ => bytes.push(byte),
+            }
+        }
+
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    fn parse_escape(&mut self, bytes: &mut Vec<u8>) -> Result<()> {
+        let Some(byte) = self.peek() else {
+            bail!("Unfinished escape at byte {}", self.index);
+        };
+        self.index += 1;
+
+        match byte {
+            b'n' => bytes.push(b'\n'),
+            b't' => bytes.push(b'\t'),
+            b'r' => bytes.push(b'\r'),
+            b'a' => bytes.push(0x07),
+            b'b' => bytes.push(0x08),
+            b'f' => bytes.push(0x0c),
+            b'v' => bytes.push(0x0b),
+            b'\\' | b'"' | b'\'' => bytes.push(byte),
+            // An escaped line break is a line break.
+            b'\n' => {
+                bytes.push(b'\n');
+                self.eat(b'\r');
+            }
+            b'\r' => {
+                bytes.push(b'\n');
+                self.eat(b'\n');
+            }
+            // Skips the whitespace that follows.
+            b'z' => {
+                while self.peek().is_some_and(|x| x.is_ascii_whitespace()) {
+                    self.index += 1;
+                }
+            }
+            b'x' => {
+                let digits = self
+                    .input
+                    .get(self.index..self.index + 2)
+                    .unwrap_or_default();
+                let digits = std::str::from_utf8(digits).unwrap_or_default();
+                let value = u8::from_str_radix(digits, 16)
+                    .with_context(|| format!("Invalid escape at byte {}", self.index))?;
+                bytes.push(value);
+                self.index += 2;
+            }
+            b'u' => {
+                self.expect(b'{')?;
+                let start = self.index;
+                while self.peek().is_some_and(|x| x.is_ascii_hexdigit()) {
+                    self.index += 1;
+                }
+                let digits = std::str::from_utf8(&self.input[start..self.index])?;
+                let value = u32::from_str_radix(digits, 16)
+                    .ok()
+                    .and_then(char::from_u32);
+                let value = value.with_context(|| format!("Invalid escape at byte {}", start))?;
+                self.expect(b'}')?;
+                bytes.extend_from_slice(value.encode_utf8(&mut [0; 4]).as_bytes());
+            }
+            b'0'..=b'9' => {
+                let start = self.index - 1;
+                while self.index - start < 3 && self.peek().is_some_and(|x| x.is_ascii_digit()) {
+                    self.index += 1;
+                }
+                let digits = std::str::from_utf8(&self.input[start..self.index])?;
+                let value: u8 = digits
+                    .parse()
+                    .with_context(|| format!("Invalid escape at byte {}", start))?;
+                bytes.push(value);
+            }
+            _ => bail!("Invalid escape at byte {}", self.index - 1),
+        }
+
+        Ok(())
+    }
+
+    /// Returns the level of a long bracket at the current position, e.g. 2 for `[==[`.
+    fn long_bracket_level(&self) -> Option<usize> {
+        let rest = self.input.get(self.index + 1..)?;
+        let level = rest.iter().take_while(|x| **x == b'=').count();
+        (rest.get(level) == Some(&b'[')).then_some(level)
+    }
+
+    /// Parses a long string, e.g. `[[...]]`, whose first line break is skipped.
+    fn parse_long_string(&mut self) -> Result<String> {
+        let start = self.index;
+        let level = self
+            .long_bracket_level()
+            .context("Expected a long bracket")?;
+        self.index += level + 2;
+        if self.eat(b'\r') {
+            self.eat(b'\n');
+        } else if self.eat(b'\n') {
+            self.eat(b'\r');
+        }
+
+        let close = format!("]{}]", "=".repeat(level));
+        let rest = &self.input[self.index..];
+        let length = rest
+            .windows(close.len())
+            .position(|x| x == close.as_bytes())
+            .with_context(|| format!("Unfinished long string at byte {}", start))?;
+        let value = String::from_utf8_lossy(&rest[..length]).into_owned();
+        self.index += length + close.len();
+        Ok(value)
+    }
+}
+
+fn is_name_start(byte: u8) -> bool {
+    byte.is_ascii_alphabetic() || byte == b'_'
+}
+
+fn is_name_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(value: &str) -> LuaValue {
+        LuaValue::String(value.to_string())
+    }
+
+    #[test]
+    fn parses_sidecars() {
+        let content = r#"-- we can read Lua syntax here!
+return {
+    ["annotations"] = {
+        [1] = {
+            ["datetime"] = "2024-03-01 10:00:00",
+            ["drawer"] = "underscore",
+            ["note"] = "A \"famous\" line\
+second line",
+            ["pos0"] = "/body/DocFragment[1]/body/p[1]/text().0",
+            ["pos1"] = "/body/DocFragment[1]/body/p[1]/text().33",
+        },
+    },
+    ["doc_props"] = {
+        ["authors"] = "Jane Doe\nJohn Roe",
+        ["title"] = "Test Book",
+    },
+    ["percent_finished"] = 0.4521,
+    ["summary"] = { status = "reading", modified = '2024-03-01' },
+}
+"#;
+        let value = parse(content).unwrap();
+        let table = value.as_table().unwrap();
+
+        let annotations = table.get_table("annotations").unwrap().sequence();
+        let annotation = annotations[0].as_table().unwrap();
+        assert_eq!(
+            annotation.get_str("note"),
+            Some("A \"famous\" line\nsecond line")
+        );
+        assert_eq!(annotation.get_str("drawer"), Some("underscore"));
+
+        let props = table.get_table("doc_props").unwrap();
+        assert_eq!(props.get_str("authors"), Some("Jane Doe\nJohn Roe"));
+        assert_eq!(
+            table.get("percent_finished"),
+            Some(&LuaValue::Number(0.4521))
+        );
+        let summary = table.get_table("summary").unwrap();
+        assert_eq!(summary.get_str("status"), Some("reading"));
+        assert_eq!(summary.get_str("modified"), Some("2024-03-01"));
+    }
+
+    #[test]
+    fn parses_escapes() {
+        let value = parse(r#""a\tb\\c\'d\65\x41\u{e9}\u{1F600}\0""#).unwrap();
+        assert_eq!(value, string("a\tb\\c'dAA\u{e9}\u{1f600}\0"));
+
+        // Escaped bytes are decoded as UTF-8 together.
+        let value = parse(r#"'\xE4\xBD\xA0\228\189\160'"#).unwrap();
+        assert_eq!(value, string("你你"));
+
+        let value = parse("'one \\z\n     two'").unwrap();
+        assert_eq!(value, string("one two"));
+
+        assert!(parse(r#""\q""#).is_err());
+        assert!(parse(r#""\300""#).is_err());
+        assert!(parse("\"unfinished\nstring\"").is_err());
+    }
+
+    #[test]
+    fn parses_long_strings() {
+        assert_eq!(
+            parse("[[\nfirst\nsecond]]").unwrap(),
+            string("first\nsecond")
+        );
+        assert_eq!(parse("[==[a]]b]=]c]==]").unwrap(), string("a]]b]=]c"));
+        assert_eq!(
+            parse(r"[[no \n escapes]]").unwrap(),
+            string(r"no \n escapes")
+        );
+        assert!(parse("[=[unfinished]]").is_err());
+    }
+
+    #[test]
+    fn skips_comments() {
+        let content = "--[==[ a long\ncomment ]==] return { -- a comment\n 1, --[[ x ]] 2 }";
+        let value = parse(content).unwrap();
+        let table = value.as_table().unwrap();
+        assert_eq!(
+            table.sequence(),
+            vec![&LuaValue::Number(1.0), &LuaValue::Number(2.0)]
+        );
+    }
+
+    #[test]
+    fn parses_keys_and_numbers() {
+        let content = "{ 'a', [3] = 'c', 'b'; x = -0x10, [ [[long key]] ] = 1e-3, [true] = nil }";
+        let value = parse(content).unwrap();
+        let table = value.as_table().unwrap();
+
+        let sequence: Vec<_> = table.sequence().into_iter().cloned().collect();
+        assert_eq!(sequence, vec![string("a"), string("b"), string("c")]);
+        assert_eq!(table.get("x"), Some(&LuaValue::Number(-16.0)));
+        assert_eq!(table.get("long key"), Some(&LuaValue::Number(0.001)));
+        assert_eq!(table.fields[5], (LuaValue::Boolean(true), LuaValue::Nil));
+    }
+
+    #[test]
+    fn rejects_code() {
+        assert!(parse("return { a = os.execute('x') }").is_err());
+        assert!(parse("{ [nil] = 1 }").is_err());
+        assert!(parse("{ 1 2 }").is_err());
+        assert!(parse("{ 1 } { 2 }").is_err());
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let nested = |depth: usize| format!("{}{}", "{".repeat(depth), "}".repeat(depth));
+        assert!(parse(&nested(MAX_DEPTH)).is_ok());
+        assert!(parse(&nested(MAX_DEPTH + 1)).is_err());
+        assert!(parse(&nested(100_000)).is_err());
+    }
+}
@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, LazyLock};
+
+use anyhow::{Context, Error, Result};
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use regex::Regex;
+
+use crate::epub::text::{DocumentText, DomPosition};
+use crate::epub::EpubFile;
+
+/// Matches the spine document of an XPointer, e.g. `/body/DocFragment[3]`.
+static FRAGMENT: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^/body/DocFragment(?:\[(\d+)\])?(.*)$").unwrap());
+
+/// Matches a step of an XPointer, e.g. `p[2]` or `text()[2]`.
+static STEP: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^([\w:.-]+|text\(\))(?:\[(\d+)\])?$").unwrap());
+
+/// A location as written by KOReader, e.g. `/body/DocFragment[3]/body/div/p[2]/text().15`: the
+/// spine document, an XPath to an element or a text node in it, and an offset in characters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XPointer {
+    /// The index of the document in the spine.
+    pub spine_index: usize,
+    /// The names of the elements from the root element, and their indices from 1 among the
+    /// elements with the same name.
+    pub steps: Vec<(String, usize)>,
+    /// The index of the text node from 1, if the location is in one.
+    pub text_node: Option<usize>,
+    pub offset: usize,
+}
+
+impl FromStr for XPointer {
+    type Err = Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let captures = FRAGMENT
+            .captures(value.trim())
+            .context("Not an XPointer of a document")?;
+        let fragment: usize = match captures.get(1) {
+            Some(x) => x.as_str().parse()?,
+            None => 1,
+        };
+        let mut path = captures.get(2).map_or("", |x| x.as_str());
+
+        // The offset follows the last step, e.g. `text().15`.
+        let mut offset = 0;
+        if let Some((rest, digits)) = path.rsplit_once('.') {
+            if let Ok(value) = digits.parse() {
+                (path, offset) = (rest, value);
+            }
+        }
+
+        let mut steps = Vec::new();
+        let mut text_node = None;
+        for step in path.split('/').filter(|x| !x.is_empty()) {
+            let captures = STEP.captures(step).context("Invalid step in XPointer")?;
+            let index: usize = captures.get(2).map_or(Ok(1), |x| x.as_str().parse())?;
+            match &captures[1] {
+                "text()" => text_node = Some(index),
+                name => steps.push((name.to_lowercase(), index)),
+            }
+        }
+
+        Ok(Self {
+            spine_index: fragment
+                .checked_sub(1)
+                .context("Invalid document in XPointer")?,
+            steps,
+            text_node,
+            offset,
+        })
+    }
+}
+
+impl XPointer {
+    /// Finds the location in its spine document, and returns the text of the document with the
+    /// offset of the location in it. If an element of the path isn't found, e.g. as KOReader
+    /// wraps some elements, the location is placed in the closest element found.
+    pub fn resolve(&self, epub: &EpubFile) -> Option<(Arc<DocumentText>, usize)> {
+        let (_, path) = epub.get_spine_path(self.spine_index)?;
+        let text = epub.document_text(&path).ok()?;
+        let content = epub.read_document(&path).ok()?;
+        let position = self.find_position(&content).ok()?;
+        let offset = text.offset(&position)?;
+        Some((text, offset))
+    }
+
+    /// Maps the path to the CFI steps of the node, and the offset to UTF-16 code units.
+    fn find_position(&self, content: &str) -> Result<DomPosition> {
+        let mut reader = Reader::from_str(content);
+        reader.check_end_names(false);
+
+        // The open elements, with the number of their child elements, of the child elements
+        // with each name, and of their text nodes.
+        let mut open: Vec<(usize, HashMap<String, usize>, usize)> = Vec::new();
+        let mut steps = Vec::new();
+        let mut matched = 0;
+
+        loop {
+            // Whether the innermost open element is the one of the path found last.
+            let current = open.len() == matched + 1;
+            let (start, empty) = match reader.read_event()? {
+                Event::Eof => break,
+                Event::Start(start) => (start, false),
+                Event::Empty(start) => (start, true),
+                // The element ended without the rest of the path.
+                Event::End(_) if current => break,
+                Event::End(_) => {
+                    open.pop();
+                    continue;
+                }
+                Event::Text(text) if current && matched == self.steps.len() => {
+                    let text = text.unescape()?;
+                    // KOReader drops the text nodes of only whitespace.
+                    if text.trim().is_empty() {
+                        continue;
+                    }
+                    let (children, _, texts) = open.last_mut().unwrap();
+                    *texts += 1;
+                    if self.text_node == Some(*texts) {
+                        steps.push(*children * 2 + 1);
+                        let skipped = text.chars().take(self.offset);
+                        return Ok(DomPosition {
+                            steps,
+                            offset: skipped.map(char::len_utf16).sum(),
+                        });
+                    }
+                    continue;
+                }
+                _ => continue,
+            };
+
+            if current {
+                let name = String::from_utf8_lossy(start.local_name().as_ref()).to_lowercase();
+                let (children, names, _) = open.last_mut().unwrap();
+                *children += 1;
+                let count = names.entry(name.clone()).or_default();
+                *count += 1;
+
+                if self.steps.get(matched) == Some(&(name, *count)) {
+                    matched += 1;
+                    steps.push(*children * 2);
+                    if empty || (matched == self.steps.len() && self.text_node.is_none()) {
+                        break;
+                    }
+                }
+            }
+            if !empty {
+                open.push((0, HashMap::new(), 0));
+            }
+        }
+
+        Ok(DomPosition { steps, offset: 0 })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DOCUMENT: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<html xmlns="http://www.w3.org/1999/xhtml">
+<head><title>Chapter One</title></head>
+<body>
+  <h1>Chapter One</h1>
+  <p>It was a bright cold day in April, and the clocks were striking thirteen.</p>
+  <p>Winston Smith <em>slipped</em> quickly through the glass doors, <br/>though not
+  quickly enough.</p>
+  <div><p>Première, déjà vu — 你好.</p></div>
+</body>
+</html>"#;
+
+    fn find(xpointer: &str) -> DomPosition {
+        let xpointer: XPointer = xpointer.parse().unwrap();
+        xpointer.find_position(DOCUMENT).unwrap()
+    }
+
+    /// Returns the text of the document from a position.
+    fn text_from(xpointer: &str) -> String {
+        let text = DocumentText::parse(DOCUMENT).unwrap();
+        let offset = text.offset(&find(xpointer)).unwrap();
+        text.text[offset..].to_string()
+    }
+
+    #[test]
+    fn parses_xpointers() {
+        let xpointer: XPointer = "/body/DocFragment[3]/body/div/p[2]/text().15"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            xpointer,
+            XPointer {
+                spine_index: 2,
+                steps: vec![
+                    ("body".to_string(), 1),
+                    ("div".to_string(), 1),
+                    ("p".to_string(), 2)
+                ],
+                text_node: Some(1),
+                offset: 15,
+            }
+        );
+
+        let xpointer: XPointer = "/body/DocFragment/body/section.3".parse().unwrap();
+        assert_eq!(xpointer.spine_index, 0);
+        assert_eq!(
+            xpointer.steps,
+            vec![("body".to_string(), 1), ("section".to_string(), 1)]
+        );
+        assert_eq!(xpointer.text_node, None);
+        assert_eq!(xpointer.offset, 3);
+
+        let xpointer: XPointer = "/body/DocFragment[12]/body/DIV[2]/svg:image"
+            .parse()
+            .unwrap();
+        assert_eq!(xpointer.spine_index, 11);
+        assert_eq!(xpointer.steps[1], ("div".to_string(), 2));
+        assert_eq!(xpointer.steps[2], ("svg:image".to_string(), 1));
+
+        assert!("/body/DocFragment[0]/body".parse::<XPointer>().is_err());
+        assert!("#_doc_fragment_3".parse::<XPointer>().is_err());
+        assert!("/body/DocFragment[1]/body/p[x]"
+            .parse::<XPointer>()
+            .is_err());
+    }
+
+    #[test]
+    fn finds_text_nodes() {
+        assert_eq!(
+            find("/body/DocFragment[1]/body/p[1]/text().16"),
+            DomPosition {
+                steps: vec![4, 4, 1],
+                offset: 16
+            }
+        );
+        assert!(text_from("/body/DocFragment[1]/body/p[1]/text().16").starts_with("cold day"));
+    }
+
+    #[test]
+    fn counts_text_nodes_around_elements() {
+        assert!(text_from("/body/DocFragment[1]/body/p[2]/text()[2].1").starts_with("quickly"));
+        assert!(text_from("/body/DocFragment[1]/body/p[2]/em/text().0").starts_with("slipped"));
+        assert!(text_from("/body/DocFragment[1]/body/p[2]/text()[3].2").starts_with("ough not"));
+    }
+
+    #[test]
+    fn counts_offsets_in_characters() {
+        let position = find("/body/DocFragment[1]/body/div/p/text().20");
+        // `—` and `你` are one UTF-16 unit each, like the other characters.
+        assert_eq!(position.offset, 20);
+        assert!(text_from("/body/DocFragment[1]/body/div/p/text().10").starts_with("déjà"));
+        assert!(text_from("/body/DocFragment[1]/body/div/p/text().20").starts_with("你好"));
+    }
+
+    #[test]
+    fn falls_back_to_the_closest_element() {
+        // KOReader may wrap elements which aren't in the document.
+        let position = find("/body/DocFragment[1]/body/p[2]/span/text().4");
+        assert_eq!(position.steps, vec![4, 6]);
+        assert_eq!(position.offset, 0);
+
+        let position = find("/body/DocFragment[1]/body/p[9]/text().4");
+        assert_eq!(position.steps, vec![4]);
+    }
+}
@@ -7,9 +7,18 @@ use std::time::SystemTime;
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 
+use crate::epub::anchor::TextQuote;
+use crate::epub::cfi::compare_locations;
+use crate::epub::progress::ReadingProgress;
+use crate::epub::stats::BookStats;
 use crate::epub::EpubFile;
+use crate::settings::RenderSettings;
 use crate::utils::get_config_dir_path;
 
+use self::bookmark::Bookmark;
+
+pub mod bookmark;
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 /// Most of the time we do both read and write (e.g. updating reading state),
 /// so we don't use a RwLock as it's not a read-heavy load.
@@ -29,6 +38,7 @@ impl Library {
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(path)
             .context("Failed to open library.json")?;
         let reader = BufReader::new(file);
@@ -57,10 +67,65 @@ impl Library {
 pub struct Book {
     pub path: String,
     pub location: Option<String>,
+    /// The text around `location`, to find it again if the book changes.
+    #[serde(default)]
+    pub location_quote: Option<TextQuote>,
+    /// Where `location` is in the book, if it could be resolved.
+    #[serde(default)]
+    pub progress: Option<ReadingProgress>,
     #[serde(with = "humantime_serde")]
     pub last_read_at: SystemTime,
     #[serde(default)]
     pub metadata: BookMetadata,
+    #[serde(default)]
+    pub render: RenderSettings,
+    /// Computed in the background after the book is opened, from the file with `signature`.
+    #[serde(default)]
+    pub stats: Option<BookStats>,
+    /// Sorted by location.
+    #[serde(default)]
+    pub bookmarks: Vec<Bookmark>,
+    /// The signature of the file when the saved locations were last placed in it. If the file
+    /// changes, they're placed again by the text quoted at them, and the stats are computed
+    /// again.
+    #[serde(default)]
+    pub signature: Option<String>,
+    /// The saved locations which couldn't be placed the last time the file changed.
+    #[serde(default)]
+    pub lost_anchors: Vec<LostAnchor>,
+    /// The documents which had to be repaired to be read.
+    #[serde(default)]
+    pub warnings: Vec<String>,
+}
+
+impl Book {
+    /// Inserts a bookmark, keeping the bookmarks sorted by location.
+    pub fn add_bookmark(&mut self, bookmark: Bookmark) {
+        let index = self
+            .bookmarks
+            .partition_point(|x| compare_locations(&x.cfi, &bookmark.cfi).is_le());
+        self.bookmarks.insert(index, bookmark);
+    }
+}
+
+/// A saved location which couldn't be found again after the file of a book changed, and was
+/// left where it was.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LostAnchor {
+    pub kind: AnchorKind,
+    /// The ID of the bookmark or annotation.
+    pub id: Option<String>,
+    pub cfi: String,
+    /// The quoted text, or the text before the location if it isn't a range.
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnchorKind {
+    Location,
+    Bookmark,
+    Annotation,
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
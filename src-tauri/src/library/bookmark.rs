@@ -0,0 +1,75 @@
+use std::time::SystemTime;
+
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+
+use crate::epub::anchor::TextQuote;
+use crate::epub::cfi::Cfi;
+use crate::epub::progress::find_toc_entry;
+use crate::epub::EpubFile;
+use crate::search::is_cjk;
+
+/// The number of characters of the text at a bookmark in its label.
+const EXCERPT_LENGTH: usize = 60;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bookmark {
+    pub id: String,
+    /// The location, saved against the original spine.
+    pub cfi: String,
+    #[serde(with = "humantime_serde")]
+    pub created_at: SystemTime,
+    /// The chapter and the text at the location, e.g. `Chapter 3: It was a bright cold day…`.
+    pub label: String,
+    /// The title given by the user, shown instead of the label.
+    pub title: Option<String>,
+    /// The text around the location, to find it again if the book changes.
+    #[serde(default)]
+    pub quote: Option<TextQuote>,
+}
+
+impl Bookmark {
+    pub fn new(epub: &EpubFile, cfi: &Cfi, title: Option<String>) -> Self {
+        Self {
+            id: Alphanumeric.sample_string(&mut rand::thread_rng(), 16),
+            cfi: cfi.to_string(),
+            created_at: SystemTime::now(),
+            label: make_label(epub, cfi).unwrap_or_default(),
+            title: title.filter(|x| !x.trim().is_empty()),
+            quote: TextQuote::new(epub, cfi),
+        }
+    }
+}
+
+fn make_label(epub: &EpubFile, cfi: &Cfi) -> Option<String> {
+    let index = cfi.spine_index()?;
+    let (_, path) = epub.get_spine_path(index)?;
+    let text = epub.document_text(&path).ok()?;
+    let offset = cfi.start().position().and_then(|x| text.offset(&x));
+    let offset = offset.unwrap_or_default();
+
+    let entries = epub.toc().entries();
+    let chapter = find_toc_entry(epub, &entries, index, &text, offset);
+    let chapter = chapter.map(|x| entries[x].label.as_str());
+
+    // The excerpt starts at the start of the word at the location.
+    let before = &text.text[..offset];
+    let start = before
+        .char_indices()
+        .rfind(|(_, c)| !c.is_alphanumeric() || is_cjk(*c))
+        .map_or(0, |(i, c)| i + c.len_utf8());
+
+    let rest = text.text[start..].replace('\n', " ");
+    let rest = rest.trim();
+    let mut excerpt: String = rest.chars().take(EXCERPT_LENGTH).collect();
+    if excerpt.len() < rest.len() {
+        excerpt.truncate(excerpt.trim_end().len());
+        excerpt.push('…');
+    }
+
+    Some(match (chapter, excerpt.is_empty()) {
+        (Some(chapter), false) => format!("{chapter}: {excerpt}"),
+        (Some(chapter), true) => chapter.to_string(),
+        (None, _) => excerpt,
+    })
+}
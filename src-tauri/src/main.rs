@@ -6,7 +6,6 @@
 #![feature(lazy_cell)]
 
 use std::env;
-use std::error::Error;
 use std::ffi::OsString;
 use std::path::PathBuf;
 
@@ -14,22 +13,32 @@ use anyhow::{Context, Result};
 use serde_json::json;
 use state::AppState;
 use tauri::api::dialog;
-use tauri::http::{Request, Response};
 use tauri::{App, AppHandle, Manager, WindowBuilder, WindowEvent, WindowUrl, Wry};
 use typed_path::Utf8NativePathBuf;
 use utils::{get_config_dir_path, init_dir};
 
+pub mod annotations;
+pub mod cli;
 pub mod commands;
 pub mod epub;
 pub mod error;
+pub mod index;
+pub mod koreader;
 pub mod library;
 pub mod path;
 pub mod renderer;
+pub mod search;
+pub mod settings;
 pub mod state;
 pub mod utils;
 pub mod zip;
 
 fn main() {
+    let args: Vec<OsString> = env::args_os().collect();
+    if let Some(code) = cli::run(&args) {
+        std::process::exit(code);
+    }
+
     tauri::Builder::default()
         .setup(|app| {
             if let Err(e) = app_setup(app) {
@@ -44,7 +53,7 @@ fn main() {
             let cwd = PathBuf::from(cwd);
             launch(app.clone(), argv, cwd);
         }))
-        .register_uri_scheme_protocol("book", book_protocol)
+        .register_uri_scheme_protocol("book", renderer::protocol::handle_request)
         .invoke_handler(tauri::generate_handler![
             commands::open_book,
             commands::open_library,
@@ -52,8 +61,33 @@ fn main() {
             commands::get_library,
             commands::get_toc,
             commands::get_rootfile,
+            commands::get_book_warnings,
+            commands::get_chapter_text,
+            commands::search_book,
+            commands::search_library,
+            commands::get_reading_time,
+            commands::get_page_list,
+            commands::get_page,
+            commands::get_page_location,
             commands::get_progress,
             commands::save_progress,
+            commands::add_bookmark,
+            commands::list_bookmarks,
+            commands::rename_bookmark,
+            commands::remove_bookmark,
+            commands::create_annotation,
+            commands::update_annotation,
+            commands::delete_annotation,
+            commands::list_annotations,
+            commands::find_annotations_by_tag,
+            commands::export_annotations,
+            commands::import_annotations,
+            commands::import_kindle_clippings,
+            commands::import_koreader,
+            commands::get_settings,
+            commands::save_settings,
+            commands::get_book_settings,
+            commands::save_book_settings,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -64,9 +98,10 @@ fn app_setup(app: &mut App) -> Result<()> {
     init_dir(&dir)?;
     init_dir(&dir.join("cover"))?;
 
-    let port = renderer::start_http_server(app.handle())?;
-    let state = AppState::init(port)?;
+    let (port, token) = renderer::start_http_server(app.handle())?;
+    let state = AppState::init(port, token)?;
     app.manage(state);
+    app.state::<AppState>().start_indexer(app.handle());
 
     let handle = app.handle();
     let args = env::args_os().collect();
@@ -76,10 +111,6 @@ fn app_setup(app: &mut App) -> Result<()> {
     Ok(())
 }
 
-fn book_protocol(_app: &AppHandle, _req: &Request) -> Result<Response, Box<dyn Error>> {
-    unimplemented!()
-}
-
 fn launch(app: AppHandle, argv: Vec<OsString>, cwd: PathBuf) {
     let argv = argv.into_iter().skip(1);
     if argv.len() == 0 {
@@ -118,7 +149,6 @@ fn launch(app: AppHandle, argv: Vec<OsString>, cwd: PathBuf) {
 
 fn launch_library(app: AppHandle) -> Result<()> {
     let state = app.state::<AppState>();
-    let port = state.renderer_port();
 
     match app.get_window("library") {
         Some(window) => window.set_focus().context("Failed to focus library window"),
@@ -126,7 +156,7 @@ fn launch_library(app: AppHandle) -> Result<()> {
             let url = WindowUrl::App("index.html".into());
 
             let config = json!({
-                "renderer": format!("http://127.0.0.1:{port}"),
+                "renderer": state.renderer_url(),
             });
 
             let window = WindowBuilder::new(&app, "library", url)
@@ -150,22 +180,31 @@ fn launch_library(app: AppHandle) -> Result<()> {
 /// `path` must be canonicalized before calling this function.
 fn launch_book(app: AppHandle, path: Utf8NativePathBuf) -> Result<()> {
     let state = app.state::<AppState>();
-    let port = state.renderer_port();
     let (id, book) = state.open_book(path.clone())?;
 
+    // The reader waits for the saved locations before it gets them.
+    state.begin_refresh(&id);
+    let handle = app.clone();
+    let book_id = id.clone();
+    std::thread::spawn(
+        move || match handle.state::<AppState>().refresh_book(&book_id) {
+            Ok(lost) if !lost.is_empty() => {
+                eprintln!(
+                    "{} saved locations of {} couldn't be placed",
+                    lost.len(),
+                    book_id
+                );
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Failed to refresh {}:\n{:?}", book_id, e),
+        },
+    );
+
     match app.get_window(&id) {
         Some(window) => window.set_focus().context("Failed to focus reader window"),
         None => {
             let url = WindowUrl::App("index.html".into());
 
-            /*
-            // TODO: use custom protocol after tauri-apps/tauri#6536 resolved
-            #[cfg(windows)]
-            let origin = "https://book.localhost";
-            #[cfg(unix)]
-            let origin = "book://localhost";
-            */
-
             let title = match book.metadata.title {
                 Some(title) => format!("{} - Ellisia", title),
                 None => match path.file_stem() {
@@ -179,7 +218,8 @@ fn launch_book(app: AppHandle, path: Utf8NativePathBuf) -> Result<()> {
                     "id": id,
                     "path": path.as_str(),
                 },
-                "renderer": format!("http://127.0.0.1:{port}"),
+                "books": state.books_url(),
+                "renderer": state.renderer_url(),
             });
 
             let window = WindowBuilder::new(&app, id.clone(), url)
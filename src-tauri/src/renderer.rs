@@ -1,9 +1,10 @@
-use std::io::Cursor;
+use std::io::{Cursor, Read};
 use std::str::FromStr;
 use std::sync::LazyLock;
 
 use anyhow::{anyhow, Context, Result};
 use image::{DynamicImage, ImageOutputFormat};
+use rand::distributions::{Alphanumeric, DistString};
 use rayon_core::ThreadPoolBuilder;
 use regex::Regex;
 use tauri::{AppHandle, Manager};
@@ -13,32 +14,64 @@ use typed_path::Utf8NativePathBuf;
 use crate::epub::EpubFile;
 use crate::state::AppState;
 use crate::utils::get_config_dir_path;
-use crate::zip::ZipError;
+
+use self::resource::{get_book_resource, ResourceRequest};
+
+pub mod protocol;
+pub mod resource;
+pub mod transform;
 
 type BytesResponse = Response<Cursor<Vec<u8>>>;
 
-pub fn start_http_server(app: AppHandle) -> Result<u16> {
+/// The origin of the app's own pages, which is the only one allowed to read responses.
+#[cfg(windows)]
+const APP_ORIGIN: &str = "https://tauri.localhost";
+#[cfg(not(windows))]
+const APP_ORIGIN: &str = "tauri://localhost";
+
+/// The `devPath` in `Tauri.toml`.
+#[cfg(debug_assertions)]
+const DEV_ORIGIN: &str = "http://localhost:1420";
+
+/// Starts the server and returns its port and the access token generated for this session.
+///
+/// Any local process can connect to the server, so requests to `/book/` and `/cover/` must
+/// carry the token, either as the first path segment (e.g. `/{token}/book/...`) or in the
+/// `X-Ellisia-Token` header.
+pub fn start_http_server(app: AppHandle) -> Result<(u16, String)> {
     let server = Server::http("127.0.0.1:0").map_err(|e| anyhow!("{e}"))?;
     let port = server.server_addr().to_ip().unwrap().port();
+    let token = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
 
     let cpus = num_cpus::get().min(8);
     let pool = ThreadPoolBuilder::new().num_threads(cpus).build()?;
 
+    let server_token = token.clone();
     std::thread::spawn(move || {
         for request in server.incoming_requests() {
             let app = app.clone();
+            let token = server_token.clone();
             pool.spawn(move || {
-                if let Err(e) = handle_request(app, request) {
+                if let Err(e) = handle_request(app, request, &token) {
                     eprintln!("Error handling request:\n{:?}", e);
                 }
             });
         }
     });
 
-    Ok(port)
+    Ok((port, token))
+}
+
+pub fn is_app_origin(origin: &str) -> bool {
+    #[cfg(debug_assertions)]
+    if origin == DEV_ORIGIN {
+        return true;
+    }
+
+    origin == APP_ORIGIN
 }
 
-fn handle_request(app: AppHandle, request: Request) -> Result<()> {
+fn handle_request(app: AppHandle, request: Request, token: &str) -> Result<()> {
     if request.method() != &Method::Get {
         let response = Response::new_empty(StatusCode(405));
         return Ok(request.respond(response)?);
@@ -46,14 +79,38 @@ fn handle_request(app: AppHandle, request: Request) -> Result<()> {
 
     let uri = tauri::http::Uri::try_from(request.url())?;
 
-    let response = match uri.path() {
+    let (authorized, path) = match strip_token(uri.path(), token) {
+        Some(path) => (true, path),
+        None => {
+            let header = find_header(&request, "X-Ellisia-Token");
+            let authorized = header.is_some_and(|value| constant_time_eq(value, token));
+            (authorized, uri.path())
+        }
+    };
+
+    let protected = path.starts_with("/book/") || path.starts_with("/cover/");
+    if protected && !authorized {
+        let response = make_response(403, "Forbidden");
+        return respond(request, response);
+    }
+
+    let response = match path {
         "/" => handle_root_request(),
-        path if path.starts_with("/book/") => handle_book_request(app, path),
+        path if path.starts_with("/book/") => return handle_book_request(app, request, path),
         path if path.starts_with("/cover/") => handle_thumbnail_request(app, path),
         path if path.starts_with("/static/") => handle_asset_request(app, &request, path),
         path => Ok(make_response(404, format!("Not Found: {path}"))),
     }?;
 
+    respond(request, response)
+}
+
+/// Responds with the CORS headers added.
+fn respond<R: Read>(request: Request, mut response: Response<R>) -> Result<()> {
+    for header in make_cors_headers(&request) {
+        response.add_header(header);
+    }
+
     Ok(request.respond(response)?)
 }
 
@@ -62,7 +119,9 @@ fn handle_root_request() -> Result<BytesResponse> {
     Ok(response)
 }
 
-fn handle_book_request(app: AppHandle, path: &str) -> Result<BytesResponse> {
+/// Book resources are streamed from the zip entry instead of being buffered, so this handler
+/// responds by itself rather than returning a [`BytesResponse`].
+fn handle_book_request(app: AppHandle, request: Request, path: &str) -> Result<()> {
     static PARAMS: LazyLock<Regex> =
         LazyLock::new(|| Regex::new("^/book/([A-Za-z0-9_-]+)/(.+)$").unwrap());
 
@@ -74,32 +133,42 @@ fn handle_book_request(app: AppHandle, path: &str) -> Result<BytesResponse> {
 
     let Some((id, path)) = params else {
         let response = make_response(404, format!("Not Found: {path}"));
-        return Ok(response);
+        return respond(request, response);
     };
 
-    let state = app.state::<AppState>();
-    let epubs = state.epubs().read();
-    let epub = epubs.get(id).context("Book not opened")?;
-
-    let content = match epub.read_file(path) {
-        Ok(content) => content,
-        Err(e) => match e.root_cause().downcast_ref::<ZipError>() {
-            Some(ZipError::EntryNotFound | ZipError::EntryIsNotFile) => {
-                let response = make_response(404, format!("File not found: {path}"));
-                return Ok(response);
-            }
-            _ => {
-                let response = make_response(500, format!("Failed to read file: {path}\n{e}"));
-                return Ok(response);
-            }
+    let resource = get_book_resource(
+        &app,
+        &ResourceRequest {
+            id,
+            path,
+            range: find_header(&request, "Range"),
+            if_range: find_header(&request, "If-Range"),
+            if_none_match: find_header(&request, "If-None-Match"),
         },
-    };
+    );
 
-    let media_type = epub.get_media_type(path).unwrap_or("text/plain");
-    let content_type = Header::from_str(&format!("Content-Type: {media_type}")).unwrap();
-    let response = make_response(200, content).with_header(content_type);
+    let mut headers = make_cors_headers(&request);
+    for (name, value) in &resource.headers {
+        headers.push(Header::from_str(&format!("{name}: {value}")).unwrap());
+    }
 
-    Ok(response)
+    let length = resource.body.len() as usize;
+    let reader = match resource.body.reader() {
+        Ok(reader) => reader,
+        Err(e) => {
+            let response = make_response(500, format!("Failed to read file: {path}\n{e:#}"));
+            return respond(request, response);
+        }
+    };
+    let response = Response::new(
+        StatusCode(resource.status),
+        headers,
+        reader,
+        Some(length),
+        None,
+    );
+
+    Ok(request.respond(response)?)
 }
 
 fn handle_thumbnail_request(app: AppHandle, path: &str) -> Result<BytesResponse> {
@@ -185,9 +254,49 @@ fn handle_asset_request(app: AppHandle, request: &Request, path: &str) -> Result
 }
 
 fn make_response<T: Into<Vec<u8>>>(status: u16, body: T) -> BytesResponse {
-    Response::from_data(body)
-        .with_status_code(status)
-        .with_header(Header::from_str("Access-Control-Allow-Origin: *").unwrap())
+    Response::from_data(body).with_status_code(status)
+}
+
+fn make_cors_headers(request: &Request) -> Vec<Header> {
+    let mut headers = vec![Header::from_str("Vary: Origin").unwrap()];
+
+    if let Some(origin) = find_header(request, "Origin").filter(|x| is_app_origin(x)) {
+        let allow_origin = format!("Access-Control-Allow-Origin: {origin}");
+        headers.push(Header::from_str(&allow_origin).unwrap());
+    }
+
+    headers
+}
+
+/// Returns the rest of the path if its first segment is the token.
+fn strip_token<'a>(path: &'a str, token: &str) -> Option<&'a str> {
+    let path = path.strip_prefix('/')?;
+    let (segment, rest) = match path.find('/') {
+        Some(index) => path.split_at(index),
+        None => (path, "/"),
+    };
+
+    constant_time_eq(segment, token).then_some(rest)
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let diff = a
+        .bytes()
+        .zip(b.bytes())
+        .fold(0, |acc, (x, y)| acc | (x ^ y));
+    diff == 0
+}
+
+fn find_header<'a>(request: &'a Request, field: &'static str) -> Option<&'a str> {
+    request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv(field))
+        .map(|header| header.value.as_str())
 }
 
 fn make_cover_thumbnail(epub: &EpubFile) -> Result<DynamicImage> {
@@ -0,0 +1,78 @@
+use std::error::Error;
+use std::sync::LazyLock;
+
+use regex::Regex;
+use tauri::http::{Request, Response, ResponseBuilder, Uri};
+use tauri::AppHandle;
+
+use super::resource::{get_book_resource, Resource, ResourceRequest};
+
+/// The origin of the `book://` protocol as seen by the webview. WebView2 doesn't support custom
+/// schemes, so tauri maps them to `https://{scheme}.localhost` on Windows.
+#[cfg(windows)]
+pub const ORIGIN: &str = "https://book.localhost";
+#[cfg(not(windows))]
+pub const ORIGIN: &str = "book://localhost";
+
+/// Handles `book://localhost/{id}/{path}`.
+pub fn handle_request(app: &AppHandle, request: &Request) -> Result<Response, Box<dyn Error>> {
+    static PARAMS: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new("^/([A-Za-z0-9_-]+)/(.+)$").unwrap());
+
+    let uri = Uri::try_from(request.uri())?;
+    let path = uri.path();
+
+    let params = PARAMS.captures(path).and_then(|captures| {
+        let id = captures.get(1)?.as_str();
+        let path = captures.get(2)?.as_str();
+        Some((id, path))
+    });
+
+    let Some((id, path)) = params else {
+        return make_response(404, format!("Not Found: {path}"));
+    };
+
+    let header = |name: &str| request.headers().get(name).and_then(|x| x.to_str().ok());
+    let origin = header("Origin").filter(|x| super::is_app_origin(x));
+
+    let request = ResourceRequest {
+        id,
+        path,
+        range: header("Range"),
+        if_range: header("If-Range"),
+        if_none_match: header("If-None-Match"),
+    };
+
+    let Resource {
+        status,
+        headers,
+        body,
+    } = get_book_resource(app, &request);
+
+    let mut builder = ResponseBuilder::new()
+        .status(status)
+        .header("Vary", "Origin");
+    if let Some(origin) = origin {
+        builder = builder.header("Access-Control-Allow-Origin", origin);
+    }
+
+    for (name, value) in headers {
+        builder = match name {
+            "Content-Type" => builder.mimetype(&value),
+            name => builder.header(name, value),
+        };
+    }
+
+    // Custom protocol responses can't be streamed in tauri v1.
+    match body.into_bytes() {
+        Ok(bytes) => builder.body(bytes),
+        Err(e) => make_response(500, format!("Failed to read file: {}\n{e:#}", request.path)),
+    }
+}
+
+fn make_response(status: u16, body: String) -> Result<Response, Box<dyn Error>> {
+    ResponseBuilder::new()
+        .status(status)
+        .mimetype("text/plain")
+        .body(body.into_bytes())
+}
@@ -0,0 +1,370 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::Read;
+use std::sync::Arc;
+
+use anyhow::Result;
+use encoding_rs::UTF_8;
+use tauri::{AppHandle, Manager};
+
+use super::transform::{TransformContext, PIPELINE};
+use crate::epub::media_type::{self, is_text};
+use crate::epub::{encoding, EpubFile};
+use crate::settings::RenderSettings;
+use crate::state::AppState;
+use crate::zip::{SharedZipEntry, ZipError};
+
+/// A request for a file in an opened book, independent of the transport it came from.
+pub struct ResourceRequest<'a> {
+    pub id: &'a str,
+    pub path: &'a str,
+    pub range: Option<&'a str>,
+    pub if_range: Option<&'a str>,
+    pub if_none_match: Option<&'a str>,
+}
+
+pub struct Resource {
+    pub status: u16,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: ResourceBody,
+}
+
+impl Resource {
+    pub fn text(status: u16, body: String) -> Self {
+        Self {
+            status,
+            headers: vec![("Content-Type", "text/plain; charset=utf-8".into())],
+            body: ResourceBody::Bytes(body.into_bytes()),
+        }
+    }
+
+    fn empty(status: u16, headers: Vec<(&'static str, String)>) -> Self {
+        Self {
+            status,
+            headers,
+            body: ResourceBody::Bytes(Vec::new()),
+        }
+    }
+}
+
+pub enum ResourceBody {
+    Bytes(Vec<u8>),
+    /// A (part of a) zip entry. It is only opened when the transport starts sending the body.
+    Entry {
+        epub: Arc<EpubFile>,
+        path: String,
+        skip: u64,
+        length: u64,
+    },
+}
+
+impl ResourceBody {
+    pub fn len(&self) -> u64 {
+        match self {
+            ResourceBody::Bytes(bytes) => bytes.len() as u64,
+            ResourceBody::Entry { length, .. } => *length,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn reader(&self) -> Result<Box<dyn Read + '_>> {
+        match self {
+            ResourceBody::Bytes(bytes) => Ok(Box::new(bytes.as_slice())),
+            ResourceBody::Entry {
+                epub,
+                path,
+                skip,
+                length,
+            } => {
+                let mut reader = epub.entry(path)?.reader();
+                // Entries are usually deflated, so the only way to seek is to decompress and discard.
+                std::io::copy(&mut (&mut reader).take(*skip), &mut std::io::sink())?;
+                Ok(Box::new(reader.take(*length)))
+            }
+        }
+    }
+
+    pub fn into_bytes(self) -> Result<Vec<u8>> {
+        match self {
+            ResourceBody::Bytes(bytes) => Ok(bytes),
+            body => {
+                let mut buf = Vec::with_capacity(body.len() as usize);
+                body.reader()?.read_to_end(&mut buf)?;
+                Ok(buf)
+            }
+        }
+    }
+}
+
+/// Serves a file of an opened book. Books which aren't opened aren't found, and failures to read
+/// the file are server errors.
+pub fn get_book_resource(app: &AppHandle, request: &ResourceRequest) -> Resource {
+    let state = app.state::<AppState>();
+    let Some(epub) = state.epubs().read().get(request.id).cloned() else {
+        return Resource::text(404, format!("Book not opened: {}", request.id));
+    };
+
+    let settings = state.render_settings(request.id);
+    match read_book_resource(epub, &settings, request) {
+        Ok(resource) => resource,
+        Err(e) => {
+            let path = request.path;
+            Resource::text(500, format!("Failed to read file: {path}\n{e:#}"))
+        }
+    }
+}
+
+fn read_book_resource(
+    epub: Arc<EpubFile>,
+    settings: &RenderSettings,
+    request: &ResourceRequest,
+) -> Result<Resource> {
+    let path = request.path;
+
+    let entry = match epub.entry(path) {
+        Ok(entry) => entry,
+        Err(ZipError::EntryNotFound | ZipError::EntryIsNotFile) => {
+            return match get_document_part(&epub, settings, request)? {
+                Some(resource) => Ok(resource),
+                None => Ok(Resource::text(404, format!("File not found: {path}"))),
+            };
+        }
+    };
+
+    let size = entry.size();
+    let etag = make_entry_etag(&entry);
+    let media_type = match epub.get_media_type(path) {
+        Some(media_type) => media_type,
+        None => sniff_media_type(&entry, path)?,
+    };
+
+    let ctx = TransformContext {
+        epub: &epub,
+        path,
+        media_type,
+        settings,
+    };
+
+    // Transcoded and transformed resources are always served as a whole.
+    let text = is_text(media_type);
+    let mut bytes = None;
+    if text {
+        let content = entry.bytes()?;
+        if encoding::detect(&content, media_type) != UTF_8 || PIPELINE.applies_to(&ctx) {
+            let content = encoding::decode(&content, media_type).into_owned();
+            return Ok(transform_resource(&ctx, request, &etag, content));
+        }
+        // Served from the bytes already read, instead of decompressing the entry again.
+        bytes = Some(content);
+    }
+
+    let mut headers = vec![
+        ("Accept-Ranges", "bytes".into()),
+        ("ETag", etag.clone()),
+        ("Content-Type", make_content_type(media_type, text)),
+    ];
+
+    if let Some(value) = request.if_none_match {
+        if etag_matches(value, &etag) {
+            return Ok(Resource::empty(304, headers));
+        }
+    }
+
+    // Ranges are ignored if the client holds an outdated copy of the resource.
+    let range = match request.range {
+        Some(_)
+            if request
+                .if_range
+                .is_some_and(|value| !etag_matches(value, &etag)) =>
+        {
+            None
+        }
+        Some(value) => match parse_range(value, size) {
+            Ok(range) => range,
+            Err(()) => {
+                headers.push(("Content-Range", format!("bytes */{size}")));
+                return Ok(Resource::empty(416, headers));
+            }
+        },
+        None => None,
+    };
+
+    let (status, skip, length) = match range {
+        Some((start, end)) => {
+            headers.push(("Content-Range", format!("bytes {start}-{end}/{size}")));
+            (206, start, end - start + 1)
+        }
+        None => (200, 0, size),
+    };
+
+    let body = match bytes {
+        Some(mut bytes) => {
+            bytes.truncate((skip + length) as usize);
+            bytes.drain(..skip as usize);
+            ResourceBody::Bytes(bytes)
+        }
+        None => ResourceBody::Entry {
+            epub,
+            path: path.to_string(),
+            skip,
+            length,
+        },
+    };
+
+    Ok(Resource {
+        status,
+        headers,
+        body,
+    })
+}
+
+fn transform_resource(
+    ctx: &TransformContext,
+    request: &ResourceRequest,
+    entry_etag: &str,
+    content: String,
+) -> Resource {
+    let etag = make_transformed_etag(entry_etag, ctx.settings);
+    let mut headers = vec![
+        ("ETag", etag.clone()),
+        ("Content-Type", make_content_type(ctx.media_type, true)),
+    ];
+
+    if let Some(value) = request.if_none_match {
+        if etag_matches(value, &etag) {
+            return Resource::empty(304, headers);
+        }
+    }
+
+    // The original content isn't served if it can't be transformed, as it wouldn't be sanitized.
+    let content = match PIPELINE.run(ctx, content) {
+        Ok(transformed) => transformed,
+        Err(e) => {
+            eprintln!("Failed to transform {}:\n{:?}", ctx.path, e);
+            let message = format!("Failed to transform file: {}\n{e:#}", ctx.path);
+            return Resource::text(500, message);
+        }
+    };
+    headers.extend(PIPELINE.headers(ctx));
+
+    Resource {
+        status: 200,
+        headers,
+        body: ResourceBody::Bytes(content.into_bytes()),
+    }
+}
+
+/// Serves a part of a split document from its synthetic path.
+fn get_document_part(
+    epub: &EpubFile,
+    settings: &RenderSettings,
+    request: &ResourceRequest,
+) -> Result<Option<Resource>> {
+    let threshold = settings.split_threshold.unwrap_or_default();
+    if threshold == 0 {
+        return Ok(None);
+    }
+
+    let spine = epub.virtual_spine(threshold)?;
+    let Some((split, part)) = spine.find_part(request.path).filter(|(_, part)| *part > 0) else {
+        return Ok(None);
+    };
+
+    let entry = epub.entry(&split.paths[0])?;
+    let etag = make_entry_etag(&entry);
+    let etag = format!("\"{}-part{part}\"", etag.trim_matches('"'));
+
+    let ctx = TransformContext {
+        epub,
+        path: request.path,
+        media_type: "application/xhtml+xml",
+        settings,
+    };
+    let content = split.content(part).to_string();
+    Ok(Some(transform_resource(&ctx, request, &etag, content)))
+}
+
+fn sniff_media_type(entry: &SharedZipEntry, path: &str) -> Result<&'static str> {
+    let mut head = Vec::with_capacity(512);
+    entry.reader().take(512).read_to_end(&mut head)?;
+    Ok(media_type::sniff(path, &head))
+}
+
+/// Text resources are transcoded to UTF-8 if necessary, so that the declared encoding is right.
+fn make_content_type(media_type: &str, text: bool) -> String {
+    match text {
+        true => format!("{media_type}; charset=utf-8"),
+        false => media_type.into(),
+    }
+}
+
+fn make_entry_etag(entry: &SharedZipEntry) -> String {
+    // Fall back to the modification time if the CRC is missing from the central directory.
+    match entry.crc32() {
+        0 => format!("\"{:x}-{:x}\"", entry.modified(), entry.size()),
+        crc32 => format!("\"{:08x}-{:x}\"", crc32, entry.size()),
+    }
+}
+
+/// Derives the ETag of a transformed resource, which changes along with the render settings.
+fn make_transformed_etag(etag: &str, settings: &RenderSettings) -> String {
+    let mut hasher = DefaultHasher::new();
+    serde_json::to_string(settings)
+        .unwrap_or_default()
+        .hash(&mut hasher);
+    let etag = etag.trim_matches('"');
+    format!("\"{etag}-{:x}\"", hasher.finish())
+}
+
+fn etag_matches(header: &str, etag: &str) -> bool {
+    header.split(',').map(str::trim).any(|candidate| {
+        candidate == "*" || candidate.strip_prefix("W/").unwrap_or(candidate) == etag
+    })
+}
+
+/// Parses a single-range `Range` header into inclusive offsets. Returns `Ok(None)` for
+/// headers that should be ignored (e.g. multiple ranges), and `Err` for unsatisfiable ranges.
+fn parse_range(header: &str, size: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+
+    if spec.contains(',') {
+        return Ok(None);
+    }
+
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return Ok(None);
+    };
+
+    let range = match (start.trim(), end.trim()) {
+        ("", "") => return Ok(None),
+        ("", suffix) => {
+            let suffix: u64 = suffix.parse().map_err(|_| ())?;
+            if suffix == 0 {
+                return Err(());
+            }
+            (size.saturating_sub(suffix), size.wrapping_sub(1))
+        }
+        (start, "") => {
+            let start: u64 = start.parse().map_err(|_| ())?;
+            (start, size.wrapping_sub(1))
+        }
+        (start, end) => {
+            let start: u64 = start.parse().map_err(|_| ())?;
+            let end: u64 = end.parse().map_err(|_| ())?;
+            if end < start {
+                return Ok(None);
+            }
+            (start, end.min(size.wrapping_sub(1)))
+        }
+    };
+
+    match range {
+        (start, _) if start >= size => Err(()),
+        range => Ok(Some(range)),
+    }
+}
@@ -0,0 +1,123 @@
+use std::sync::LazyLock;
+
+use anyhow::Result;
+use quick_xml::events::Event;
+use quick_xml::{Reader, Writer};
+
+use crate::epub::media_type::is_document;
+use crate::epub::EpubFile;
+use crate::settings::RenderSettings;
+
+use self::chinese::ConvertChinese;
+use self::highlight::HighlightCode;
+use self::hyphenate::Hyphenate;
+use self::publisher_style::StripPublisherStyle;
+use self::repair::RepairDocument;
+use self::sanitize::Sanitize;
+use self::split::SplitDocuments;
+use self::user_style::InjectUserStyle;
+
+pub mod chinese;
+pub mod highlight;
+pub mod hyphenate;
+pub mod publisher_style;
+pub mod repair;
+pub mod sanitize;
+pub mod split;
+pub mod user_style;
+
+/// The transforms applied to book resources, in order.
+pub static PIPELINE: LazyLock<Pipeline> = LazyLock::new(|| {
+    Pipeline::new()
+        .with(RepairDocument)
+        .with(SplitDocuments)
+        .with(Sanitize)
+        .with(ConvertChinese)
+        .with(Hyphenate)
+        .with(HighlightCode)
+        .with(StripPublisherStyle)
+        .with(InjectUserStyle)
+});
+
+pub struct TransformContext<'a> {
+    pub epub: &'a EpubFile,
+    pub path: &'a str,
+    pub media_type: &'a str,
+    pub settings: &'a RenderSettings,
+}
+
+impl TransformContext<'_> {
+    pub fn is_document(&self) -> bool {
+        is_document(self.media_type)
+    }
+
+    pub fn is_stylesheet(&self) -> bool {
+        self.media_type == "text/css"
+    }
+}
+
+pub trait Transform: Send + Sync {
+    /// Whether the transform should be applied to the resource.
+    fn applies_to(&self, ctx: &TransformContext) -> bool;
+
+    fn transform(&self, ctx: &TransformContext, content: String) -> Result<String>;
+
+    /// Extra headers to send along with the transformed resource.
+    fn headers(&self, _ctx: &TransformContext) -> Vec<(&'static str, String)> {
+        Vec::new()
+    }
+}
+
+#[derive(Default)]
+pub struct Pipeline {
+    transforms: Vec<Box<dyn Transform>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with<T: Transform + 'static>(mut self, transform: T) -> Self {
+        self.transforms.push(Box::new(transform));
+        self
+    }
+
+    pub fn applies_to(&self, ctx: &TransformContext) -> bool {
+        self.transforms.iter().any(|x| x.applies_to(ctx))
+    }
+
+    pub fn headers(&self, ctx: &TransformContext) -> Vec<(&'static str, String)> {
+        let transforms = self.transforms.iter().filter(|x| x.applies_to(ctx));
+        transforms.flat_map(|x| x.headers(ctx)).collect()
+    }
+
+    pub fn run(&self, ctx: &TransformContext, mut content: String) -> Result<String> {
+        for transform in &self.transforms {
+            if transform.applies_to(ctx) {
+                content = transform.transform(ctx, content)?;
+            }
+        }
+        Ok(content)
+    }
+}
+
+/// Feeds every event of the document to `f`, which writes the events to keep (possibly
+/// modified) to the writer.
+pub fn rewrite_document<F>(content: &str, mut f: F) -> Result<String>
+where
+    F: FnMut(Event, &mut Writer<Vec<u8>>) -> Result<()>,
+{
+    let mut reader = Reader::from_str(content);
+    reader.check_end_names(false);
+
+    let mut writer = Writer::new(Vec::with_capacity(content.len()));
+    loop {
+        match reader.read_event()? {
+            Event::Eof => break,
+            event => f(event, &mut writer)?,
+        }
+    }
+
+    Ok(String::from_utf8(writer.into_inner())?)
+}
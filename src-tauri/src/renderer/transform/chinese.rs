@@ -0,0 +1,59 @@
+use anyhow::Result;
+use quick_xml::events::{BytesText, Event};
+use zhconv::{get_builtin_converter, Variant, ZhConverter};
+
+use super::{rewrite_document, Transform, TransformContext};
+use crate::settings::{ChineseConversion, RenderSettings};
+
+/// Converts text nodes between Simplified and Traditional Chinese, leaving the markup intact.
+pub struct ConvertChinese;
+
+impl Transform for ConvertChinese {
+    fn applies_to(&self, ctx: &TransformContext) -> bool {
+        let is_toc = ctx.media_type == "application/x-dtbncx+xml";
+        (ctx.is_document() || is_toc) && get_converter(ctx.settings).is_some()
+    }
+
+    fn transform(&self, ctx: &TransformContext, content: String) -> Result<String> {
+        let Some(converter) = get_converter(ctx.settings) else {
+            return Ok(content);
+        };
+
+        let mut in_code = false;
+        rewrite_document(&content, |event, writer| {
+            let event = match event {
+                Event::Start(start) => {
+                    let name = start.local_name();
+                    in_code = matches!(name.as_ref(), b"style" | b"script");
+                    Event::Start(start)
+                }
+                Event::End(end) => {
+                    in_code = false;
+                    Event::End(end)
+                }
+                // Entities are ASCII and never converted, so the text doesn't need unescaping.
+                Event::Text(text) if !in_code => {
+                    let text = converter.convert(&String::from_utf8_lossy(&text));
+                    Event::Text(BytesText::from_escaped(text))
+                }
+                event => event,
+            };
+
+            Ok(writer.write_event(event)?)
+        })
+    }
+}
+
+/// Returns the converter for the settings, or `None` if the text shouldn't be converted.
+pub fn get_converter(settings: &RenderSettings) -> Option<&'static ZhConverter> {
+    let variant = match settings.chinese_conversion? {
+        ChineseConversion::Off => return None,
+        ChineseConversion::Simplified => Variant::ZhHans,
+        ChineseConversion::Traditional => Variant::ZhHant,
+        ChineseConversion::Mainland => Variant::ZhCN,
+        ChineseConversion::Taiwan => Variant::ZhTW,
+        ChineseConversion::HongKong => Variant::ZhHK,
+    };
+
+    Some(get_builtin_converter(variant))
+}
@@ -0,0 +1,241 @@
+use std::sync::LazyLock;
+
+use anyhow::Result;
+use quick_xml::events::{BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::Writer;
+use syntect::highlighting::ThemeSet;
+use syntect::html::{css_for_theme_with_class_style, ClassStyle, ClassedHTMLGenerator};
+use syntect::parsing::{SyntaxReference, SyntaxSet};
+use syntect::util::LinesWithEndings;
+
+use super::{rewrite_document, Transform, TransformContext};
+
+static SYNTAXES: LazyLock<SyntaxSet> = LazyLock::new(SyntaxSet::load_defaults_newlines);
+static THEMES: LazyLock<ThemeSet> = LazyLock::new(ThemeSet::load_defaults);
+
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: "hl-" };
+const DEFAULT_THEME: &str = "InspiredGitHub";
+
+/// Lays the highlighted copy of a block over its original content, which is made transparent.
+const OVERLAY_CSS: &str = "
+pre.ellisia-code { position: relative; }
+pre.ellisia-code, pre.ellisia-code > :not(.ellisia-code-overlay) { color: transparent; }
+.ellisia-code-overlay {
+    display: block; position: absolute; inset: 0; margin: 0; padding: inherit;
+    font: inherit; white-space: inherit; background: none;
+    pointer-events: none; user-select: none; -webkit-user-select: none;
+}
+";
+
+/// Prefixes of class names declaring the language of a code block.
+const CLASS_PREFIXES: &[&str] = &["language-", "lang-", "highlight-", "brush:"];
+
+/// Highlights `<pre>` blocks declaring their language with a class name, e.g. `language-rust`,
+/// with syntect. Only in XHTML documents, since the blocks are buffered until their end tags,
+/// which may be missing in HTML.
+pub struct HighlightCode;
+
+impl Transform for HighlightCode {
+    fn applies_to(&self, ctx: &TransformContext) -> bool {
+        ctx.media_type == "application/xhtml+xml" && ctx.settings.highlight_code == Some(true)
+    }
+
+    fn transform(&self, ctx: &TransformContext, content: String) -> Result<String> {
+        if !content.contains("<pre") {
+            return Ok(content);
+        }
+
+        let theme = ctx.settings.highlight_theme.as_deref();
+        let theme = theme
+            .and_then(|x| THEMES.themes.get(x))
+            .unwrap_or(&THEMES.themes[DEFAULT_THEME]);
+        let css = css_for_theme_with_class_style(theme, CLASS_STYLE)? + OVERLAY_CSS;
+
+        let mut block: Option<CodeBlock> = None;
+
+        rewrite_document(&content, |event, writer| {
+            if let Some(current) = &mut block {
+                match &event {
+                    Event::Start(start) if is_pre(start.local_name().as_ref()) => {
+                        current.depth += 1;
+                    }
+                    Event::End(end) if is_pre(end.local_name().as_ref()) => match current.depth {
+                        0 => return block.take().unwrap().write(writer),
+                        _ => current.depth -= 1,
+                    },
+                    _ => {}
+                }
+                current.events.push(event.into_owned());
+                return Ok(());
+            }
+
+            match event {
+                Event::Start(start) if is_pre(start.local_name().as_ref()) => {
+                    block = Some(CodeBlock {
+                        start: start.into_owned(),
+                        events: Vec::new(),
+                        depth: 0,
+                    });
+                    Ok(())
+                }
+                Event::End(end) if end.local_name().as_ref().eq_ignore_ascii_case(b"head") => {
+                    let mut style = BytesStart::new("style");
+                    style.push_attribute(("id", "ellisia-highlight-style"));
+                    writer.write_event(Event::Start(style))?;
+                    writer.write_event(Event::Text(BytesText::new(&css)))?;
+                    writer.write_event(Event::End(BytesEnd::new("style")))?;
+                    Ok(writer.write_event(Event::End(end))?)
+                }
+                event => Ok(writer.write_event(event)?),
+            }
+        })
+    }
+}
+
+/// A `<pre>` element and the events of its content.
+struct CodeBlock {
+    start: BytesStart<'static>,
+    events: Vec<Event<'static>>,
+    depth: usize,
+}
+
+impl CodeBlock {
+    fn write(self, writer: &mut Writer<Vec<u8>>) -> Result<()> {
+        let name = String::from_utf8_lossy(self.start.name().as_ref()).into_owned();
+
+        let code = self.get_code();
+        let syntax = code
+            .as_ref()
+            .and_then(|(_, inner)| self.find_syntax(inner.as_ref()));
+
+        let (Some((code, inner)), Some(syntax)) = (code, syntax) else {
+            writer.write_event(Event::Start(self.start))?;
+            for event in self.events {
+                writer.write_event(event)?;
+            }
+            writer.write_event(Event::End(BytesEnd::new(name)))?;
+            return Ok(());
+        };
+
+        let mut generator =
+            ClassedHTMLGenerator::new_with_class_style(syntax, &SYNTAXES, CLASS_STYLE);
+        for line in LinesWithEndings::from(&code) {
+            generator.parse_html_for_line_which_includes_newline(line)?;
+        }
+        let html = generator.finalize();
+
+        // The original content is kept, made transparent, and the highlighted copy is laid over
+        // it as the last child, so that the text nodes keep their CFIs and remain selectable.
+        writer.write_event(Event::Start(add_class(&self.start, "hl-code ellisia-code")))?;
+        for event in self.events {
+            writer.write_event(event)?;
+        }
+
+        let mut overlay = BytesStart::new("span");
+        overlay.push_attribute(("class", "hl-code ellisia-code-overlay"));
+        overlay.push_attribute(("aria-hidden", "true"));
+        writer.write_event(Event::Start(overlay))?;
+        if let Some(Event::Start(start)) = &inner {
+            writer.write_event(Event::Start(remove_id(start)))?;
+        }
+        writer.write_event(Event::Text(BytesText::from_escaped(html)))?;
+        if let Some(Event::Start(start)) = &inner {
+            let name = String::from_utf8_lossy(start.name().as_ref()).into_owned();
+            writer.write_event(Event::End(BytesEnd::new(name)))?;
+        }
+        writer.write_event(Event::End(BytesEnd::new("span")))?;
+        writer.write_event(Event::End(BytesEnd::new(name)))?;
+
+        Ok(())
+    }
+
+    /// Returns the text of the block, and the `<code>` wrapping it if any. Blocks containing
+    /// other markup are left alone.
+    fn get_code(&self) -> Option<(String, Option<Event<'static>>)> {
+        let (inner, events) = match self.events.as_slice() {
+            [start @ Event::Start(code), events @ .., Event::End(_)]
+                if code.local_name().as_ref().eq_ignore_ascii_case(b"code") =>
+            {
+                (Some(start.clone()), events)
+            }
+            events => (None, events),
+        };
+
+        let mut code = String::new();
+        for event in events {
+            match event {
+                Event::Text(text) => code.push_str(&text.unescape().ok()?),
+                Event::CData(cdata) => code.push_str(&String::from_utf8_lossy(cdata)),
+                _ => return None,
+            }
+        }
+
+        Some((code, inner))
+    }
+
+    fn find_syntax(&self, inner: Option<&Event>) -> Option<&'static SyntaxReference> {
+        let mut classes = get_classes(&self.start);
+        if let Some(Event::Start(start)) = inner {
+            classes.extend(get_classes(start));
+        }
+
+        for class in &classes {
+            let token = CLASS_PREFIXES
+                .iter()
+                .find_map(|prefix| class.strip_prefix(prefix));
+            if let Some(syntax) = token.and_then(|x| SYNTAXES.find_syntax_by_token(x.trim())) {
+                return Some(syntax);
+            }
+        }
+
+        None
+    }
+}
+
+fn is_pre(name: &[u8]) -> bool {
+    name.eq_ignore_ascii_case(b"pre")
+}
+
+fn get_classes(start: &BytesStart) -> Vec<String> {
+    let class = start
+        .attributes()
+        .flatten()
+        .find(|attr| attr.key.as_ref() == b"class")
+        .and_then(|attr| attr.unescape_value().ok());
+
+    // `brush: js` of SyntaxHighlighter contains a space after the prefix.
+    let class = class.unwrap_or_default().replace(": ", ":");
+    class.split_whitespace().map(str::to_owned).collect()
+}
+
+fn remove_id<'a>(start: &'a BytesStart) -> BytesStart<'a> {
+    let mut modified = start.borrow();
+    modified.clear_attributes();
+    for attr in start.attributes().flatten() {
+        if attr.key.as_ref() != b"id" {
+            modified.push_attribute(attr);
+        }
+    }
+    modified
+}
+
+fn add_class<'a>(start: &'a BytesStart, class: &str) -> BytesStart<'a> {
+    let mut modified = start.borrow();
+    modified.clear_attributes();
+
+    let mut classes = None;
+    for attr in start.attributes().flatten() {
+        match attr.key.as_ref() {
+            b"class" => classes = attr.unescape_value().ok(),
+            _ => modified.push_attribute(attr),
+        }
+    }
+
+    let classes = match classes {
+        Some(classes) => format!("{classes} {class}"),
+        None => class.to_string(),
+    };
+    modified.push_attribute(("class", classes.as_str()));
+
+    modified
+}
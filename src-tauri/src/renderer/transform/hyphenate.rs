@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock};
+
+use anyhow::Result;
+use hyphenation::{Hyphenator, Language, Load, Standard};
+use parking_lot::Mutex;
+use quick_xml::events::{BytesStart, BytesText, Event};
+
+use super::{rewrite_document, Transform, TransformContext};
+
+/// Elements whose text shouldn't be hyphenated.
+const SKIPPED_ELEMENTS: &[&[u8]] = &[
+    b"script", b"style", b"title", b"pre", b"code", b"kbd", b"samp", b"svg", b"math",
+];
+
+/// Inserts soft hyphens into the words of text nodes, using the TeX patterns of the language
+/// declared by the nearest `xml:lang` or `lang`, or else the language of the book.
+pub struct Hyphenate;
+
+impl Transform for Hyphenate {
+    fn applies_to(&self, ctx: &TransformContext) -> bool {
+        ctx.is_document() && ctx.settings.hyphenate == Some(true)
+    }
+
+    fn transform(&self, ctx: &TransformContext, content: String) -> Result<String> {
+        let forced = ctx.settings.hyphenation_language.as_deref();
+        let metadata = &ctx.epub.rootfile().package.metadata;
+        let book_language = metadata.language.first().map(String::as_str);
+        let default = forced.or(book_language).and_then(find_language);
+
+        // The language and whether hyphenation is skipped, in each open element.
+        let mut scopes: Vec<(Option<Language>, bool)> = Vec::new();
+
+        rewrite_document(&content, |event, writer| {
+            let (language, skipped) = scopes.last().copied().unwrap_or((default, false));
+
+            match &event {
+                Event::Start(start) => {
+                    let language = match forced {
+                        Some(_) => language,
+                        None => get_language(start).map_or(language, |x| find_language(&x)),
+                    };
+                    let name = start.local_name().as_ref().to_ascii_lowercase();
+                    let skipped = skipped || SKIPPED_ELEMENTS.contains(&&*name);
+                    scopes.push((language, skipped));
+                }
+                Event::End(_) => {
+                    scopes.pop();
+                }
+                Event::Text(text) if !skipped => {
+                    if let Some(dictionary) = language.and_then(get_dictionary) {
+                        let text = hyphenate_text(&String::from_utf8_lossy(text), &dictionary);
+                        return Ok(writer.write_event(Event::Text(BytesText::from_escaped(text)))?);
+                    }
+                }
+                _ => {}
+            }
+
+            Ok(writer.write_event(event)?)
+        })
+    }
+}
+
+fn get_language(start: &BytesStart) -> Option<String> {
+    let mut language = None;
+    for attr in start.attributes().flatten() {
+        match attr.key.as_ref() {
+            // `xml:lang` takes precedence over `lang`.
+            b"xml:lang" => return Some(attr.unescape_value().ok()?.into_owned()),
+            b"lang" => language = attr.unescape_value().ok().map(|x| x.into_owned()),
+            _ => {}
+        }
+    }
+    language
+}
+
+/// Finds the patterns for a language tag, falling back to the primary language subtag.
+fn find_language(tag: &str) -> Option<Language> {
+    let tag = tag.trim().to_ascii_lowercase().replace('_', "-");
+    if let Some(language) = Language::try_from_code(&tag) {
+        return Some(language);
+    }
+
+    match tag.split('-').next()? {
+        "en" => Some(Language::EnglishUS),
+        "de" => Some(Language::German1996),
+        "el" => Some(Language::GreekMono),
+        "mn" => Some(Language::Mongolian),
+        "sr" => Some(Language::SerbianCyrillic),
+        "no" => Some(Language::NorwegianBokmal),
+        primary => Language::try_from_code(primary),
+    }
+}
+
+fn get_dictionary(language: Language) -> Option<Arc<Standard>> {
+    static DICTIONARIES: LazyLock<Mutex<HashMap<Language, Option<Arc<Standard>>>>> =
+        LazyLock::new(Default::default);
+
+    let mut dictionaries = DICTIONARIES.lock();
+    let dictionary = dictionaries
+        .entry(language)
+        .or_insert_with(|| Standard::from_embedded(language).ok().map(Arc::new));
+    dictionary.clone()
+}
+
+/// Hyphenates the words of escaped text. Entities are left intact.
+fn hyphenate_text(text: &str, dictionary: &Standard) -> String {
+    let mut output = String::with_capacity(text.len() + text.len() / 8);
+    let mut rest = text;
+
+    while let Some(start) = rest.find(char::is_alphanumeric) {
+        output.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let end = rest
+            .find(|c: char| !c.is_alphanumeric())
+            .unwrap_or(rest.len());
+        let word = &rest[..end];
+        rest = &rest[end..];
+
+        if output.ends_with(['&', '#']) || word.contains(|c: char| c.is_numeric()) {
+            output.push_str(word);
+            continue;
+        }
+
+        let mut last = 0;
+        for index in dictionary.hyphenate(word).breaks {
+            output.push_str(&word[last..index]);
+            output.push('\u{ad}');
+            last = index;
+        }
+        output.push_str(&word[last..]);
+    }
+
+    output.push_str(rest);
+    output
+}
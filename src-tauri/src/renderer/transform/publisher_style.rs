@@ -0,0 +1,231 @@
+use anyhow::Result;
+use quick_xml::events::{BytesCData, BytesStart, BytesText, Event};
+
+use super::{rewrite_document, Transform, TransformContext};
+
+/// Removes font sizes and colors forced by the publisher, in stylesheets, `<style>` elements
+/// and `style` attributes.
+pub struct StripPublisherStyle;
+
+impl Transform for StripPublisherStyle {
+    fn applies_to(&self, ctx: &TransformContext) -> bool {
+        (ctx.is_document() || ctx.is_stylesheet()) && !stripped_properties(ctx).is_empty()
+    }
+
+    fn transform(&self, ctx: &TransformContext, content: String) -> Result<String> {
+        let properties = stripped_properties(ctx);
+
+        if ctx.is_stylesheet() {
+            return Ok(strip_stylesheet(&content, &properties));
+        }
+
+        let mut in_style = false;
+        rewrite_document(&content, |event, writer| {
+            let event = match event {
+                Event::Start(start) => {
+                    in_style = start.local_name().as_ref().eq_ignore_ascii_case(b"style");
+                    Event::Start(strip_style_attribute(start, &properties))
+                }
+                Event::Empty(start) => Event::Empty(strip_style_attribute(start, &properties)),
+                Event::End(end) => {
+                    in_style = false;
+                    Event::End(end)
+                }
+                Event::Text(text) if in_style => match ctx.media_type {
+                    "text/html" => {
+                        let css = String::from_utf8_lossy(&text);
+                        let css = strip_stylesheet(&css, &properties);
+                        Event::Text(BytesText::from_escaped(css))
+                    }
+                    _ => {
+                        let css = strip_stylesheet(&text.unescape()?, &properties);
+                        Event::Text(BytesText::new(&css).into_owned())
+                    }
+                },
+                Event::CData(cdata) if in_style => {
+                    let css = String::from_utf8_lossy(&cdata);
+                    let css = strip_stylesheet(&css, &properties);
+                    Event::CData(BytesCData::new(css))
+                }
+                event => event,
+            };
+
+            Ok(writer.write_event(event)?)
+        })
+    }
+}
+
+fn stripped_properties(ctx: &TransformContext) -> Vec<&'static str> {
+    let mut properties = Vec::new();
+    if ctx.settings.strip_font_sizes == Some(true) {
+        properties.push("font-size");
+    }
+    if ctx.settings.strip_colors == Some(true) {
+        properties.extend(["color", "background-color"]);
+    }
+    properties
+}
+
+fn strip_style_attribute<'a>(start: BytesStart<'a>, properties: &[&str]) -> BytesStart<'a> {
+    let has_style = start
+        .attributes()
+        .flatten()
+        .any(|attr| attr.key.as_ref().eq_ignore_ascii_case(b"style"));
+
+    if !has_style {
+        return start;
+    }
+
+    let mut stripped = start.to_owned();
+    stripped.clear_attributes();
+
+    for attr in start.attributes().flatten() {
+        if !attr.key.as_ref().eq_ignore_ascii_case(b"style") {
+            stripped.push_attribute(attr);
+            continue;
+        }
+
+        match attr.unescape_value() {
+            Ok(value) => {
+                let value = strip_declarations(&value, properties);
+                stripped.push_attribute((attr.key.as_ref(), value.as_bytes()));
+            }
+            Err(_) => stripped.push_attribute(attr),
+        }
+    }
+
+    stripped
+}
+
+/// Strips the declarations in all blocks of a stylesheet, including the nested ones.
+fn strip_stylesheet(css: &str, properties: &[&str]) -> String {
+    let mut output = String::with_capacity(css.len());
+    let mut rest = css;
+
+    while let Some(open) = find_unquoted(rest, b'{') {
+        let block = &rest[open + 1..];
+        let close = find_block_end(block).unwrap_or(block.len());
+        let inner = &block[..close];
+
+        output.push_str(&rest[..=open]);
+        match find_unquoted(inner, b'{') {
+            Some(_) => output.push_str(&strip_stylesheet(inner, properties)),
+            None => output.push_str(&strip_declarations(inner, properties)),
+        }
+
+        rest = &block[close..];
+    }
+
+    output.push_str(rest);
+    output
+}
+
+/// Strips the declarations in a declaration list, e.g. the content of a `style` attribute.
+fn strip_declarations(declarations: &str, properties: &[&str]) -> String {
+    split_unquoted(declarations, b';')
+        .into_iter()
+        .filter(|declaration| {
+            let name = declaration.split(':').next().unwrap_or_default().trim();
+            !properties.iter().any(|x| name.eq_ignore_ascii_case(x))
+        })
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// Finds the first `needle` outside of strings, comments and parentheses.
+fn find_unquoted(css: &str, needle: u8) -> Option<usize> {
+    let mut scanner = Scanner::default();
+    let bytes = css.as_bytes();
+    (0..bytes.len()).find(|&i| scanner.feed(bytes, i) && bytes[i] == needle)
+}
+
+/// Finds the `}` closing the block whose content starts at the beginning of `css`.
+fn find_block_end(css: &str) -> Option<usize> {
+    let mut scanner = Scanner::default();
+    let mut depth = 0usize;
+    let bytes = css.as_bytes();
+
+    for i in 0..bytes.len() {
+        if !scanner.feed(bytes, i) {
+            continue;
+        }
+        match bytes[i] {
+            b'{' => depth += 1,
+            b'}' if depth == 0 => return Some(i),
+            b'}' => depth -= 1,
+            _ => {}
+        }
+    }
+
+    None
+}
+
+fn split_unquoted(css: &str, separator: u8) -> Vec<&str> {
+    let mut scanner = Scanner::default();
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let bytes = css.as_bytes();
+
+    for i in 0..bytes.len() {
+        if scanner.feed(bytes, i) && bytes[i] == separator {
+            parts.push(&css[start..i]);
+            start = i + 1;
+        }
+    }
+
+    parts.push(&css[start..]);
+    parts
+}
+
+/// Tracks whether a byte of CSS is inside a string, a comment or parentheses.
+#[derive(Default)]
+struct Scanner {
+    quote: Option<u8>,
+    comment: bool,
+    parens: usize,
+    escaped: bool,
+}
+
+impl Scanner {
+    /// Returns true if `bytes[i]` is a structural character, i.e. not inside of anything.
+    fn feed(&mut self, bytes: &[u8], i: usize) -> bool {
+        let byte = bytes[i];
+
+        if self.comment {
+            if byte == b'/' && i > 0 && bytes[i - 1] == b'*' {
+                self.comment = false;
+            }
+            return false;
+        }
+
+        if let Some(quote) = self.quote {
+            match byte {
+                _ if self.escaped => self.escaped = false,
+                b'\\' => self.escaped = true,
+                _ if byte == quote => self.quote = None,
+                _ => {}
+            }
+            return false;
+        }
+
+        match byte {
+            b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                self.comment = true;
+                false
+            }
+            b'"' | b'\'' => {
+                self.quote = Some(byte);
+                false
+            }
+            b'(' => {
+                self.parens += 1;
+                false
+            }
+            b')' => {
+                self.parens = self.parens.saturating_sub(1);
+                false
+            }
+            _ => self.parens == 0,
+        }
+    }
+}
@@ -0,0 +1,18 @@
+use anyhow::Result;
+
+use super::{Transform, TransformContext};
+
+/// Replaces documents that aren't well-formed with their repaired versions, since the webview
+/// refuses to render such XHTML at all, and the later transforms read documents as XML. HTML
+/// documents are repaired too, as they rarely are well-formed.
+pub struct RepairDocument;
+
+impl Transform for RepairDocument {
+    fn applies_to(&self, ctx: &TransformContext) -> bool {
+        ctx.is_document()
+    }
+
+    fn transform(&self, ctx: &TransformContext, content: String) -> Result<String> {
+        Ok(ctx.epub.repair_document(ctx.path, content))
+    }
+}
@@ -0,0 +1,200 @@
+use anyhow::Result;
+use quick_xml::events::attributes::Attribute;
+use quick_xml::events::{BytesStart, Event};
+
+use super::{rewrite_document, Transform, TransformContext};
+
+/// Elements emptied, unless the document is scripted.
+const SCRIPT_ELEMENTS: &[&[u8]] = &[b"script", b"noscript"];
+
+/// Attributes containing URLs to be loaded along with the document.
+const LOAD_ATTRIBUTES: &[&[u8]] = &[b"src", b"srcset", b"poster", b"data", b"background"];
+
+/// Attributes containing URLs to be navigated to.
+const LINK_ATTRIBUTES: &[&[u8]] = &[b"href", b"action", b"formaction", b"cite"];
+
+const DEFAULT_POLICY: &str = "default-src 'none'; \
+    img-src 'self' data: blob:; \
+    media-src 'self' data: blob:; \
+    font-src 'self' data:; \
+    style-src 'self' 'unsafe-inline'; \
+    frame-src 'self'; \
+    script-src 'none'";
+
+const SCRIPTED_POLICY: &str = "default-src 'none'; \
+    img-src 'self' data: blob:; \
+    media-src 'self' data: blob:; \
+    font-src 'self' data:; \
+    style-src 'self' 'unsafe-inline'; \
+    frame-src 'self'; \
+    script-src 'self' 'unsafe-inline'";
+
+/// Strips scripts (unless the manifest item is `scripted`) and remote resources from documents
+/// from untrusted sources. Elements are emptied rather than removed, so that the other nodes keep
+/// their CFIs.
+pub struct Sanitize;
+
+impl Transform for Sanitize {
+    fn applies_to(&self, ctx: &TransformContext) -> bool {
+        let enabled = ctx.settings.sanitize.unwrap_or(true);
+        enabled && (ctx.is_document() || ctx.media_type == "image/svg+xml")
+    }
+
+    fn transform(&self, ctx: &TransformContext, content: String) -> Result<String> {
+        let scripted = is_scripted(ctx);
+
+        // The element being emptied and the depth of nested elements with the same name.
+        let mut emptying: Option<(Vec<u8>, usize)> = None;
+
+        rewrite_document(&content, |event, writer| {
+            if let Some((name, depth)) = &mut emptying {
+                match &event {
+                    Event::Start(start)
+                        if start.local_name().as_ref().eq_ignore_ascii_case(name) =>
+                    {
+                        *depth += 1
+                    }
+                    Event::End(end) if end.local_name().as_ref().eq_ignore_ascii_case(name) => {
+                        match depth {
+                            0 => {
+                                emptying = None;
+                                return Ok(writer.write_event(event)?);
+                            }
+                            _ => *depth -= 1,
+                        }
+                    }
+                    _ => {}
+                }
+                return Ok(());
+            }
+
+            let event = match event {
+                Event::Start(start) if should_empty(&start, scripted) => {
+                    let name = start.local_name().as_ref().to_ascii_lowercase();
+                    emptying = Some((name, 0));
+                    Event::Start(clear_attributes(start))
+                }
+                Event::Empty(start) if should_empty(&start, scripted) => {
+                    Event::Empty(clear_attributes(start))
+                }
+                Event::Start(start) => Event::Start(sanitize_attributes(start, scripted)),
+                Event::Empty(start) => Event::Empty(sanitize_attributes(start, scripted)),
+                event => event,
+            };
+
+            Ok(writer.write_event(event)?)
+        })
+    }
+
+    fn headers(&self, ctx: &TransformContext) -> Vec<(&'static str, String)> {
+        let policy = match &ctx.settings.content_security_policy {
+            Some(policy) => policy.clone(),
+            None if is_scripted(ctx) => SCRIPTED_POLICY.into(),
+            None => DEFAULT_POLICY.into(),
+        };
+
+        vec![
+            ("Content-Security-Policy", policy),
+            ("X-Content-Type-Options", "nosniff".into()),
+        ]
+    }
+}
+
+fn is_scripted(ctx: &TransformContext) -> bool {
+    let item = ctx.epub.get_manifest_item(ctx.path);
+    item.is_some_and(|item| item.has_property("scripted"))
+}
+
+fn should_empty(start: &BytesStart, scripted: bool) -> bool {
+    let name = start.local_name().as_ref().to_ascii_lowercase();
+
+    if !scripted && SCRIPT_ELEMENTS.contains(&&*name) {
+        return true;
+    }
+
+    match &*name {
+        // May redirect relative URLs to remote hosts.
+        b"base" => true,
+        // May navigate to remote pages.
+        b"meta" => start.attributes().flatten().any(|attr| {
+            attr.key.as_ref().eq_ignore_ascii_case(b"http-equiv")
+                && attr.value.eq_ignore_ascii_case(b"refresh")
+        }),
+        _ => false,
+    }
+}
+
+fn clear_attributes(start: BytesStart) -> BytesStart {
+    let mut cleared = start.to_owned();
+    cleared.clear_attributes();
+    cleared
+}
+
+fn sanitize_attributes(start: BytesStart, scripted: bool) -> BytesStart {
+    let name = start.local_name().as_ref().to_ascii_lowercase();
+
+    let keep = |attr: &Attribute| {
+        let key = attr.key.local_name().as_ref().to_ascii_lowercase();
+
+        if !scripted && key.starts_with(b"on") {
+            return false;
+        }
+
+        let is_load = LOAD_ATTRIBUTES.contains(&&*key);
+        let is_link = LINK_ATTRIBUTES.contains(&&*key);
+        if !is_load && !is_link {
+            return true;
+        }
+
+        let url = get_url(attr);
+
+        if !scripted && is_javascript(&url) {
+            return false;
+        }
+
+        // `<link href>` is loaded, while `<a href>` is only navigated to. SVG `<image>` and
+        // `<use>` load their `xlink:href` too.
+        let is_load = is_load || matches!(&*name, b"link" | b"image" | b"use");
+        !(is_load && is_remote(&url))
+    };
+
+    if start.attributes().flatten().all(|attr| keep(&attr)) {
+        return start;
+    }
+
+    let mut sanitized = start.to_owned();
+    sanitized.clear_attributes();
+    for attr in start.attributes().flatten() {
+        if keep(&attr) {
+            sanitized.push_attribute(attr);
+        }
+    }
+
+    sanitized
+}
+
+/// Returns the unescaped URL, lowercased and with the whitespaces and control characters
+/// removed, as browsers ignore them in schemes.
+fn get_url(attr: &Attribute) -> Vec<u8> {
+    let value = match attr.unescape_value() {
+        Ok(value) => value.into_owned().into_bytes(),
+        Err(_) => attr.value.to_vec(),
+    };
+
+    let value = value
+        .into_iter()
+        .filter(|x| !x.is_ascii_whitespace() && !x.is_ascii_control());
+    value.map(|x| x.to_ascii_lowercase()).collect()
+}
+
+fn is_javascript(url: &[u8]) -> bool {
+    url.starts_with(b"javascript:") || url.starts_with(b"vbscript:")
+}
+
+fn is_remote(url: &[u8]) -> bool {
+    // `srcset` contains a comma-separated list of URLs.
+    url.split(|x| *x == b',').any(|url| {
+        const SCHEMES: &[&[u8]] = &[b"http:", b"https:", b"ftp:", b"ws:", b"wss:", b"//"];
+        SCHEMES.iter().any(|scheme| url.starts_with(scheme))
+    })
+}
@@ -0,0 +1,144 @@
+use anyhow::Result;
+use quick_xml::events::{BytesStart, Event};
+
+use super::{rewrite_document, Transform, TransformContext};
+use crate::epub::split::VirtualSpine;
+
+const PACKAGE_MEDIA_TYPE: &str = "application/oebps-package+xml";
+const NCX_MEDIA_TYPE: &str = "application/x-dtbncx+xml";
+
+/// Attributes containing links which may point into another part of a split document.
+const LINK_ATTRIBUTES: &[&[u8]] = &[b"href", b"xlink:href", b"src"];
+
+/// Serves large documents as several virtual chapters: the package document lists the parts in
+/// the manifest and the spine, the original document is replaced by its first part, and links to
+/// fragments are pointed to the parts containing them.
+pub struct SplitDocuments;
+
+impl Transform for SplitDocuments {
+    fn applies_to(&self, ctx: &TransformContext) -> bool {
+        let enabled = ctx.settings.split_threshold.is_some_and(|x| x > 0);
+        let media_type = ctx.media_type;
+        enabled
+            && (ctx.is_document()
+                || media_type == PACKAGE_MEDIA_TYPE
+                || media_type == NCX_MEDIA_TYPE)
+    }
+
+    fn transform(&self, ctx: &TransformContext, content: String) -> Result<String> {
+        let threshold = ctx.settings.split_threshold.unwrap_or_default();
+        let spine = ctx.epub.virtual_spine(threshold)?;
+        if spine.is_empty() {
+            return Ok(content);
+        }
+
+        if ctx.media_type == PACKAGE_MEDIA_TYPE {
+            return add_parts(&spine, &content);
+        }
+
+        let content = match spine.find_part(ctx.path) {
+            Some((split, part)) => split.content(part).to_string(),
+            None => content,
+        };
+
+        rewrite_document(&content, |event, writer| {
+            let event = match event {
+                Event::Start(start) => Event::Start(rewrite_links(ctx, &spine, start)),
+                Event::Empty(start) => Event::Empty(rewrite_links(ctx, &spine, start)),
+                event => event,
+            };
+            Ok(writer.write_event(event)?)
+        })
+    }
+}
+
+/// Adds the parts after the original documents in the manifest and the spine.
+fn add_parts(spine: &VirtualSpine, content: &str) -> Result<String> {
+    rewrite_document(content, |event, writer| {
+        let Event::Empty(start) = &event else {
+            return Ok(writer.write_event(event)?);
+        };
+
+        let is_item = match start.local_name().as_ref() {
+            b"item" => true,
+            b"itemref" => false,
+            _ => return Ok(writer.write_event(event)?),
+        };
+
+        let idref = get_attribute(start, if is_item { b"id" } else { b"idref" });
+        let split = idref.and_then(|x| spine.get_document_by_idref(&x));
+        writer.write_event(event.borrow())?;
+
+        let Some(split) = split else {
+            return Ok(());
+        };
+
+        for part in 1..split.len() {
+            let mut item = start.borrow();
+            item.clear_attributes();
+
+            for attr in start.attributes().flatten() {
+                let value = attr.unescape_value()?;
+                match attr.key.as_ref() {
+                    key @ (b"id" | b"idref") => {
+                        item.push_attribute((key, format!("{value}-part{part}").as_bytes()));
+                    }
+                    b"href" if is_item => {
+                        let href = format!("{value}.part{part}.xhtml");
+                        item.push_attribute((&b"href"[..], href.as_bytes()));
+                    }
+                    // The properties of the original document, e.g. `nav`, don't apply to parts.
+                    b"properties" if is_item => {}
+                    _ => item.push_attribute(attr),
+                }
+            }
+
+            writer.write_event(Event::Empty(item))?;
+        }
+
+        Ok(())
+    })
+}
+
+fn rewrite_links<'a>(
+    ctx: &TransformContext,
+    spine: &VirtualSpine,
+    start: BytesStart<'a>,
+) -> BytesStart<'a> {
+    let needs_rewrite = start.attributes().flatten().any(|attr| {
+        LINK_ATTRIBUTES.contains(&attr.key.as_ref())
+            && attr
+                .unescape_value()
+                .is_ok_and(|x| spine.rewrite_link(ctx.path, &x).is_some())
+    });
+    if !needs_rewrite {
+        return start;
+    }
+
+    let mut rewritten = start.borrow().into_owned();
+    rewritten.clear_attributes();
+
+    for attr in start.attributes().flatten() {
+        let link = match LINK_ATTRIBUTES.contains(&attr.key.as_ref()) {
+            true => attr
+                .unescape_value()
+                .ok()
+                .and_then(|x| spine.rewrite_link(ctx.path, &x)),
+            false => None,
+        };
+        match link {
+            Some(link) => rewritten.push_attribute((attr.key.as_ref(), link.as_bytes())),
+            None => rewritten.push_attribute(attr),
+        }
+    }
+
+    rewritten
+}
+
+fn get_attribute(start: &BytesStart, key: &[u8]) -> Option<String> {
+    let attr = start
+        .attributes()
+        .flatten()
+        .find(|x| x.key.as_ref() == key)?;
+    Some(attr.unescape_value().ok()?.into_owned())
+}
@@ -0,0 +1,95 @@
+use std::fmt::Write;
+
+use anyhow::Result;
+use quick_xml::events::{BytesEnd, BytesStart, BytesText, Event};
+use quick_xml::Writer;
+
+use super::{rewrite_document, Transform, TransformContext};
+use crate::settings::RenderSettings;
+
+/// Appends a `<style>` built from the render settings to the end of `<head>`, so that it takes
+/// precedence over the publisher styles. Documents without `<head>` get it at the end of the root
+/// element, where it doesn't change the CFIs of the other elements.
+pub struct InjectUserStyle;
+
+impl Transform for InjectUserStyle {
+    fn applies_to(&self, ctx: &TransformContext) -> bool {
+        ctx.is_document() && make_user_css(ctx.settings).is_some()
+    }
+
+    fn transform(&self, ctx: &TransformContext, content: String) -> Result<String> {
+        let Some(css) = make_user_css(ctx.settings) else {
+            return Ok(content);
+        };
+
+        let mut injected = false;
+        let mut depth = 0usize;
+        rewrite_document(&content, |event, writer| {
+            let before = match &event {
+                Event::Start(_) => {
+                    depth += 1;
+                    false
+                }
+                Event::End(end) => {
+                    depth = depth.saturating_sub(1);
+                    depth == 0 || end.local_name().as_ref().eq_ignore_ascii_case(b"head")
+                }
+                _ => false,
+            };
+            if before && !injected {
+                write_style(ctx, &css, writer)?;
+                injected = true;
+            }
+            Ok(writer.write_event(event)?)
+        })
+    }
+}
+
+fn write_style(ctx: &TransformContext, css: &str, writer: &mut Writer<Vec<u8>>) -> Result<()> {
+    let mut style = BytesStart::new("style");
+    style.push_attribute(("id", "ellisia-user-style"));
+    writer.write_event(Event::Start(style))?;
+    // The content of `<style>` is not unescaped in HTML.
+    let text = match ctx.media_type {
+        "text/html" => BytesText::from_escaped(css),
+        _ => BytesText::new(css),
+    };
+    writer.write_event(Event::Text(text))?;
+    writer.write_event(Event::End(BytesEnd::new("style")))?;
+    Ok(())
+}
+
+fn make_user_css(settings: &RenderSettings) -> Option<String> {
+    let mut css = String::new();
+
+    let mut body = String::new();
+    if let Some(color) = &settings.text_color {
+        let _ = write!(body, "color: {color} !important; ");
+    }
+    if let Some(color) = &settings.background_color {
+        let _ = write!(body, "background-color: {color} !important; ");
+    }
+    if let Some(size) = settings.font_size {
+        let _ = write!(body, "font-size: {size}% !important; ");
+    }
+    if !body.is_empty() {
+        let _ = writeln!(css, "html, body {{ {body}}}");
+    }
+
+    let mut all = String::new();
+    if let Some(family) = &settings.font_family {
+        let _ = write!(all, "font-family: {family} !important; ");
+    }
+    if let Some(height) = settings.line_height {
+        let _ = write!(all, "line-height: {height} !important; ");
+    }
+    if !all.is_empty() {
+        let _ = writeln!(css, "body, body * {{ {all}}}");
+    }
+
+    if let Some(user_css) = &settings.user_css {
+        css.push_str(user_css);
+    }
+
+    (!css.trim().is_empty()).then_some(css)
+}
@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::LazyLock;
+
+use anyhow::{bail, Context, Result};
+use parking_lot::Mutex;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use unicode_normalization::char::{decompose_canonical, is_combining_mark};
+
+use crate::epub::media_type::is_document;
+use crate::epub::text::DocumentText;
+use crate::epub::toc::EpubTocEntry;
+use crate::epub::EpubFile;
+
+/// The number of characters around a match in snippets.
+const SNIPPET_CONTEXT: usize = 40;
+
+const DEFAULT_LIMIT: usize = 1000;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct SearchOptions {
+    pub case_sensitive: bool,
+    pub diacritic_sensitive: bool,
+    pub whole_word: bool,
+    /// Treats the query as a regular expression.
+    pub regex: bool,
+    /// The maximum number of results. Defaults to 1000.
+    pub limit: Option<usize>,
+}
+
+/// A match, sent to the frontend in `search-result` events.
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchResult {
+    pub search_id: u64,
+    /// The index of the document in the original spine.
+    pub index: usize,
+    /// The label of the nearest entry of the table of contents before the match.
+    pub label: Option<String>,
+    pub snippet: String,
+    /// The range of the match in the snippet, in UTF-16 code units.
+    pub highlight: (usize, usize),
+    pub cfi: Option<String>,
+}
+
+/// Sent to the frontend in the `search-finished` event once a search ends.
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchFinished {
+    pub search_id: u64,
+    pub count: usize,
+    pub cancelled: bool,
+    pub error: Option<String>,
+}
+
+/// Finds the matches of a query in text.
+pub struct Matcher {
+    regex: Regex,
+    options: SearchOptions,
+}
+
+impl Matcher {
+    pub fn new(query: &str, options: &SearchOptions) -> Result<Self> {
+        if query.trim().is_empty() {
+            bail!("Empty search query");
+        }
+
+        let pattern = match options.regex {
+            true => query.to_string(),
+            // The text has its whitespace collapsed, so the query should too.
+            false => regex::escape(&query.split_whitespace().collect::<Vec<_>>().join(" ")),
+        };
+        let pattern = match options.diacritic_sensitive {
+            true => pattern,
+            false => fold(&pattern, true).text,
+        };
+
+        let regex = RegexBuilder::new(&pattern)
+            .case_insensitive(!options.case_sensitive)
+            .build()
+            .context("Invalid regular expression")?;
+
+        Ok(Self {
+            regex,
+            options: options.clone(),
+        })
+    }
+
+    /// Returns the byte ranges of the matches in the text.
+    pub fn find(&self, text: &str) -> Vec<Range<usize>> {
+        let folded = fold(text, !self.options.diacritic_sensitive);
+
+        let mut matches = Vec::new();
+        for found in self.regex.find_iter(&folded.text) {
+            if found.is_empty() {
+                continue;
+            }
+
+            let range = folded.to_original(text, found.range());
+            if self.options.whole_word && !is_whole_word(text, &range) {
+                continue;
+            }
+            matches.push(range);
+        }
+
+        matches
+    }
+}
+
+/// Text folded for matching, and the offsets of the characters it comes from.
+struct FoldedText {
+    text: String,
+    /// The offset in the original text of each byte of the folded text.
+    origins: Vec<usize>,
+}
+
+impl FoldedText {
+    fn to_original(&self, original: &str, range: Range<usize>) -> Range<usize> {
+        let start = self.origins[range.start];
+        // The end may fall inside the decomposition of a character, which is included as whole.
+        let last = self.origins[range.end - 1];
+        let end = last + original[last..].chars().next().map_or(0, char::len_utf8);
+        start..end
+    }
+}
+
+/// Removes the spaces between CJK characters, which come from line breaks in the source, and
+/// optionally the diacritics.
+fn fold(text: &str, diacritics: bool) -> FoldedText {
+    let mut folded = String::with_capacity(text.len());
+    let mut origins = Vec::with_capacity(text.len());
+
+    let mut previous = None;
+    let mut chars = text.char_indices().peekable();
+
+    while let Some((index, c)) = chars.next() {
+        let next = chars.peek().map(|(_, c)| *c);
+        let current = previous;
+        previous = Some(c);
+
+        if c == ' ' && current.is_some_and(is_cjk) && next.is_some_and(is_cjk) {
+            continue;
+        }
+
+        let start = folded.len();
+        match diacritics {
+            true => decompose_canonical(c, |c| {
+                if !is_combining_mark(c) {
+                    folded.push(c);
+                }
+            }),
+            false => folded.push(c),
+        }
+        origins.resize(origins.len() + folded.len() - start, index);
+    }
+
+    FoldedText {
+        text: folded,
+        origins,
+    }
+}
+
+/// Checks that a match isn't part of a longer word. Boundaries next to CJK characters always
+/// count, since these scripts don't separate words.
+fn is_whole_word(text: &str, range: &Range<usize>) -> bool {
+    let first = text[range.start..].chars().next();
+    let last = text[..range.end].chars().next_back();
+    let before = text[..range.start].chars().next_back();
+    let after = text[range.end..].chars().next();
+
+    let is_boundary = |outer: Option<char>, inner: Option<char>| match (outer, inner) {
+        (Some(outer), Some(inner)) => !is_word(outer) || is_cjk(outer) || is_cjk(inner),
+        _ => true,
+    };
+    is_boundary(before, first) && is_boundary(after, last)
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Scripts written without spaces between words: Han, Hiragana and Katakana.
+pub fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30ff}'
+        | '\u{3400}'..='\u{4dbf}'
+        | '\u{4e00}'..='\u{9fff}'
+        | '\u{f900}'..='\u{faff}'
+        | '\u{ff66}'..='\u{ff9f}'
+        | '\u{20000}'..='\u{2fa1f}')
+}
+
+/// The latest search of each book, so that the previous ones can be cancelled.
+static SEARCHES: LazyLock<Mutex<HashMap<String, u64>>> = LazyLock::new(Default::default);
+static NEXT_SEARCH_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Registers a new search of a book, cancelling the previous one.
+pub fn begin_search(book_id: &str) -> u64 {
+    let search_id = NEXT_SEARCH_ID.fetch_add(1, Ordering::Relaxed);
+    SEARCHES.lock().insert(book_id.to_string(), search_id);
+    search_id
+}
+
+pub fn is_cancelled(book_id: &str, search_id: u64) -> bool {
+    SEARCHES.lock().get(book_id) != Some(&search_id)
+}
+
+/// Unregisters a search once it has finished, unless another search of the book has started.
+pub fn end_search(book_id: &str, search_id: u64) {
+    let mut searches = SEARCHES.lock();
+    if searches.get(book_id) == Some(&search_id) {
+        searches.remove(book_id);
+    }
+}
+
+/// Searches the spine documents in reading order, passing each result to `emit` until it returns
+/// `false`, the limit is reached or the search is cancelled. Returns the number of results.
+pub fn search_book(
+    epub: &EpubFile,
+    matcher: &Matcher,
+    book_id: &str,
+    search_id: u64,
+    mut emit: impl FnMut(SearchResult) -> bool,
+) -> Result<usize> {
+    let limit = matcher.options.limit.unwrap_or(DEFAULT_LIMIT);
+    let entries = epub.toc().entries();
+    let mut label = None;
+    let mut count = 0;
+
+    for index in 0..epub.rootfile().package.spine.children.len() {
+        let Some((idref, path)) = epub.get_spine_path(index) else {
+            continue;
+        };
+        if !epub.get_media_type(&path).is_some_and(is_document) {
+            continue;
+        }
+        // Documents without matches may take a while too.
+        if is_cancelled(book_id, search_id) {
+            break;
+        }
+
+        // Documents which can't be read are skipped rather than failing the whole search.
+        let text = match epub.read_document(&path) {
+            Ok(content) => DocumentText::parse(&content),
+            Err(e) => Err(e),
+        };
+        let Ok(text) = text else {
+            eprintln!("Failed to search {path}");
+            continue;
+        };
+
+        let entries: Vec<_> = entries.iter().filter(|x| x.path == path).collect();
+        let mut entries = entries.as_slice();
+
+        for range in matcher.find(&text.text) {
+            while let Some((entry, rest)) = entries.split_first() {
+                if get_entry_offset(&text, entry) > range.start {
+                    break;
+                }
+                label = Some(entry.label.clone());
+                entries = rest;
+            }
+
+            let (snippet, highlight) = make_snippet(&text, &range);
+            let result = SearchResult {
+                search_id,
+                index,
+                label: label.clone(),
+                snippet,
+                highlight,
+                cfi: text.range_to_cfi(index, idref, range),
+            };
+
+            count += 1;
+            if !emit(result) || count >= limit {
+                return Ok(count);
+            }
+        }
+
+        // Entries after the last match still apply to the following documents.
+        if let Some(entry) = entries.last() {
+            label = Some(entry.label.clone());
+        }
+    }
+
+    Ok(count)
+}
+
+fn get_entry_offset(text: &DocumentText, entry: &EpubTocEntry) -> usize {
+    let fragment = entry.fragment.as_ref();
+    fragment
+        .and_then(|x| text.anchors.get(x))
+        .copied()
+        .unwrap_or(0)
+}
+
+/// Returns the text around a match in its block, and the range of the match in it.
+fn make_snippet(text: &DocumentText, range: &Range<usize>) -> (String, (usize, usize)) {
+    let block = text
+        .blocks
+        .iter()
+        .find(|x| x.range.end >= range.start)
+        .map_or(0..text.text.len(), |x| x.range.clone());
+
+    let before = &text.text[block.start.min(range.start)..range.start];
+    let skipped = before
+        .chars()
+        .rev()
+        .take(SNIPPET_CONTEXT)
+        .map(char::len_utf8)
+        .sum::<usize>();
+    let start = range.start - skipped;
+
+    let after = &text.text[range.end..block.end.max(range.end)];
+    let taken = after
+        .chars()
+        .take(SNIPPET_CONTEXT)
+        .map(char::len_utf8)
+        .sum::<usize>();
+    let end = range.end + taken;
+
+    let mut snippet = String::new();
+    if start > block.start {
+        snippet.push('…');
+    }
+    let highlight_start =
+        snippet.encode_utf16().count() + utf16_len(&text.text[start..range.start]);
+    let highlight_end = highlight_start + utf16_len(&text.text[range.clone()]);
+    snippet.push_str(&text.text[start..end]);
+    if end < block.end {
+        snippet.push('…');
+    }
+
+    (snippet.replace('\n', " "), (highlight_start, highlight_end))
+}
+
+fn utf16_len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
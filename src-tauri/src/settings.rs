@@ -0,0 +1,137 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{get_config_dir_path, read_json_file, write_json_file};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Settings {
+    #[serde(default)]
+    pub transport: RendererTransport,
+    /// Defaults of all books. Can be overridden by `Book::render`.
+    #[serde(default)]
+    pub render: RenderSettings,
+}
+
+impl Settings {
+    fn get_path() -> Result<PathBuf> {
+        let dir = get_config_dir_path()?;
+        Ok(dir.join("settings.json"))
+    }
+
+    pub fn load() -> Result<Self> {
+        let path = Self::get_path()?;
+        read_json_file(&path).context("Failed to read settings.json")
+    }
+
+    pub fn persist(&self) -> Result<()> {
+        let path = Self::get_path()?;
+        write_json_file(&path, self).context("Failed to write settings.json")
+    }
+}
+
+/// How the reader windows load book resources. Cover thumbnails and static assets are always
+/// served by the HTTP server.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RendererTransport {
+    /// The local HTTP server on `127.0.0.1`.
+    #[default]
+    Http,
+    /// The `book://` custom protocol.
+    Protocol,
+}
+
+/// How book documents are transformed before being served. `None` means "not specified", so
+/// that the per-book settings can fall back to the global ones.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RenderSettings {
+    /// Arbitrary CSS appended to every document.
+    pub user_css: Option<String>,
+    pub font_family: Option<String>,
+    /// In percent of the default font size.
+    pub font_size: Option<u32>,
+    pub line_height: Option<f32>,
+    pub text_color: Option<String>,
+    pub background_color: Option<String>,
+    /// Removes `font-size` declarations in publisher styles.
+    pub strip_font_sizes: Option<bool>,
+    /// Removes `color` and `background-color` declarations in publisher styles.
+    pub strip_colors: Option<bool>,
+    /// Strips scripts and remote resources from documents. Enabled by default.
+    pub sanitize: Option<bool>,
+    /// Overrides the `Content-Security-Policy` of sanitized documents.
+    pub content_security_policy: Option<String>,
+    /// Converts the text of documents and the table of contents between Chinese scripts.
+    pub chinese_conversion: Option<ChineseConversion>,
+    /// Inserts soft hyphens into the text of documents.
+    pub hyphenate: Option<bool>,
+    /// Overrides the language of documents for hyphenation, e.g. `en-gb`.
+    pub hyphenation_language: Option<String>,
+    /// Highlights the syntax of code blocks whose language is declared by a class name.
+    pub highlight_code: Option<bool>,
+    /// The name of a builtin syntect theme, e.g. `base16-ocean.dark`.
+    pub highlight_theme: Option<String>,
+    /// Splits spine documents larger than this many bytes into virtual chapters. Disabled if 0.
+    pub split_threshold: Option<u64>,
+}
+
+impl RenderSettings {
+    /// Returns the settings with the unspecified fields taken from `fallback`.
+    pub fn or(&self, fallback: &RenderSettings) -> RenderSettings {
+        RenderSettings {
+            user_css: self.user_css.clone().or_else(|| fallback.user_css.clone()),
+            font_family: self
+                .font_family
+                .clone()
+                .or_else(|| fallback.font_family.clone()),
+            font_size: self.font_size.or(fallback.font_size),
+            line_height: self.line_height.or(fallback.line_height),
+            text_color: self
+                .text_color
+                .clone()
+                .or_else(|| fallback.text_color.clone()),
+            background_color: self
+                .background_color
+                .clone()
+                .or_else(|| fallback.background_color.clone()),
+            strip_font_sizes: self.strip_font_sizes.or(fallback.strip_font_sizes),
+            strip_colors: self.strip_colors.or(fallback.strip_colors),
+            sanitize: self.sanitize.or(fallback.sanitize),
+            content_security_policy: self
+                .content_security_policy
+                .clone()
+                .or_else(|| fallback.content_security_policy.clone()),
+            chinese_conversion: self.chinese_conversion.or(fallback.chinese_conversion),
+            hyphenate: self.hyphenate.or(fallback.hyphenate),
+            hyphenation_language: self
+                .hyphenation_language
+                .clone()
+                .or_else(|| fallback.hyphenation_language.clone()),
+            highlight_code: self.highlight_code.or(fallback.highlight_code),
+            highlight_theme: self
+                .highlight_theme
+                .clone()
+                .or_else(|| fallback.highlight_theme.clone()),
+            split_threshold: self.split_threshold.or(fallback.split_threshold),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ChineseConversion {
+    /// Overrides the global setting for books that shouldn't be converted.
+    Off,
+    /// To Simplified Chinese, character by character.
+    Simplified,
+    /// To Traditional Chinese, character by character.
+    Traditional,
+    /// To Simplified Chinese with the phrases used in Mainland China.
+    Mainland,
+    /// To Traditional Chinese with the phrases used in Taiwan.
+    Taiwan,
+    /// To Traditional Chinese with the phrases used in Hong Kong.
+    HongKong,
+}
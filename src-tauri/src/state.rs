@@ -1,32 +1,71 @@
 use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
 use std::time::SystemTime;
 
 use anyhow::{bail, Context, Result};
-use parking_lot::{Mutex, RwLock};
+use parking_lot::{Condvar, Mutex, RwLock};
 use rand::distributions::{Alphanumeric, DistString};
+use tauri::AppHandle;
 use typed_path::Utf8NativePathBuf;
 
+use crate::annotations::AnnotationStore;
+use crate::epub::anchor::TextQuote;
+use crate::epub::cfi::{compare_locations, Cfi};
+use crate::epub::progress::ReadingProgress;
+use crate::epub::split::VirtualSpine;
 use crate::epub::EpubFile;
-use crate::library::{Book, BookMetadata, Library};
+use crate::index::{self, LibraryIndex};
+use crate::library::{AnchorKind, Book, BookMetadata, Library, LostAnchor};
+use crate::renderer;
+use crate::renderer::transform::chinese::get_converter;
+use crate::settings::{RenderSettings, RendererTransport, Settings};
+use crate::utils::get_file_signature;
 
 /// Most of the time we do both read and write (e.g. updating reading state),
 /// so we don't use a `RwLock<Library>`. Also, we need to persist the entire
 /// library to disk at every write, so we don't use locks on individual books.
 pub struct AppState {
     renderer_port: u16,
+    renderer_token: String,
+    settings: RwLock<Settings>,
     library: Mutex<Library>,
-    epubs: RwLock<HashMap<String, EpubFile>>,
+    epubs: RwLock<HashMap<String, Arc<EpubFile>>>,
+    annotations: Mutex<AnnotationStore>,
+    /// `None` if the index couldn't be opened, in which case the library can't be searched.
+    index: Option<Arc<LibraryIndex>>,
+    indexer: Mutex<Option<Sender<()>>>,
+    /// The books whose saved locations are being placed again after they were opened.
+    reanchoring: Mutex<HashSet<String>>,
+    reanchored: Condvar,
 }
 
 impl AppState {
-    pub fn init(renderer_port: u16) -> Result<Self> {
+    pub fn init(renderer_port: u16, renderer_token: String) -> Result<Self> {
+        let settings = Settings::load().context("Failed to load settings.json")?;
         let library = Library::load().context("Failed to load library.json")?;
+        let annotations = AnnotationStore::load().context("Failed to load annotations.json")?;
+        let index = match LibraryIndex::open() {
+            Ok(index) => Some(Arc::new(index)),
+            Err(e) => {
+                eprintln!("Failed to open the library index:\n{:?}", e);
+                None
+            }
+        };
 
         Ok(Self {
             renderer_port,
+            renderer_token,
+            settings: RwLock::new(settings),
             library: Mutex::new(library),
             epubs: RwLock::new(HashMap::new()),
+            annotations: Mutex::new(annotations),
+            index,
+            indexer: Mutex::new(None),
+            reanchoring: Mutex::new(HashSet::new()),
+            reanchored: Condvar::new(),
         })
     }
 
@@ -34,14 +73,80 @@ impl AppState {
         self.renderer_port
     }
 
+    /// The base URL of the renderer HTTP server, including the access token.
+    pub fn renderer_url(&self) -> String {
+        format!(
+            "http://127.0.0.1:{}/{}",
+            self.renderer_port, self.renderer_token
+        )
+    }
+
+    /// The base URL of book resources, which is followed by `/{id}/{path}`.
+    pub fn books_url(&self) -> String {
+        match self.settings.read().transport {
+            RendererTransport::Http => format!("{}/book", self.renderer_url()),
+            RendererTransport::Protocol => renderer::protocol::ORIGIN.to_string(),
+        }
+    }
+
+    pub fn settings(&self) -> &RwLock<Settings> {
+        &self.settings
+    }
+
+    /// The render settings of a book, with the unspecified fields taken from the global ones.
+    pub fn render_settings(&self, id: &str) -> RenderSettings {
+        let global = self.settings.read().render.clone();
+        let library = self.library.lock();
+        match library.books().get(id) {
+            Some(book) => book.render.or(&global),
+            None => global,
+        }
+    }
+
+    /// The split spine of an opened book, or `None` if documents aren't split.
+    pub fn virtual_spine(&self, id: &str) -> Result<Option<Arc<VirtualSpine>>> {
+        let threshold = self.render_settings(id).split_threshold.unwrap_or_default();
+        let epub = self.epubs.read().get(id).cloned();
+        match epub {
+            Some(epub) if threshold > 0 => {
+                let spine = epub.virtual_spine(threshold)?;
+                Ok(Some(spine).filter(|x| !x.is_empty()))
+            }
+            _ => Ok(None),
+        }
+    }
+
     pub fn library(&self) -> &Mutex<Library> {
         &self.library
     }
 
-    pub fn epubs(&self) -> &RwLock<HashMap<String, EpubFile>> {
+    pub fn epubs(&self) -> &RwLock<HashMap<String, Arc<EpubFile>>> {
         &self.epubs
     }
 
+    pub fn annotations(&self) -> &Mutex<AnnotationStore> {
+        &self.annotations
+    }
+
+    pub fn index(&self) -> Option<&LibraryIndex> {
+        self.index.as_deref()
+    }
+
+    /// Starts indexing the library in the background.
+    pub fn start_indexer(&self, app: AppHandle) {
+        if let Some(index) = &self.index {
+            *self.indexer.lock() = Some(index::start_indexer(app, index.clone()));
+        }
+        self.update_index();
+    }
+
+    /// Syncs the index with the library in the background, e.g. after adding books.
+    pub fn update_index(&self) {
+        if let Some(sender) = &*self.indexer.lock() {
+            let _ = sender.send(());
+        }
+    }
+
     pub fn open_book(&self, path: Utf8NativePathBuf) -> Result<(String, Book)> {
         let mut library = self.library.lock();
         let mut epubs = self.epubs.write();
@@ -61,7 +166,7 @@ impl AppState {
             Entry::Occupied(entry) => entry.into_mut(),
             Entry::Vacant(entry) => {
                 let epub = EpubFile::open(path.clone()).context("Failed to open epub.")?;
-                entry.insert(epub)
+                entry.insert(Arc::new(epub))
             }
         };
 
@@ -77,14 +182,240 @@ impl AppState {
                 let book = Book {
                     path: path.to_string(),
                     location: None,
+                    location_quote: None,
+                    progress: None,
                     last_read_at: SystemTime::now(),
                     metadata: BookMetadata::new(epub),
+                    render: RenderSettings::default(),
+                    stats: None,
+                    bookmarks: Vec::new(),
+                    signature: get_file_signature(Path::new(path.as_str())),
+                    lost_anchors: Vec::new(),
+                    warnings: Vec::new(),
                 };
                 entry.insert(book)
             }
         };
 
-        Ok((id, book.clone()))
+        let book = book.clone();
+        self.update_index();
+
+        Ok((id, book))
+    }
+
+    /// Marks the saved locations of an opened book as about to be placed again by
+    /// `refresh_book`, so that readers wait for them.
+    pub fn begin_refresh(&self, id: &str) {
+        self.reanchoring.lock().insert(id.to_string());
+    }
+
+    /// Places the saved locations of a book again if its file has changed, and then computes its
+    /// stats if they're missing or out of date. Both read every document of a changed book, so
+    /// their texts are kept for the whole pass.
+    pub fn refresh_book(&self, id: &str) -> Result<Vec<LostAnchor>> {
+        let epub = self.epubs.read().get(id).cloned();
+        let _kept = epub.as_ref().map(|x| x.keep_document_texts());
+
+        let lost = self.reanchor_book(id);
+        self.reanchoring.lock().remove(id);
+        self.reanchored.notify_all();
+
+        let lost = lost?;
+        self.update_stats(id)?;
+        Ok(lost)
+    }
+
+    /// Waits until the saved locations of a book have been placed again after it was opened.
+    pub fn wait_for_anchors(&self, id: &str) {
+        let mut reanchoring = self.reanchoring.lock();
+        while reanchoring.contains(id) {
+            self.reanchored.wait(&mut reanchoring);
+        }
+    }
+
+    /// Computes the stats of a book if they're missing or its file has changed since.
+    pub fn update_stats(&self, id: &str) -> Result<()> {
+        let (path, signature) = {
+            let library = self.library.lock();
+            let book = library
+                .books()
+                .get(id)
+                .context("Book not found in library")?;
+            let signature = book.stats.as_ref().and(book.signature.clone());
+            (book.path.clone(), signature)
+        };
+        if signature.is_some() && signature == get_file_signature(Path::new(&path)) {
+            return Ok(());
+        }
+
+        let epub = match self.epubs.read().get(id).cloned() {
+            Some(epub) => epub,
+            None => Arc::new(EpubFile::open(Utf8NativePathBuf::from(path))?),
+        };
+        // Don't hold the lock of the library while reading the whole book.
+        let stats = epub.stats()?;
+
+        let mut library = self.library.lock();
+        if let Some(book) = library.books_mut().get_mut(id) {
+            book.stats = Some(stats);
+            // Every document has been read, so this replaces the warnings of older versions.
+            book.warnings = epub.warnings();
+            library.persist()?;
+        }
+        Ok(())
+    }
+
+    /// Records the repair warnings of an opened book in the library, and returns all of them.
+    pub fn record_warnings(&self, id: &str) -> Result<Vec<String>> {
+        let warnings = match self.epubs.read().get(id) {
+            Some(epub) => epub.warnings(),
+            None => Vec::new(),
+        };
+
+        let mut library = self.library.lock();
+        let book = library
+            .books_mut()
+            .get_mut(id)
+            .context("Book not found in library")?;
+        let count = book.warnings.len();
+        for warning in warnings {
+            if !book.warnings.contains(&warning) {
+                book.warnings.push(warning);
+            }
+        }
+
+        let warnings = book.warnings.clone();
+        if warnings.len() != count {
+            library.persist()?;
+        }
+        Ok(warnings)
+    }
+
+    /// Places the saved location, bookmarks and annotations of a book again if its file has
+    /// changed since they were saved, by finding the text quoted at them. Those which can't be
+    /// found are left where they were, and returned and recorded in the book. The stats of the
+    /// old file are dropped.
+    fn reanchor_book(&self, id: &str) -> Result<Vec<LostAnchor>> {
+        let settings = self.render_settings(id);
+        let (book, signature) = {
+            let library = self.library.lock();
+            let book = library
+                .books()
+                .get(id)
+                .context("Book not found in library")?;
+            (book.clone(), get_file_signature(Path::new(&book.path)))
+        };
+        if book.signature == signature {
+            return Ok(Vec::new());
+        }
+        // Books added before signatures were recorded are assumed to be unchanged, but their
+        // stats may not be.
+        if book.signature.is_none() {
+            let mut library = self.library.lock();
+            if let Some(book) = library.books_mut().get_mut(id) {
+                book.signature = signature;
+                book.stats = None;
+                library.persist()?;
+            }
+            return Ok(Vec::new());
+        }
+
+        let epub = match self.epubs.read().get(id).cloned() {
+            Some(epub) => epub,
+            None => Arc::new(EpubFile::open(Utf8NativePathBuf::from(book.path.clone()))?),
+        };
+        let annotations = self.annotations.lock().get(id).to_vec();
+
+        // Don't hold the locks while searching the book.
+        let mut lost = Vec::new();
+        let mut place = |kind, item: Option<&String>, cfi: &String, quote: Option<&TextQuote>| {
+            let quote = quote?;
+            let placed = reanchor(&epub, cfi, quote);
+            if placed.is_none() {
+                lost.push(LostAnchor {
+                    kind,
+                    id: item.cloned(),
+                    cfi: cfi.clone(),
+                    text: match quote.text.is_empty() {
+                        true => quote.prefix.clone(),
+                        false => quote.text.clone(),
+                    },
+                });
+            }
+            placed
+        };
+
+        let location = book.location.as_ref().and_then(|cfi| {
+            let placed = place(
+                AnchorKind::Location,
+                None,
+                cfi,
+                book.location_quote.as_ref(),
+            )?;
+            Some((cfi.clone(), placed))
+        });
+        let bookmarks: Vec<_> = book
+            .bookmarks
+            .iter()
+            .filter_map(|x| {
+                let placed = place(AnchorKind::Bookmark, Some(&x.id), &x.cfi, x.quote.as_ref())?;
+                Some((x.id.clone(), x.cfi.clone(), placed))
+            })
+            .collect();
+        let annotations: Vec<_> = annotations
+            .iter()
+            .filter_map(|x| {
+                let placed = place(AnchorKind::Annotation, Some(&x.id), &x.cfi, Some(&x.quote))?;
+                Some((x.id.clone(), x.cfi.clone(), placed))
+            })
+            .collect();
+
+        let progress = location.as_ref().and_then(|(_, (cfi, _))| {
+            let cfi = Cfi::parse(cfi).ok()?;
+            let mut progress = ReadingProgress::new(&epub, book.stats.as_ref(), &cfi)?;
+            if let Some(converter) = get_converter(&settings) {
+                progress.toc_label = progress.toc_label.as_deref().map(|x| converter.convert(x));
+            }
+            Some(progress)
+        });
+
+        // Locations saved while searching are newer, and are kept.
+        let mut library = self.library.lock();
+        if let Some(book) = library.books_mut().get_mut(id) {
+            if let Some((old, (cfi, quote))) = location {
+                if book.location.as_ref() == Some(&old) {
+                    book.location = Some(cfi);
+                    book.location_quote = Some(quote);
+                    book.progress = progress;
+                }
+            }
+            for (bookmark_id, old, (cfi, quote)) in bookmarks {
+                let bookmark = book.bookmarks.iter_mut().find(|x| x.id == bookmark_id);
+                if let Some(bookmark) = bookmark.filter(|x| x.cfi == old) {
+                    bookmark.cfi = cfi;
+                    bookmark.quote = Some(quote);
+                }
+            }
+            book.bookmarks
+                .sort_by(|a, b| compare_locations(&a.cfi, &b.cfi));
+            book.signature = signature;
+            book.stats = None;
+            book.lost_anchors = lost.clone();
+            library.persist()?;
+        }
+        drop(library);
+
+        let mut store = self.annotations.lock();
+        for (annotation_id, old, (cfi, quote)) in annotations {
+            if let Some(annotation) = store.get_mut(id, &annotation_id).filter(|x| x.cfi == old) {
+                annotation.cfi = cfi;
+                annotation.quote = quote;
+            }
+        }
+        store.sort(id);
+        store.persist()?;
+
+        Ok(lost)
     }
 
     pub fn close_book(&self, id: &str) {
@@ -92,3 +423,12 @@ impl AppState {
         epubs.remove(id);
     }
 }
+
+/// Finds a saved location again by the text quoted at it, and returns the new location with the
+/// text now around it.
+fn reanchor(epub: &EpubFile, cfi: &str, quote: &TextQuote) -> Option<(String, TextQuote)> {
+    let hint = Cfi::parse(cfi).ok()?;
+    let found = quote.find(epub, Some(&hint))?;
+    let quote = TextQuote::new(epub, &found).unwrap_or_else(|| quote.clone());
+    Some((found.to_string(), quote))
+}
@@ -1,7 +1,10 @@
 use std::borrow::Cow;
 use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
 
 use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 
 #[cfg(windows)]
 pub fn path_to_bytes(path: &Path) -> Cow<[u8]> {
@@ -39,3 +42,37 @@ pub fn init_dir(path: &Path) -> Result<()> {
 
     Ok(())
 }
+
+/// Identifies a version of a file by its size and modification time.
+pub fn get_file_signature(path: &Path) -> Option<String> {
+    let metadata = std::fs::metadata(path).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(format!("{:x}-{:x}", metadata.len(), modified.as_millis()))
+}
+
+/// Reads a JSON file of the config dir. A missing or empty file is the default value, but a file
+/// which can't be parsed is an error, so that it isn't overwritten with the default.
+pub fn read_json_file<T: DeserializeOwned + Default>(path: &Path) -> Result<T> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(T::default()),
+        Err(e) => return Err(e.into()),
+    };
+    if content.trim().is_empty() {
+        return Ok(T::default());
+    }
+    Ok(serde_json::from_str(&content)?)
+}
+
+/// Writes a JSON file to a temporary file next to it, and then replaces it, so that the file is
+/// never left half written.
+pub fn write_json_file<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+
+    let content = serde_json::to_vec_pretty(value)?;
+    std::fs::write(&temp, content)?;
+    std::fs::rename(&temp, path)?;
+    Ok(())
+}
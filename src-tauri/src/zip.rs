@@ -115,8 +115,23 @@ impl<'a> SharedZipEntry<'a> {
         Self { file, entry }
     }
 
-    pub fn reader(&self) -> EntryReader<'_, Cursor<&RandomAccessFile>> {
-        EntryReader::new(self.entry, |offset| Cursor::new_pos(self.file, offset))
+    pub fn reader(&self) -> EntryReader<'a, Cursor<&'a RandomAccessFile>> {
+        let file = self.file;
+        EntryReader::new(self.entry, move |offset| Cursor::new_pos(file, offset))
+    }
+
+    /// Size in bytes after decompression.
+    pub fn size(&self) -> u64 {
+        self.entry.uncompressed_size
+    }
+
+    /// CRC-32 from the central directory. May be zero for entries written in streaming mode.
+    pub fn crc32(&self) -> u32 {
+        self.entry.crc32
+    }
+
+    pub fn modified(&self) -> i64 {
+        self.entry.modified().timestamp()
     }
 
     pub fn bytes(&self) -> Result<Vec<u8>> {
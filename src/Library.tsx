@@ -6,6 +6,7 @@ import { createStore, reconcile } from 'solid-js/store';
 
 import { Toolbar } from './components/Toolbar';
 import { ToolbarIcon } from './components/ToolbarIcon';
+import { formatMinutes } from './duration';
 
 export function Library() {
     const [library, { refetch }] = createResource(() => invoke<EllisiaLibrary>('get_library'));
@@ -44,6 +45,24 @@ export function Library() {
         }
     };
 
+    const importKindleClippings = async () => {
+        const report = await invoke<EllisiaKindleImportReport | null>('import_kindle_clippings');
+        if (!report) {
+            return;
+        }
+
+        const lines = report.books.map(
+            (x) => `${x.title}: ${x.imported} imported, ${x.unplaced.length} not found`,
+        );
+        if (report.unmatched.length) {
+            lines.push(`Not in the library: ${report.unmatched.join(', ')}`);
+        }
+        if (report.ambiguous.length) {
+            lines.push(`Matching several books: ${report.ambiguous.join(', ')}`);
+        }
+        await dialog.message(lines.join('\n') || 'No highlights found', 'Kindle Clippings');
+    };
+
     const openBook = async (path: string) => {
         const result = await invoke<boolean>('open_book', { path });
         if (result) {
@@ -55,6 +74,12 @@ export function Library() {
         <div id="library">
             <Toolbar size="large">
                 <ToolbarIcon bordered onClick={openNewBook} icon="folder-open-line" label="Open" />
+                <ToolbarIcon
+                    bordered
+                    onClick={importKindleClippings}
+                    icon="file-text-line"
+                    label="Kindle"
+                />
             </Toolbar>
 
             <div class="books" onClick={deselectBook}>
@@ -81,6 +106,26 @@ export function Library() {
                                     {book.metadata.title ?? getFilename(book.path)}
                                 </div>
                                 <div class="author">{book.metadata.author}</div>
+                                {book.progress && (
+                                    <div class="progress">
+                                        {Math.floor(book.progress.percentage * 100)}% read
+                                        {book.progress.toc_index != null &&
+                                            ` · ${book.progress.toc_label ?? 'Chapter'} (${
+                                                book.progress.toc_index + 1
+                                            } of ${book.progress.toc_count})`}
+                                    </div>
+                                )}
+                                {book.stats && (
+                                    <div class="reading-time">
+                                        About {formatMinutes(book.stats.minutes)} to read
+                                    </div>
+                                )}
+                                {!!book.lost_anchors?.length && (
+                                    <div class="lost-anchors">
+                                        {book.lost_anchors.length} notes couldn't be found in the
+                                        updated book
+                                    </div>
+                                )}
                                 <div class="last-read">
                                     Last Read:
                                     <br />
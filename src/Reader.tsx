@@ -1,6 +1,7 @@
 import './Reader.scss';
+import './epubjs/softHyphens';
 
-import { invoke } from '@tauri-apps/api';
+import { dialog, invoke } from '@tauri-apps/api';
 import { Book, Contents, EpubCFI, Location } from 'epubjs';
 import { createSignal, onCleanup } from 'solid-js';
 
@@ -11,6 +12,7 @@ import HEAD_END from './assets/head-end.html?raw';
 import { Navigation, TocItem } from './components/Navigation';
 import { Toolbar } from './components/Toolbar';
 import { ToolbarIcon } from './components/ToolbarIcon';
+import { formatMinutes } from './duration';
 import { IframeViewWithCSP } from './epubjs/IframeViewWithCSP';
 import { AbstractHistory } from './history';
 
@@ -39,7 +41,7 @@ export function Reader() {
         displaySection(state);
     };
 
-    let book = new Book(`${ELLISIA.renderer}/book/${ELLISIA.book.id}/`);
+    let book = new Book(`${ELLISIA.books}/${ELLISIA.book.id}/`);
     onCleanup(() => book.destroy());
 
     const getCfiFromHref = async (href: string) => {
@@ -95,6 +97,95 @@ export function Reader() {
         setCurrentTocItem(lastItemAbove);
     };
 
+    const [readingTime, setReadingTime] = createSignal<EllisiaReadingTime>();
+
+    const updateReadingTime = async () => {
+        const id = ELLISIA.book.id;
+        setReadingTime(await invoke<EllisiaReadingTime>('get_reading_time', { id }));
+    };
+
+    let currentLocation: string | undefined;
+
+    const addBookmark = async () => {
+        if (currentLocation) {
+            await invoke('add_bookmark', { id: ELLISIA.book.id, location: currentLocation });
+        }
+    };
+
+    let selectedRange: string | undefined;
+
+    // The IDs of the annotations drawn in the book.
+    const shownAnnotations = new Set<string>();
+
+    const showAnnotation = (annotation: EllisiaAnnotation) => {
+        if (shownAnnotations.has(annotation.id)) return;
+        shownAnnotations.add(annotation.id);
+
+        const annotations = book.rendition.annotations;
+        const add =
+            annotation.style === 'underline' ? annotations.underline : annotations.highlight;
+        const styles = annotation.color ? { fill: annotation.color, stroke: annotation.color } : {};
+        add.call(
+            annotations,
+            annotation.cfi,
+            { id: annotation.id },
+            undefined,
+            `ellisia-annotation-${annotation.style}`,
+            styles
+        );
+    };
+
+    const addAnnotation = async () => {
+        if (!selectedRange) return;
+        const annotation = await invoke<EllisiaAnnotation>('create_annotation', {
+            id: ELLISIA.book.id,
+            location: selectedRange,
+            changes: {},
+        });
+        showAnnotation(annotation);
+        selectedRange = undefined;
+    };
+
+    const exportAnnotations = async () => {
+        await invoke<boolean>('export_annotations', { id: ELLISIA.book.id });
+    };
+
+    const importAnnotations = async () => {
+        const report = await invoke<EllisiaAnnotationImportReport | null>('import_annotations', {
+            id: ELLISIA.book.id,
+        });
+        if (!report) return;
+
+        if (report.source === 'koreader' && report.location) displaySection(report.location);
+        const annotations = await invoke<EllisiaAnnotation[]>('list_annotations', {
+            id: ELLISIA.book.id,
+        });
+        annotations.forEach(showAnnotation);
+
+        const lines = [`${report.imported} imported, ${report.duplicates} already added`];
+        if (report.skipped) {
+            lines.push(`${report.skipped} of other books`);
+        }
+        if (report.source === 'koreader') {
+            lines.push(`${report.bookmarks} bookmarks imported`);
+            if (report.location) lines.push('Moved to the location in KOReader');
+        }
+        if (report.unplaced.length) {
+            lines.push(`Not found: ${report.unplaced.join(', ')}`);
+        }
+        await dialog.message(lines.join('\n'), 'Import Annotations');
+    };
+
+    const [page, setPage] = createSignal<string>();
+
+    const updatePage = async (location: string) => {
+        const page = await invoke<EllisiaPagePosition | null>('get_page', {
+            id: ELLISIA.book.id,
+            location,
+        });
+        setPage(page ? `Page ${page.label} of ${page.count}` : undefined);
+    };
+
     const displaySection = (target: string) => {
         requestIdleCallback(() => {
             const rendition = book.rendition;
@@ -125,7 +216,7 @@ export function Reader() {
                 .querySelectorAll('meta[http-equiv="Content-Security-Policy"]')
                 .forEach((element) => element.remove());
 
-            const origins = `${ELLISIA.renderer} ${assets}`;
+            const origins = `${ELLISIA.books} ${ELLISIA.renderer} ${assets}`;
             const csp = document.createElement('meta');
             csp.setAttribute('http-equiv', 'Content-Security-Policy');
             csp.setAttribute('content', CSP.replaceAll('{origins}', origins));
@@ -134,11 +225,17 @@ export function Reader() {
 
         rendition.on('relocated', (location: Location) => {
             calcCurrentTocItem(location);
+            currentLocation = location.start.cfi;
             history.replace(location.start.cfi);
             invoke('save_progress', {
                 id: ELLISIA.book.id,
                 location: location.start.cfi,
-            });
+            }).then(updateReadingTime);
+            updatePage(location.start.cfi);
+        });
+
+        rendition.on('selected', (cfiRange: string) => {
+            selectedRange = cfiRange;
         });
 
         // Process document after it is rendered to iframe
@@ -197,7 +294,13 @@ export function Reader() {
         // This will trigger `book.spine.hooks.content`, so it needs to be placed after that.
         await initializeToc();
 
+        // Waits for the saved locations to be placed again if the book has changed.
         const location = await invoke<any>('get_progress', { id: ELLISIA.book.id });
+        const annotations = await invoke<EllisiaAnnotation[]>('list_annotations', {
+            id: ELLISIA.book.id,
+        });
+        annotations.forEach(showAnnotation);
+
         history.reset([location]);
         displaySection(location);
     };
@@ -219,6 +322,18 @@ export function Reader() {
                 <div class="sep" />
                 <ToolbarIcon icon="database-line" onClick={openLibrary} />
                 <div class="flex-spacer" />
+                {page() && <div class="reading-time">{page()}</div>}
+                {readingTime() && (
+                    <div class="reading-time">
+                        {readingTime()!.chapter_remaining != null &&
+                            `${formatMinutes(readingTime()!.chapter_remaining!)} left in chapter · `}
+                        {formatMinutes(readingTime()!.remaining)} left in book
+                    </div>
+                )}
+                <ToolbarIcon icon="mark-pen-line" onClick={addAnnotation} />
+                <ToolbarIcon icon="bookmark-line" onClick={addBookmark} />
+                <ToolbarIcon icon="upload-line" onClick={importAnnotations} />
+                <ToolbarIcon icon="download-line" onClick={exportAnnotations} />
                 <ToolbarIcon icon="font-size" />
                 <ToolbarIcon icon="information-line" />
             </Toolbar>
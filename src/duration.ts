@@ -0,0 +1,11 @@
+/**
+ * Formats a duration in minutes, e.g. `7h 20m`.
+ */
+export function formatMinutes(minutes: number) {
+    const rounded = Math.max(1, Math.round(minutes));
+    const hours = Math.floor(rounded / 60);
+    if (hours === 0) {
+        return `${rounded}m`;
+    }
+    return `${hours}h ${rounded % 60}m`;
+}
@@ -0,0 +1,61 @@
+import { EpubCFI } from 'epubjs';
+
+// Soft hyphens inserted when hyphenating documents don't count towards the offsets of CFIs, so
+// that CFIs match the ones of the original documents, which the backend computes.
+
+const SOFT_HYPHEN = '\u00ad';
+
+interface Segment {
+    terminal: { offset: number | null };
+}
+
+interface Prototype {
+    pathTo(node: Node, offset: number | null, ignoreClass?: string): Segment;
+    toRange(doc?: Document, ignoreClass?: string): Range | null;
+}
+
+const prototype = EpubCFI.prototype as unknown as Prototype;
+const pathTo = prototype.pathTo;
+const toRange = prototype.toRange;
+
+prototype.pathTo = function (node, offset, ignoreClass) {
+    const segment = pathTo.call(this, node, offset, ignoreClass);
+    if (node.nodeType === Node.TEXT_NODE && segment.terminal.offset != null) {
+        segment.terminal.offset = removeSoftHyphens(node as Text, segment.terminal.offset);
+    }
+    return segment;
+};
+
+prototype.toRange = function (doc, ignoreClass) {
+    const range = toRange.call(this, doc, ignoreClass);
+    if (range) {
+        const { startContainer, startOffset, endContainer, endOffset } = range;
+        if (endContainer.nodeType === Node.TEXT_NODE) {
+            range.setEnd(endContainer, addSoftHyphens(endContainer as Text, endOffset));
+        }
+        if (startContainer.nodeType === Node.TEXT_NODE) {
+            range.setStart(startContainer, addSoftHyphens(startContainer as Text, startOffset));
+        }
+    }
+    return range;
+};
+
+/** Maps an offset in a text node to the offset without the soft hyphens before it. */
+function removeSoftHyphens(node: Text, offset: number) {
+    return offset - node.data.slice(0, offset).split(SOFT_HYPHEN).length + 1;
+}
+
+/** Maps an offset without soft hyphens to the offset in a text node. */
+function addSoftHyphens(node: Text, offset: number) {
+    let index = 0;
+    for (let count = 0; index < node.data.length; index++) {
+        if (node.data[index] === SOFT_HYPHEN) {
+            continue;
+        }
+        if (count === offset) {
+            break;
+        }
+        count++;
+    }
+    return index;
+}
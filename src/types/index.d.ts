@@ -4,6 +4,7 @@ declare global {
             id: string;
             path: string;
         };
+        books: string;
         renderer: string;
     };
 
@@ -19,7 +20,97 @@ declare global {
             unique_id?: string;
             title?: string;
             author?: string;
-        }
+        };
+        stats?: EllisiaBookStats;
+        progress?: EllisiaReadingProgress;
+        bookmarks?: EllisiaBookmark[];
+        lost_anchors?: EllisiaLostAnchor[];
+    }
+
+    export interface EllisiaImportReport {
+        imported: number;
+        duplicates: number;
+        skipped: number;
+        unplaced: string[];
+    }
+
+    export interface EllisiaKindleImportReport {
+        books: (EllisiaImportReport & { book_id: string; title: string })[];
+        unmatched: string[];
+        ambiguous: string[];
+    }
+
+    export interface EllisiaKoreaderImportReport extends EllisiaImportReport {
+        bookmarks: number;
+        location?: string;
+    }
+
+    export type EllisiaAnnotationImportReport =
+        | (EllisiaImportReport & { source: 'web_annotation' })
+        | (EllisiaKoreaderImportReport & { source: 'koreader' });
+
+    export interface EllisiaLostAnchor {
+        kind: 'location' | 'bookmark' | 'annotation';
+        id?: string;
+        cfi: string;
+        text: string;
+    }
+
+    export interface EllisiaBookmark {
+        id: string;
+        cfi: string;
+        created_at: string;
+        label: string;
+        title?: string;
+    }
+
+    export interface EllisiaAnnotation {
+        id: string;
+        cfi: string;
+        text: string;
+        prefix: string;
+        suffix: string;
+        color?: string;
+        style: 'highlight' | 'underline' | 'strikethrough';
+        note?: string;
+        tags: string[];
+        created_at: string;
+        updated_at: string;
+    }
+
+    export interface EllisiaReadingProgress {
+        index: number;
+        count: number;
+        fraction: number;
+        percentage: number;
+        toc_index?: number;
+        toc_count: number;
+        toc_label?: string;
+    }
+
+    export interface EllisiaBookStats {
+        words: number;
+        characters: number;
+        images: number;
+        minutes: number;
+    }
+
+    export interface EllisiaPageList {
+        print: boolean;
+        pages: { label: string; index: number; cfi: string }[];
+    }
+
+    export interface EllisiaPagePosition {
+        index: number;
+        label: string;
+        count: number;
+    }
+
+    export interface EllisiaReadingTime {
+        total: number;
+        remaining: number;
+        index?: number;
+        chapter_remaining?: number;
     }
 }
 